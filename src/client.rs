@@ -5,22 +5,53 @@
 //! - Event handling
 //! - Lease management
 
-use crate::{config::ClientConfig, error::HeraldError, v4::handler::DhcpV4Handler};
+use crate::{
+    config::ClientConfig,
+    error::HeraldError,
+    network::{
+        applier::{self, LeaseApplier},
+        configurator::ApplyReport,
+        interface::Interface,
+        ipv4ll::{LinkLocalClaim, LinkLocalEvent},
+        link::{LinkEvent, LinkMonitor},
+        netns,
+        packet::PacketSocket,
+        shared::ClientSocket,
+    },
+    v4::{
+        boot::BootInfo,
+        handler::DhcpV4Handler,
+        message::{build_dhcp_release, MessageOptions},
+        options::SubOptions,
+    },
+};
+use dhcproto::v4::HType;
 use std::{
+    collections::BTreeMap,
+    fmt,
     net::{Ipv4Addr, SocketAddr},
     time::Duration,
 };
-use tokio::{
-    net::UdpSocket,
-    time::{self},
-};
+use tokio::{sync::broadcast, time};
+
+/// Events kept for a subscriber that falls behind.
+const EVENT_CAPACITY: usize = 16;
 
 /// 状态机可以返回的动作，由客户端驱动器执行
 #[derive(Debug)]
 pub enum Action {
     Send(Vec<u8>, SocketAddr),
-    StoreLease(Lease),
+    StoreLease(Box<Lease>),
+    /// Claim an IPv4 link-local address, then resume with `Event::Timeout`
+    ClaimLinkLocal,
+    /// Drop the IPv4 link-local address, then resume with `Event::Timeout`
+    ReleaseLinkLocal,
+    /// Remove the configuration of a lease the server declined, then resume
+    /// with `Event::Timeout`
+    DropLease,
     Wait(Duration),
+    /// Wait for the carrier to return, then resume with `Event::CarrierUp`
+    WaitForCarrier,
     Exit,
 }
 
@@ -29,6 +60,10 @@ pub enum Action {
 pub enum Event<'a> {
     PacketReceived(&'a [u8]),
     Timeout,
+    /// The interface lost its carrier
+    CarrierDown,
+    /// The interface has a carrier again
+    CarrierUp,
 }
 
 /// 获得的租约信息
@@ -36,12 +71,100 @@ pub enum Event<'a> {
 pub struct Lease {
     pub offered_ip: Ipv4Addr,
     pub subnet_mask: Option<Ipv4Addr>,
+    /// Broadcast address (option 28)
+    pub broadcast_address: Option<Ipv4Addr>,
     pub routers: Option<Vec<Ipv4Addr>>,
+    /// Static host routes (option 33) as (destination, router) pairs
+    pub static_routes: Option<Vec<(Ipv4Addr, Ipv4Addr)>>,
     pub dns_servers: Option<Vec<Ipv4Addr>>,
-    #[allow(dead_code)]
     pub lease_duration: Option<Duration>,
-    #[allow(dead_code)]
+    /// Renewal (T1) time (option 58)
+    pub renewal_time: Option<Duration>,
+    /// Rebinding (T2) time (option 59)
+    pub rebinding_time: Option<Duration>,
     pub server_identifier: Option<Ipv4Addr>,
+    /// Interface MTU (option 26)
+    pub interface_mtu: Option<u16>,
+    /// NTP servers (option 42)
+    pub ntp_servers: Option<Vec<Ipv4Addr>>,
+    /// Captive portal API URI (option 114), validated to be HTTPS
+    pub captive_portal: Option<url::Url>,
+    /// Offset of the client's subnet from UTC in seconds (option 2, deprecated by RFC 4833)
+    pub time_offset: Option<i32>,
+    /// POSIX TZ string (option 100)
+    pub posix_timezone: Option<String>,
+    /// tz database timezone name (option 101)
+    pub tz_database_name: Option<String>,
+    /// Network boot parameters (siaddr, sname, file and options 66, 67, 150)
+    pub boot_info: Option<BootInfo>,
+    /// Vendor-specific information (option 43), decoded into sub-options
+    pub vendor_specific: Option<SubOptions>,
+    /// Vendor-identifying vendor-specific information (option 125), keyed by enterprise number
+    pub vendor_identifying: Option<BTreeMap<u32, SubOptions>>,
+}
+
+impl Lease {
+    /// Creates a lease for `offered_ip` with no other settings
+    pub fn new(offered_ip: Ipv4Addr) -> Self {
+        Self {
+            offered_ip,
+            subnet_mask: None,
+            broadcast_address: None,
+            routers: None,
+            static_routes: None,
+            dns_servers: None,
+            lease_duration: None,
+            renewal_time: None,
+            rebinding_time: None,
+            server_identifier: None,
+            interface_mtu: None,
+            ntp_servers: None,
+            captive_portal: None,
+            time_offset: None,
+            posix_timezone: None,
+            tz_database_name: None,
+            boot_info: None,
+            vendor_specific: None,
+            vendor_identifying: None,
+        }
+    }
+}
+
+/// Changes to a client's lease, sent to subscribers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// A lease was bound, renewed or confirmed
+    Bound {
+        address: Ipv4Addr,
+        /// Captive portal API URI (option 114)
+        captive_portal: Option<url::Url>,
+    },
+    /// The lease ended and its configuration was removed
+    Lost(Ipv4Addr),
+}
+
+/// What a client is doing and the lease it holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientStatus {
+    pub interface: String,
+    /// Name of the DHCP state
+    pub state: &'static str,
+    pub address: Option<Ipv4Addr>,
+    /// Captive portal API URI (option 114) of the current lease
+    pub captive_portal: Option<url::Url>,
+}
+
+impl fmt::Display for ClientStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.interface, self.state)?;
+        if let Some(address) = self.address {
+            write!(f, ", address {address}")?;
+        }
+        if let Some(ref uri) = self.captive_portal {
+            write!(f, ", captive portal {uri}")?;
+        }
+        Ok(())
+    }
 }
 
 /// DHCP 状态机的通用 Trait
@@ -55,31 +178,124 @@ pub trait DhcpStateMachine {
 pub struct DhcpClient {
     #[allow(dead_code)]
     config: ClientConfig,
-    socket: UdpSocket,
-    state_machine: Box<dyn DhcpStateMachine + Send>,
+    socket: ClientSocket,
+    /// Raw socket used until the interface is bound; `None` if it could not be opened
+    packet_socket: Option<PacketSocket>,
+    /// Carrier notifications; `None` if they could not be subscribed to
+    link_monitor: Option<LinkMonitor>,
+    state_machine: Box<dyn DhcpStateMachine + Send + Sync>,
+    options: MessageOptions,
+    applier: Box<dyn LeaseApplier>,
+    lease: Option<Lease>,
+    /// How applying the current lease went, step by step
+    apply_report: Option<ApplyReport>,
+    /// IPv4 link-local address configured while no server answers
+    link_local: Option<Ipv4Addr>,
+    /// Background claim and defense of the link-local address
+    link_local_claim: Option<LinkLocalClaim>,
+    /// Lease changes for subscribers
+    events: broadcast::Sender<ClientEvent>,
 }
 
 impl DhcpClient {
     pub async fn new(config: ClientConfig) -> Result<Self, HeraldError> {
-        let socket = crate::network::new_tokio_socket_bound_to_device(
-            &config.interface,
-            config.client_port,
-        )?;
+        let applier = applier::from_config(&config)?;
+        Self::with_applier(config, applier).await
+    }
 
-        let state_machine = Box::new(DhcpV4Handler::new(config.mac_address.clone()));
+    /// Creates a client that applies leases through `applier` instead of the
+    /// configured backend
+    pub async fn with_applier(
+        config: ClientConfig,
+        applier: Box<dyn LeaseApplier>,
+    ) -> Result<Self, HeraldError> {
+        // Sockets stay in the namespace they are created in
+        let (socket, packet_socket, link_monitor) =
+            netns::run_in(config.netns.as_ref(), || open_sockets(&config))?;
+
+        let mut options = MessageOptions::from_config(&config);
+        options.client_identifier = crate::duid::client_identifier(&config)?;
+        options.receives_unicast = packet_socket.is_some();
+
+        let state_machine = Box::new(
+            DhcpV4Handler::new(config.mac_address.clone(), options.clone())
+                .with_link_local_fallback(config.link_local_fallback),
+        );
 
         Ok(Self {
             config,
             socket,
+            packet_socket,
+            link_monitor,
             state_machine,
+            options,
+            applier,
+            lease: None,
+            apply_report: None,
+            link_local: None,
+            link_local_claim: None,
+            events: broadcast::channel(EVENT_CAPACITY).0,
         })
     }
 
+    /// Sends a message through the packet socket while unbound, or the UDP socket otherwise
+    async fn send(&self, packet: &[u8], addr: SocketAddr) -> Result<(), HeraldError> {
+        match self.packet_socket {
+            Some(ref packet_socket) if self.lease.is_none() => {
+                packet_socket.send_to(packet, addr).await?
+            }
+            _ => {
+                self.socket.send_to(packet, addr).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Receives a message through the socket matching `send`
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        match self.packet_socket {
+            Some(ref packet_socket) if self.lease.is_none() => packet_socket.recv_from(buf).await,
+            _ => self.socket.recv_from(buf).await,
+        }
+    }
+
+    /// Waits for the next carrier change; never returns without a link monitor
+    async fn next_link_event(&self) -> Result<LinkEvent, HeraldError> {
+        match self.link_monitor {
+            Some(ref monitor) => monitor.next_event().await,
+            None => std::future::pending().await,
+        }
+    }
+
     /// 等待响应或超时的通用方法
     async fn wait_for_response(&mut self, duration: Duration) -> Result<Action, HeraldError> {
         let mut buf = [0u8; 1500];
         tracing::debug!("Waiting for response with timeout: {:?}", duration);
-        match time::timeout(duration, self.socket.recv_from(&mut buf)).await {
+        let deadline = time::Instant::now() + duration;
+        let received = loop {
+            // Taken out so the claim can be polled while `self` is borrowed
+            let mut claim = self.link_local_claim.take();
+            tokio::select! {
+                received = time::timeout_at(deadline, self.recv_from(&mut buf)) => {
+                    self.link_local_claim = claim;
+                    break received;
+                }
+                event = self.next_link_event() => {
+                    self.link_local_claim = claim;
+                    return match event? {
+                        LinkEvent::CarrierDown => {
+                            self.state_machine.handle_event(Event::CarrierDown)
+                        }
+                        LinkEvent::CarrierUp => self.state_machine.handle_event(Event::CarrierUp),
+                    };
+                }
+                event = next_link_local_event(claim.as_mut()) => {
+                    self.link_local_claim = claim;
+                    self.link_local_changed(event);
+                }
+            }
+        };
+        match received {
             Ok(Ok((len, addr))) => {
                 tracing::debug!("Received {} bytes from {}", len, addr);
                 self.state_machine
@@ -97,10 +313,80 @@ impl DhcpClient {
         }
     }
 
+    /// Name of the interface the client runs on
+    pub fn interface(&self) -> &str {
+        &self.config.interface
+    }
+
+    /// How applying the current lease went, if one was applied.
+    pub fn apply_report(&self) -> Option<&ApplyReport> {
+        self.apply_report.as_ref()
+    }
+
+    /// What the client is doing and the lease it holds.
+    pub fn status(&self) -> ClientStatus {
+        ClientStatus {
+            interface: self.config.interface.clone(),
+            state: self.state_machine.state_name(),
+            address: self.lease.as_ref().map(|lease| lease.offered_ip),
+            captive_portal: self
+                .lease
+                .as_ref()
+                .and_then(|lease| lease.captive_portal.clone()),
+        }
+    }
+
+    /// Receives the lease changes from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
+
+    /// Tells subscribers, if any, about a lease change
+    fn notify(&self, event: ClientEvent) {
+        let _ = self.events.send(event);
+    }
+
     pub async fn run(&mut self) -> Result<Lease, HeraldError> {
-        // 启动状态机
-        let mut next_action = self.state_machine.handle_event(Event::Timeout)?;
+        // 启动状态机; without a carrier, DISCOVERs would only be lost
+        let next_action = match self.link_monitor {
+            Some(ref monitor) if !monitor.has_carrier() => {
+                tracing::info!(
+                    "No carrier on {}, waiting before discovery",
+                    self.config.interface
+                );
+                self.state_machine.handle_event(Event::CarrierDown)?
+            }
+            _ => self.state_machine.handle_event(Event::Timeout)?,
+        };
+        self.drive(next_action).await
+    }
 
+    /// Keeps the lease after `run` has bound it: it is renewed at T1 and
+    /// rebound at T2, and confirmed with INIT-REBOOT when the carrier returns.
+    /// Each new ACK updates the configuration. Only returns on errors.
+    pub async fn follow_link(&mut self) -> Result<(), HeraldError> {
+        if self.link_monitor.is_none() {
+            tracing::warn!("Link monitoring unavailable, not following the carrier");
+        }
+
+        loop {
+            let next_action = match self.state_machine.handle_event(Event::Timeout)? {
+                // The lease never expires, so only the carrier matters
+                Action::Exit => match self.next_link_event().await? {
+                    LinkEvent::CarrierDown => {
+                        tracing::warn!("Carrier lost on {}", self.config.interface);
+                        self.state_machine.handle_event(Event::CarrierDown)?
+                    }
+                    LinkEvent::CarrierUp => continue,
+                },
+                action => action,
+            };
+            self.drive(next_action).await?;
+        }
+    }
+
+    /// Executes actions from the state machine until a lease is bound.
+    async fn drive(&mut self, mut next_action: Action) -> Result<Lease, HeraldError> {
         loop {
             tracing::info!(
                 "State: {}, Action: {:?}",
@@ -110,29 +396,70 @@ impl DhcpClient {
 
             match next_action {
                 Action::Send(packet, addr) => {
-                    self.socket.send_to(&packet, addr).await?;
+                    self.send(&packet, addr).await?;
                     // 发送后，等待响应或超时，使用默认的超时时间
                     let timeout_duration = Duration::from_secs(5); // 5秒超时
                     next_action = self.wait_for_response(timeout_duration).await?;
                 }
+                Action::ClaimLinkLocal => {
+                    self.claim_link_local();
+                    next_action = self.state_machine.handle_event(Event::Timeout)?;
+                }
+                Action::ReleaseLinkLocal => {
+                    self.release_link_local();
+                    next_action = self.state_machine.handle_event(Event::Timeout)?;
+                }
+                Action::DropLease => {
+                    self.drop_lease();
+                    next_action = self.state_machine.handle_event(Event::Timeout)?;
+                }
                 Action::Wait(duration) => {
                     next_action = self.wait_for_response(duration).await?;
                 }
+                Action::WaitForCarrier => {
+                    self.wait_for_carrier().await?;
+                    tracing::info!("Carrier up on {}", self.config.interface);
+                    next_action = self.state_machine.handle_event(Event::CarrierUp)?;
+                }
                 Action::StoreLease(lease) => {
+                    let lease = *lease;
                     tracing::info!("DHCP Bind Successful! Lease: {:?}", lease);
+                    if let Some(ref uri) = lease.captive_portal {
+                        tracing::info!("Network advertises captive portal API at {}", uri);
+                    }
 
-                    // Apply the lease configuration to the network interface
-                    let configurator = crate::network::configurator::NetworkConfigurator::new(
-                        self.config.interface.clone(),
-                    );
+                    // The leased address replaces any link-local fallback
+                    self.release_link_local();
 
-                    if let Err(e) = configurator.apply_lease(&lease) {
-                        tracing::error!("Failed to apply network configuration: {}", e);
-                        tracing::warn!("Lease obtained but network configuration failed");
-                    } else {
-                        tracing::info!("Network configuration applied successfully");
-                    }
+                    // Apply the lease configuration to the network interface, or
+                    // bring the configuration of a confirmed lease up to date
+                    let applied = match self.lease.take() {
+                        Some(old) => self.applier.update(&old, &lease),
+                        None => self.applier.apply(&lease),
+                    };
+                    self.apply_report = match applied {
+                        Ok(report) => {
+                            tracing::info!("Network configuration applied successfully");
+                            tracing::debug!("Applied steps: {}", report);
+                            Some(report)
+                        }
+                        Err(e) => {
+                            tracing::error!("Failed to apply network configuration: {}", e);
+                            tracing::warn!(
+                                "Lease obtained but the interface is not configured for it"
+                            );
+                            match e {
+                                HeraldError::Apply(report) => Some(report),
+                                _ => None,
+                            }
+                        }
+                    };
 
+                    self.lease = Some(lease.clone());
+                    self.notify(ClientEvent::Bound {
+                        address: lease.offered_ip,
+                        captive_portal: lease.captive_portal.clone(),
+                    });
                     return Ok(lease);
                 }
                 Action::Exit => {
@@ -143,4 +470,480 @@ impl DhcpClient {
             }
         }
     }
+
+    /// Waits for the carrier to return. If a lease is configured and the
+    /// carrier stays down longer than the grace period, the configuration is
+    /// removed while waiting.
+    async fn wait_for_carrier(&mut self) -> Result<(), HeraldError> {
+        let Some(ref monitor) = self.link_monitor else {
+            return Ok(());
+        };
+
+        if let (Some(grace), Some(_)) = (self.config.carrier_loss_grace, &self.lease) {
+            if let Ok(result) = time::timeout(grace, monitor.wait_for_carrier()).await {
+                return result;
+            }
+            if let Some(lease) = self.lease.take() {
+                self.apply_report = None;
+                tracing::warn!(
+                    "Carrier down for {:?}, removing the configuration for {}",
+                    grace,
+                    lease.offered_ip
+                );
+                if let Err(e) = self.applier.remove(&lease) {
+                    tracing::warn!("Failed to remove lease configuration: {}", e);
+                }
+                self.notify(ClientEvent::Lost(lease.offered_ip));
+            }
+        }
+
+        monitor.wait_for_carrier().await
+    }
+
+    /// Stops using the current lease and removes its configuration, as
+    /// required once the server has declined it (RFC 2131 3.2)
+    fn drop_lease(&mut self) {
+        let Some(lease) = self.lease.take() else {
+            return;
+        };
+        self.apply_report = None;
+        tracing::warn!(
+            "Lease {} is no longer valid, removing its configuration",
+            lease.offered_ip
+        );
+        if let Err(e) = self.applier.remove(&lease) {
+            tracing::warn!("Failed to remove lease configuration: {}", e);
+        }
+        self.notify(ClientEvent::Lost(lease.offered_ip));
+    }
+
+    /// Starts claiming an IPv4 link-local address in the background, while
+    /// DHCP discovery goes on. Failures are only logged.
+    fn claim_link_local(&mut self) {
+        tracing::warn!("No DHCP server answered, falling back to IPv4 link-local");
+        match LinkLocalClaim::start(
+            &self.config.interface,
+            &self.config.mac_address,
+            self.config.netns.as_ref(),
+        ) {
+            Ok(claim) => self.link_local_claim = Some(claim),
+            Err(e) => tracing::error!("Failed to claim a link-local address: {}", e),
+        }
+    }
+
+    /// Configures a claimed link-local address, or removes a lost one
+    fn link_local_changed(&mut self, event: Option<LinkLocalEvent>) {
+        match event {
+            Some(LinkLocalEvent::Claimed(addr)) => match self.applier.apply_link_local(addr) {
+                Ok(()) => self.link_local = Some(addr),
+                Err(e) => {
+                    tracing::error!("Failed to configure link-local address {}: {}", addr, e)
+                }
+            },
+            Some(LinkLocalEvent::Lost(_)) => self.remove_link_local(),
+            None => self.link_local_claim = None,
+        }
+    }
+
+    /// Stops defending the IPv4 link-local address and removes it, if one
+    /// is configured.
+    fn release_link_local(&mut self) {
+        self.link_local_claim = None;
+        self.remove_link_local();
+    }
+
+    fn remove_link_local(&mut self) {
+        if let Some(addr) = self.link_local.take() {
+            if let Err(e) = self.applier.remove_link_local(addr) {
+                tracing::warn!("Failed to remove link-local address {}: {}", addr, e);
+            }
+        }
+    }
+
+    /// Releases the current lease: sends DHCPRELEASE to the server and removes
+    /// the lease configuration from the interface.
+    pub async fn release(&mut self) -> Result<(), HeraldError> {
+        let Some(lease) = self.lease.take() else {
+            return Ok(());
+        };
+        self.apply_report = None;
+
+        if let Some(server_ip) = lease.server_identifier {
+            let packet = build_dhcp_release(
+                &self.config.mac_address,
+                rand::random(),
+                lease.offered_ip,
+                server_ip,
+                &self.options,
+            )
+            .map_err(HeraldError::Protocol)?;
+            let server_addr = SocketAddr::from((server_ip, self.config.server_port));
+            tracing::info!("Releasing lease {} to {}", lease.offered_ip, server_addr);
+            self.socket.send_to(&packet, server_addr).await?;
+        } else {
+            tracing::warn!("Lease has no server identifier, not sending DHCPRELEASE");
+        }
+
+        let result = self.applier.remove(&lease);
+        self.notify(ClientEvent::Lost(lease.offered_ip));
+        result
+    }
+
+    /// Drops the lease without telling the server and removes what was
+    /// configured for it, for interfaces that have disappeared.
+    pub fn forget(&mut self) -> Result<(), HeraldError> {
+        self.release_link_local();
+        self.apply_report = None;
+        let Some(lease) = self.lease.take() else {
+            return Ok(());
+        };
+        let result = self.applier.remove(&lease);
+        self.notify(ClientEvent::Lost(lease.offered_ip));
+        result
+    }
+}
+
+/// Waits for the next change to the link-local address; never returns
+/// without a claim
+async fn next_link_local_event(claim: Option<&mut LinkLocalClaim>) -> Option<LinkLocalEvent> {
+    match claim {
+        Some(claim) => claim.next_event().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Opens the UDP socket, or an endpoint on the shared one, and where
+/// available the packet socket and the link monitor for the configured
+/// interface
+fn open_sockets(
+    config: &ClientConfig,
+) -> Result<(ClientSocket, Option<PacketSocket>, Option<LinkMonitor>), HeraldError> {
+    let socket = match config.shared_socket {
+        Some(ref shared) => {
+            let index = Interface::query(&config.interface)?.index;
+            ClientSocket::Shared(shared.endpoint(index))
+        }
+        None => ClientSocket::Device(crate::network::new_tokio_socket_bound_to_device(
+            &config.interface,
+            config.client_port,
+        )?),
+    };
+
+    // The packet socket speaks Ethernet framing only
+    let packet_socket = if config.hardware_type != HType::Eth {
+        tracing::info!(
+            "Hardware type {:?} is not Ethernet, using UDP",
+            config.hardware_type
+        );
+        None
+    } else {
+        match PacketSocket::open(&config.interface, &config.mac_address, config.client_port) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                tracing::warn!("Raw packet socket unavailable, using UDP: {}", e);
+                None
+            }
+        }
+    };
+
+    let link_monitor = match LinkMonitor::open(&config.interface) {
+        Ok(monitor) => Some(monitor),
+        Err(e) => {
+            tracing::warn!("Link monitoring unavailable, assuming a carrier: {}", e);
+            None
+        }
+    };
+
+    Ok((socket, packet_socket, link_monitor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{
+        applier::Backend,
+        netlink::{self, NetlinkHandle},
+        netns::NetNs,
+        resolv::DnsStrategy,
+    };
+    use dhcproto::{v4, Decodable as _, Encodable as _};
+    use std::{fs::File, os::fd::AsRawFd, process::Command};
+
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 77, 0, 1);
+    const LEASED: Ipv4Addr = Ipv4Addr::new(10, 77, 0, 50);
+
+    fn ip(args: &[&str]) -> bool {
+        Command::new("ip")
+            .args(args)
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    /// A DHCP server at the far end of the veth pair dc0/dc1, in a network
+    /// namespace of its own, that answers as each test scripts it
+    struct FakeServer {
+        socket: tokio::net::UdpSocket,
+        _netns: File,
+    }
+
+    impl FakeServer {
+        /// Creates dc0 for the client and moves dc1 into the server's
+        /// namespace, or returns `None` if veth pairs are unavailable
+        fn start() -> Option<Self> {
+            if !ip(&["link", "add", "dc0", "type", "veth", "peer", "name", "dc1"]) {
+                return None;
+            }
+            let file = std::thread::spawn(|| {
+                if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+                    return None;
+                }
+                File::open("/proc/thread-self/ns/net").ok()
+            })
+            .join()
+            .unwrap()?;
+            let path = format!("/proc/{}/fd/{}", std::process::id(), file.as_raw_fd());
+            assert!(ip(&["link", "set", "dc1", "netns", &path]));
+
+            let netns = NetNs::new(path);
+            for args in [
+                ["addr", "add", "10.77.0.1/24", "dev", "dc1"],
+                ["link", "set", "dev", "dc1", "up"],
+            ] {
+                let mut command = Command::new("ip");
+                command.args(args);
+                netns.apply_to(&mut command).unwrap();
+                assert!(command.status().unwrap().success());
+            }
+            assert!(ip(&["link", "set", "dev", "dc0", "up"]));
+
+            let socket = netns
+                .run(|| Ok(crate::network::new_tokio_socket_bound_to_device("dc1", 67)?))
+                .unwrap();
+            // veth leaves UDP checksums to offloading, so the client's packet
+            // socket would see them unfinished; send none instead
+            let no_check: libc::c_int = 1;
+            let ret = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_NO_CHECK,
+                    &no_check as *const libc::c_int as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            assert_eq!(ret, 0);
+            Some(Self {
+                socket,
+                _netns: file,
+            })
+        }
+
+        /// The next message from the client
+        async fn receive(&self) -> v4::Message {
+            let mut buf = [0u8; 1500];
+            let (len, _) = self.socket.recv_from(&mut buf).await.unwrap();
+            v4::Message::decode(&mut v4::Decoder::new(&buf[..len])).unwrap()
+        }
+
+        /// Answers `request` with `msg_type`, leasing `LEASED` for
+        /// `lease_time` seconds
+        async fn reply(&self, request: &v4::Message, msg_type: v4::MessageType, lease_time: u32) {
+            self.reply_with(request, msg_type, lease_time, vec![]).await;
+        }
+
+        /// Answers like `reply`, adding `extra` options
+        async fn reply_with(
+            &self,
+            request: &v4::Message,
+            msg_type: v4::MessageType,
+            lease_time: u32,
+            extra: Vec<v4::DhcpOption>,
+        ) {
+            let mut msg = v4::Message::default();
+            msg.set_opcode(v4::Opcode::BootReply)
+                .set_xid(request.xid())
+                .set_chaddr(request.chaddr());
+            msg.opts_mut().insert(v4::DhcpOption::MessageType(msg_type));
+            msg.opts_mut()
+                .insert(v4::DhcpOption::ServerIdentifier(SERVER));
+            if msg_type != v4::MessageType::Nak {
+                msg.set_yiaddr(LEASED);
+                msg.opts_mut()
+                    .insert(v4::DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 255, 0)));
+                msg.opts_mut()
+                    .insert(v4::DhcpOption::AddressLeaseTime(lease_time));
+            }
+            for opt in extra {
+                msg.opts_mut().insert(opt);
+            }
+
+            let mut packet = Vec::new();
+            msg.encode(&mut v4::Encoder::new(&mut packet)).unwrap();
+            self.socket
+                .send_to(&packet, (Ipv4Addr::BROADCAST, 68))
+                .await
+                .unwrap();
+        }
+
+        /// Leads the client through DISCOVER, OFFER, REQUEST and ACK
+        async fn bind(&self, lease_time: u32) {
+            let discover = self.receive().await;
+            self.reply(&discover, v4::MessageType::Offer, lease_time)
+                .await;
+            let request = self.receive().await;
+            self.reply(&request, v4::MessageType::Ack, lease_time).await;
+        }
+    }
+
+    fn message_type(msg: &v4::Message) -> Option<v4::MessageType> {
+        match msg.opts().get(v4::OptionCode::MessageType) {
+            Some(v4::DhcpOption::MessageType(msg_type)) => Some(*msg_type),
+            _ => None,
+        }
+    }
+
+    /// Runs `test` with a fake server and a client on dc0 that configures
+    /// the interface over netlink
+    fn with_server<F>(test: impl FnOnce(FakeServer, DhcpClient) -> F + Send + 'static)
+    where
+        F: std::future::Future<Output = ()>,
+    {
+        netlink::in_network_namespace(|| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let Some(server) = FakeServer::start() else {
+                    eprintln!("skipping: cannot create veth interfaces");
+                    return;
+                };
+                let mut config = ClientConfig::from_interface(&Interface::query("dc0").unwrap());
+                config.network_backend = Backend::Netlink;
+                config.dns_strategy = DnsStrategy::None;
+                let client = DhcpClient::new(config).await.unwrap();
+                time::timeout(Duration::from_secs(30), test(server, client))
+                    .await
+                    .expect("test timed out");
+            });
+        });
+    }
+
+    /// The addresses on dc0, with their lifetimes
+    fn installed_addresses() -> Vec<netlink::Address> {
+        let netlink = NetlinkHandle::open().unwrap();
+        let dc0 = netlink.link_index("dc0").unwrap();
+        netlink.addresses(dc0).unwrap()
+    }
+
+    /// The addresses on dc0
+    fn addresses() -> Vec<Ipv4Addr> {
+        installed_addresses()
+            .into_iter()
+            .map(|address| address.address)
+            .collect()
+    }
+
+    #[test]
+    fn test_nak_removes_declined_lease_in_namespace() {
+        with_server(|server, mut client| async move {
+            let (lease, ()) = tokio::join!(client.run(), server.bind(3600));
+            assert_eq!(lease.unwrap().offered_ip, LEASED);
+            assert_eq!(addresses(), [LEASED]);
+
+            // The server declines the lease when the carrier returns
+            let serve = async {
+                assert!(ip(&["link", "set", "dev", "dc0", "down"]));
+                assert!(ip(&["link", "set", "dev", "dc0", "up"]));
+                let request = server.receive().await;
+                assert_eq!(message_type(&request), Some(v4::MessageType::Request));
+                server.reply(&request, v4::MessageType::Nak, 0).await;
+
+                // Only asks for a new lease once the old one is removed
+                let discover = server.receive().await;
+                assert_eq!(message_type(&discover), Some(v4::MessageType::Discover));
+            };
+            tokio::select! {
+                result = client.follow_link() => panic!("client stopped: {result:?}"),
+                () = serve => {}
+            }
+            assert!(addresses().is_empty());
+            assert!(client.apply_report().is_none());
+        });
+    }
+
+    #[test]
+    fn test_lease_is_renewed_then_expires_in_namespace() {
+        with_server(|server, mut client| async move {
+            let (lease, ()) = tokio::join!(client.run(), server.bind(2));
+            assert_eq!(lease.unwrap().offered_ip, LEASED);
+
+            let serve = async {
+                // At T1 the client asks the server for more time
+                let request = server.receive().await;
+                assert_eq!(message_type(&request), Some(v4::MessageType::Request));
+                assert_eq!(request.ciaddr(), LEASED);
+                server.reply(&request, v4::MessageType::Ack, 4).await;
+
+                // The address outlives the first lease, expiring with the second
+                time::sleep(Duration::from_millis(2500)).await;
+                let installed = installed_addresses();
+                assert_eq!(installed.len(), 1);
+                assert!(matches!(installed[0].valid_lifetime, Some(1..=3)));
+
+                // Left unanswered, the lease expires and is removed before
+                // discovery starts again
+                loop {
+                    let msg = server.receive().await;
+                    if message_type(&msg) == Some(v4::MessageType::Discover) {
+                        break;
+                    }
+                    assert_eq!(msg.ciaddr(), LEASED);
+                }
+            };
+            tokio::select! {
+                result = client.follow_link() => panic!("client stopped: {result:?}"),
+                () = serve => {}
+            }
+            assert!(addresses().is_empty());
+            assert!(client.apply_report().is_none());
+        });
+    }
+
+    #[test]
+    fn test_captive_portal_in_status_and_events_in_namespace() {
+        with_server(|server, mut client| async move {
+            let mut events = client.subscribe();
+            let portal: url::Url = "https://portal.example.com/api".parse().unwrap();
+            let serve = async {
+                let discover = server.receive().await;
+                server.reply(&discover, v4::MessageType::Offer, 3600).await;
+                let request = server.receive().await;
+                let extra = vec![v4::DhcpOption::CaptivePortal(portal.clone())];
+                server
+                    .reply_with(&request, v4::MessageType::Ack, 3600, extra)
+                    .await;
+            };
+            let (lease, ()) = tokio::join!(client.run(), serve);
+            lease.unwrap();
+
+            let status = client.status();
+            assert_eq!(status.captive_portal, Some(portal.clone()));
+            assert_eq!(
+                status.to_string(),
+                "dc0: Bound, address 10.77.0.50, captive portal https://portal.example.com/api"
+            );
+            assert_eq!(
+                events.try_recv().unwrap(),
+                ClientEvent::Bound {
+                    address: LEASED,
+                    captive_portal: Some(portal),
+                }
+            );
+
+            client.release().await.unwrap();
+            assert_eq!(events.try_recv().unwrap(), ClientEvent::Lost(LEASED));
+            assert_eq!(client.status().captive_portal, None);
+        });
+    }
 }
@@ -1,23 +1,315 @@
+use crate::{
+    duid::{ClientIdMode, DEFAULT_DUID_PATH},
+    network::{
+        applier::Backend,
+        configurator::{parse_route_table, DEFAULT_ROUTE_METRIC},
+        interface::Interface,
+        netns::NetNs,
+        ntp::NtpFormat,
+        resolv::{DnsStrategy, DEFAULT_DNS_PRIORITY},
+        shared::SharedSocket,
+    },
+    v4::{
+        boot::parse_uuid,
+        options::{parse_user_class, VendorClass},
+    },
+};
 use clap::Parser;
-use std::{net::Ipv4Addr, str::FromStr, time::Duration};
+use dhcproto::v4::HType;
+use std::{net::Ipv4Addr, path::PathBuf, str::FromStr, time::Duration};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
-    /// The network interface to bind to (e.g., 'eth0', 'lo')
-    #[arg(short, long)]
-    pub interface: String,
+    /// The network interface to bind to (e.g., 'eth0', 'lo'); may be repeated
+    /// to run a client per interface in one process
+    #[arg(short, long, required_unless_present = "hotplug")]
+    pub interface: Vec<String>,
+
+    /// Vendor class identifier to send in option 60
+    #[arg(long)]
+    pub vendor_class: Option<String>,
+
+    /// User class to send in option 77, at most 255 bytes (may be repeated)
+    #[arg(long, value_parser = parse_user_class)]
+    pub user_class: Vec<String>,
+
+    /// Vendor class to send in option 124, as <enterprise-number>:<data> (may be repeated)
+    #[arg(long)]
+    pub vendor_identifying_class: Vec<VendorClass>,
+
+    /// Client identifier mode: mac, duid-llt, duid-en, duid-ll or duid-uuid
+    #[arg(long, default_value_t = ClientIdMode::Mac)]
+    pub client_id: ClientIdMode,
+
+    /// IAID for DUID-based client identifiers (defaults to the last 4 bytes
+    /// of the MAC); offset by the interface index with several interfaces
+    #[arg(long)]
+    pub iaid: Option<u32>,
+
+    /// Enterprise number for DUID-EN client identifiers
+    #[arg(long)]
+    pub duid_enterprise_number: Option<u32>,
+
+    /// File in which the generated DUID is persisted
+    #[arg(long, default_value = DEFAULT_DUID_PATH)]
+    pub duid_file: PathBuf,
+
+    /// Export NTP servers (option 42) as a chrony or timesyncd drop-in
+    #[arg(long)]
+    pub ntp_format: Option<NtpFormat>,
+
+    /// Directory for the NTP drop-in (defaults depend on --ntp-format)
+    #[arg(long, requires = "ntp_format")]
+    pub ntp_dir: Option<PathBuf>,
+
+    /// Request option 108 and pause DHCPv4 on IPv6-only networks (RFC 8925)
+    #[arg(long)]
+    pub ipv6_only_preferred: bool,
+
+    /// PXE client system architecture to send in option 93 (e.g. 7 for EFI x86-64)
+    #[arg(long)]
+    pub client_arch: Option<u16>,
+
+    /// PXE client machine UUID to send in option 97
+    #[arg(long, value_parser = parse_uuid)]
+    pub client_uuid: Option<[u8; 16]>,
+
+    /// Link /etc/localtime to the tz database zone from option 101
+    #[arg(long)]
+    pub apply_timezone: bool,
+
+    /// Fall back to an IPv4 link-local address after this many unanswered DISCOVERs
+    #[arg(long, value_name = "ATTEMPTS")]
+    pub link_local_after: Option<u32>,
+
+    /// Script run with the lease in HERALD_* variables whenever it is applied or removed
+    #[arg(long, value_name = "PATH")]
+    pub hook: Option<PathBuf>,
+
+    /// How to apply the lease: iproute2, netlink, dry-run or none
+    #[arg(long, default_value_t = Backend::Iproute2)]
+    pub backend: Backend,
+
+    /// Metric of the default route via the first router, or IFACE=METRIC for
+    /// one interface (may be repeated)
+    #[arg(long, value_name = "[IFACE=]METRIC", value_parser = parse_route_metric)]
+    pub route_metric: Vec<PerInterface<u32>>,
+
+    /// Routing table for source-based policy routing from the leased address,
+    /// or IFACE=TABLE for one interface (may be repeated)
+    #[arg(long, value_name = "[IFACE=]TABLE", value_parser = parse_route_table_arg)]
+    pub route_table: Vec<PerInterface<u32>>,
+
+    /// How DNS servers reach the resolver: auto, merge, resolvconf or none
+    #[arg(long, default_value_t = DnsStrategy::Auto)]
+    pub dns_strategy: DnsStrategy,
+
+    /// Priority of the DNS servers when merging, lower listed first, or
+    /// IFACE=PRIORITY for one interface (may be repeated)
+    #[arg(long, value_name = "[IFACE=]PRIORITY", value_parser = parse_dns_priority)]
+    pub dns_priority: Vec<PerInterface<u32>>,
+
+    /// Keep running after binding to renew the lease and confirm it again when the carrier returns
+    #[arg(long)]
+    pub follow_link: bool,
+
+    /// Remove the lease configuration once the carrier has been down this long
+    #[arg(long, value_name = "SECONDS")]
+    pub carrier_loss_grace: Option<u64>,
+
+    /// Network namespace of the interface, by `ip netns` name or path
+    #[arg(long, value_name = "NAME|PATH")]
+    pub netns: Option<NetNs>,
+
+    /// Receive on one port 68 socket for all interfaces, told apart by IP_PKTINFO
+    #[arg(long)]
+    pub shared_socket: bool,
+
+    /// Start and stop clients as interfaces matching --allow and --deny appear and disappear
+    #[arg(long)]
+    pub hotplug: bool,
+
+    /// Glob pattern of interfaces to manage with --hotplug (may be repeated; default: all)
+    #[arg(long, value_name = "PATTERN", requires = "hotplug")]
+    pub allow: Vec<String>,
+
+    /// Glob pattern of interfaces never to manage with --hotplug (may be repeated)
+    #[arg(long, value_name = "PATTERN", requires = "hotplug")]
+    pub deny: Vec<String>,
+}
+
+impl Args {
+    /// Builds the configuration of the client for `interface`.
+    ///
+    /// When several interfaces may be managed, a route metric or table given
+    /// for all of them is offset by the interface index, so that their
+    /// default routes do not replace each other.
+    pub fn client_config(&self, interface: &Interface) -> ClientConfig {
+        let several = self.hotplug || self.interface.len() > 1;
+        let index = if several { interface.index } else { 0 };
+
+        let mut config = ClientConfig::from_interface(interface);
+        config.vendor_class = self.vendor_class.clone();
+        config.user_classes = self.user_class.clone();
+        config.vendor_identifying_classes = self.vendor_identifying_class.clone();
+        config.client_id_mode = self.client_id;
+        // Interfaces sharing the DUID need IAIDs of their own (RFC 4361)
+        config.iaid = self.iaid.map(|iaid| iaid.wrapping_add(index));
+        config.duid_enterprise_number = self.duid_enterprise_number;
+        config.duid_path = self.duid_file.clone();
+        config.ntp_format = self.ntp_format;
+        config.ntp_directory = self.ntp_dir.clone();
+        config.ipv6_only_preferred = self.ipv6_only_preferred;
+        config.client_architecture = self.client_arch;
+        config.client_machine_id = self.client_uuid;
+        config.apply_timezone = self.apply_timezone;
+        config.link_local_fallback = self.link_local_after;
+        config.hook = self.hook.clone();
+        config.network_backend = self.backend;
+        config.route_metric = match PerInterface::lookup(&self.route_metric, &interface.name) {
+            (Some(metric), _) => metric,
+            (None, common) => common.unwrap_or(DEFAULT_ROUTE_METRIC).saturating_add(index),
+        };
+        config.route_table = match PerInterface::lookup(&self.route_table, &interface.name) {
+            (Some(table), _) => Some(table),
+            (None, common) => common.map(|table| offset_route_table(table, index)),
+        };
+        config.dns_strategy = self.dns_strategy;
+        let (own, common) = PerInterface::lookup(&self.dns_priority, &interface.name);
+        config.dns_priority = own.or(common).unwrap_or(DEFAULT_DNS_PRIORITY);
+        config.carrier_loss_grace = self.carrier_loss_grace.map(Duration::from_secs);
+        config.netns = self.netns.clone();
+        config
+    }
+}
+
+/// A setting for every interface, or for one given as `IFACE=VALUE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PerInterface<T> {
+    pub interface: Option<String>,
+    pub value: T,
+}
+
+impl<T: Copy> PerInterface<T> {
+    /// The last value given for `interface` and the last one given for
+    /// every interface.
+    pub fn lookup(values: &[Self], interface: &str) -> (Option<T>, Option<T>) {
+        let last = |wanted: Option<&str>| {
+            values
+                .iter()
+                .rev()
+                .find(|v| v.interface.as_deref() == wanted)
+                .map(|v| v.value)
+        };
+        (last(Some(interface)), last(None))
+    }
+
+    fn parse(s: &str, parse: impl Fn(&str) -> Result<T, String>) -> Result<Self, String> {
+        match s.split_once('=') {
+            Some(("", _)) => Err(format!("missing interface name in '{s}'")),
+            Some((interface, value)) => Ok(Self {
+                interface: Some(interface.to_string()),
+                value: parse(value)?,
+            }),
+            None => Ok(Self {
+                interface: None,
+                value: parse(s)?,
+            }),
+        }
+    }
+}
+
+fn parse_route_metric(s: &str) -> Result<PerInterface<u32>, String> {
+    PerInterface::parse(s, |value| {
+        value
+            .parse()
+            .map_err(|e| format!("invalid metric '{value}': {e}"))
+    })
+}
+
+fn parse_route_table_arg(s: &str) -> Result<PerInterface<u32>, String> {
+    PerInterface::parse(s, parse_route_table)
+}
+
+fn parse_dns_priority(s: &str) -> Result<PerInterface<u32>, String> {
+    PerInterface::parse(s, |value| {
+        value
+            .parse()
+            .map_err(|e| format!("invalid priority '{value}': {e}"))
+    })
+}
+
+/// `table` offset by `index`, stepping over the reserved tables 253 to 255
+fn offset_route_table(table: u32, index: u32) -> u32 {
+    let offset = table.saturating_add(index);
+    if table < 253 && offset >= 253 {
+        offset.saturating_add(3)
+    } else {
+        offset
+    }
 }
 
 #[allow(dead_code)]
 pub struct ClientConfig {
     pub interface: String,
     pub mac_address: bytes::Bytes,
+    /// Hardware type (htype) sent with `mac_address` as chaddr
+    pub hardware_type: HType,
     pub client_port: u16,
     pub server_port: u16,
     pub broadcast_address: std::net::Ipv4Addr,
     pub initial_timeout: Duration,
     pub request_timeout: Duration,
+    /// Vendor class identifier (option 60)
+    pub vendor_class: Option<String>,
+    /// User classes (option 77)
+    pub user_classes: Vec<String>,
+    /// Vendor-identifying vendor classes (option 124)
+    pub vendor_identifying_classes: Vec<VendorClass>,
+    /// How the client identifier (option 61) is constructed
+    pub client_id_mode: ClientIdMode,
+    /// IAID for DUID-based client identifiers
+    pub iaid: Option<u32>,
+    /// Enterprise number for DUID-EN client identifiers
+    pub duid_enterprise_number: Option<u32>,
+    /// File in which the generated DUID is persisted
+    pub duid_path: PathBuf,
+    /// Time daemon format for exported NTP servers; `None` disables the export
+    pub ntp_format: Option<NtpFormat>,
+    /// Directory for the NTP drop-in; `None` uses the format's default
+    pub ntp_directory: Option<PathBuf>,
+    /// Whether the host can operate IPv6-only and honors option 108
+    pub ipv6_only_preferred: bool,
+    /// PXE client system architecture (option 93)
+    pub client_architecture: Option<u16>,
+    /// PXE client machine UUID (option 97)
+    pub client_machine_id: Option<[u8; 16]>,
+    /// Whether to link /etc/localtime to the zone from option 101
+    pub apply_timezone: bool,
+    /// Unanswered DISCOVERs before falling back to IPv4 link-local; `None` disables the fallback
+    pub link_local_fallback: Option<u32>,
+    /// Script run after the lease configuration changes; `None` runs nothing
+    pub hook: Option<PathBuf>,
+    /// How the lease is applied to the host
+    pub network_backend: Backend,
+    /// Metric of the default route via the first router
+    pub route_metric: u32,
+    /// Routing table for source-based policy routing; `None` disables it
+    pub route_table: Option<u32>,
+    /// How DNS servers from option 6 reach the resolver
+    pub dns_strategy: DnsStrategy,
+    /// Priority of this interface's DNS servers among all interfaces
+    pub dns_priority: u32,
+    /// How long the carrier may be down before the lease configuration is
+    /// removed; `None` keeps it until the lease is confirmed again
+    pub carrier_loss_grace: Option<Duration>,
+    /// Network namespace that sockets are created and the interface is
+    /// configured in; `None` uses herald's own
+    pub netns: Option<NetNs>,
+    /// Socket shared with other interfaces' clients; `None` binds a socket
+    /// to this interface alone
+    pub shared_socket: Option<SharedSocket>,
 }
 
 impl ClientConfig {
@@ -25,12 +317,106 @@ impl ClientConfig {
         Self {
             interface,
             mac_address,
+            hardware_type: HType::Eth,
             client_port: 68,
             server_port: 67,
             broadcast_address: Ipv4Addr::from_str("255.255.255.255")
                 .expect("Invalid hardcoded broadcast address"),
             initial_timeout: Duration::from_secs(5),
             request_timeout: Duration::from_secs(10),
+            vendor_class: None,
+            user_classes: Vec::new(),
+            vendor_identifying_classes: Vec::new(),
+            client_id_mode: ClientIdMode::Mac,
+            iaid: None,
+            duid_enterprise_number: None,
+            duid_path: PathBuf::from(DEFAULT_DUID_PATH),
+            ntp_format: None,
+            ntp_directory: None,
+            ipv6_only_preferred: false,
+            client_architecture: None,
+            client_machine_id: None,
+            apply_timezone: false,
+            link_local_fallback: None,
+            hook: None,
+            network_backend: Backend::Iproute2,
+            route_metric: DEFAULT_ROUTE_METRIC,
+            route_table: None,
+            dns_strategy: DnsStrategy::Auto,
+            dns_priority: DEFAULT_DNS_PRIORITY,
+            carrier_loss_grace: None,
+            netns: None,
+            shared_socket: None,
+        }
+    }
+
+    /// Creates a configuration for `interface`, using its hardware address
+    /// and type.
+    pub fn from_interface(interface: &Interface) -> Self {
+        let mut config = Self::new(interface.name.clone(), interface.hardware_address.clone());
+        match interface.dhcp_hardware_type() {
+            Some(htype) => config.hardware_type = htype,
+            None => tracing::warn!(
+                "Interface {} has no DHCP hardware type (ARPHRD {}), using Ethernet",
+                interface.name,
+                interface.hardware_type
+            ),
         }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_per_interface() {
+        let values = vec![
+            parse_route_metric("100").unwrap(),
+            parse_route_metric("eth1=50").unwrap(),
+            parse_route_metric("200").unwrap(),
+        ];
+        assert_eq!(PerInterface::lookup(&values, "eth0"), (None, Some(200)));
+        assert_eq!(PerInterface::lookup(&values, "eth1"), (Some(50), Some(200)));
+        assert!(parse_route_metric("=50").is_err());
+        assert!(parse_route_metric("eth0=x").is_err());
+        assert!(parse_route_table_arg("eth0=254").is_err());
+    }
+
+    #[test]
+    fn test_long_user_class_is_rejected() {
+        let long = "x".repeat(256);
+        let args = ["herald", "-i", "eth0", "--user-class", &long];
+        let error = Args::try_parse_from(args).unwrap_err();
+        assert!(error.to_string().contains("more than 255"));
+    }
+
+    #[test]
+    fn test_iaid_per_interface() {
+        let interface = |name: &str, index| Interface {
+            name: name.to_string(),
+            index,
+            hardware_type: libc::ARPHRD_ETHER,
+            hardware_address: bytes::Bytes::from_static(&[2, 0, 0, 0, 0, 1]),
+            mtu: 1500,
+            flags: 0,
+        };
+
+        let args = Args::parse_from(["herald", "-i", "eth0", "--iaid", "100"]);
+        assert_eq!(args.client_config(&interface("eth0", 2)).iaid, Some(100));
+
+        let args = Args::parse_from(["herald", "-i", "eth0", "-i", "eth1", "--iaid", "100"]);
+        assert_eq!(args.client_config(&interface("eth0", 2)).iaid, Some(102));
+        assert_eq!(args.client_config(&interface("eth1", 3)).iaid, Some(103));
+    }
+
+    #[test]
+    fn test_offset_route_table() {
+        assert_eq!(offset_route_table(100, 0), 100);
+        assert_eq!(offset_route_table(100, 2), 102);
+        assert_eq!(offset_route_table(250, 3), 256);
+        assert_eq!(offset_route_table(252, 1), 256);
+        assert_eq!(offset_route_table(300, 1), 301);
     }
 }
@@ -0,0 +1,414 @@
+//! DHCP Unique Identifier (DUID) based client identifiers
+//!
+//! This module implements RFC 4361 node-specific client identifiers:
+//! - DUID generation (LLT, EN, LL and UUID types from RFC 8415)
+//! - DUID persistence across restarts
+//! - Encoding of IAID and DUID into the client identifier option (61)
+
+use crate::{config::ClientConfig, error::HeraldError, v4::message::link_layer_identifier};
+use std::{
+    fmt, fs,
+    path::Path,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Default location of the persisted DUID.
+pub const DEFAULT_DUID_PATH: &str = "/var/lib/herald/duid";
+
+/// Location of the systemd machine ID used for DUID-UUID.
+pub const MACHINE_ID_PATH: &str = "/etc/machine-id";
+
+/// Client identifier type for a DUID-based identifier (RFC 4361 section 6.1).
+const CLIENT_ID_TYPE_DUID: u8 = 255;
+
+/// Seconds between the Unix epoch and the DUID-LLT epoch (2000-01-01 00:00:00 UTC).
+const DUID_TIME_EPOCH: u64 = 946_684_800;
+
+/// How the client identifier (option 61) is constructed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClientIdMode {
+    /// Hardware type followed by the MAC address
+    #[default]
+    Mac,
+    /// DUID based on link-layer address plus time
+    DuidLlt,
+    /// DUID assigned by vendor based on enterprise number
+    DuidEn,
+    /// DUID based on link-layer address
+    DuidLl,
+    /// DUID based on the machine ID
+    DuidUuid,
+}
+
+impl ClientIdMode {
+    /// The DUID type generated by this mode, or `None` for [`ClientIdMode::Mac`].
+    fn duid_type(self) -> Option<u16> {
+        match self {
+            ClientIdMode::Mac => None,
+            ClientIdMode::DuidLlt => Some(Duid::TYPE_LLT),
+            ClientIdMode::DuidEn => Some(Duid::TYPE_EN),
+            ClientIdMode::DuidLl => Some(Duid::TYPE_LL),
+            ClientIdMode::DuidUuid => Some(Duid::TYPE_UUID),
+        }
+    }
+}
+
+impl FromStr for ClientIdMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mac" => Ok(ClientIdMode::Mac),
+            "duid-llt" => Ok(ClientIdMode::DuidLlt),
+            "duid-en" => Ok(ClientIdMode::DuidEn),
+            "duid-ll" => Ok(ClientIdMode::DuidLl),
+            "duid-uuid" => Ok(ClientIdMode::DuidUuid),
+            _ => Err(format!(
+                "unknown client-id mode '{s}' (expected mac, duid-llt, duid-en, duid-ll or duid-uuid)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ClientIdMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ClientIdMode::Mac => "mac",
+            ClientIdMode::DuidLlt => "duid-llt",
+            ClientIdMode::DuidEn => "duid-en",
+            ClientIdMode::DuidLl => "duid-ll",
+            ClientIdMode::DuidUuid => "duid-uuid",
+        })
+    }
+}
+
+/// A DHCP Unique Identifier as defined in RFC 8415 section 11.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Duid(Vec<u8>);
+
+impl Duid {
+    pub const TYPE_LLT: u16 = 1;
+    pub const TYPE_EN: u16 = 2;
+    pub const TYPE_LL: u16 = 3;
+    pub const TYPE_UUID: u16 = 4;
+
+    /// DUID-LLT: hardware type, time and link-layer address.
+    pub fn llt(htype: u16, time: u32, link_layer_address: &[u8]) -> Self {
+        let mut bytes = Vec::with_capacity(8 + link_layer_address.len());
+        bytes.extend_from_slice(&Self::TYPE_LLT.to_be_bytes());
+        bytes.extend_from_slice(&htype.to_be_bytes());
+        bytes.extend_from_slice(&time.to_be_bytes());
+        bytes.extend_from_slice(link_layer_address);
+        Self(bytes)
+    }
+
+    /// DUID-EN: enterprise number and vendor-assigned identifier.
+    pub fn en(enterprise_number: u32, identifier: &[u8]) -> Self {
+        let mut bytes = Vec::with_capacity(6 + identifier.len());
+        bytes.extend_from_slice(&Self::TYPE_EN.to_be_bytes());
+        bytes.extend_from_slice(&enterprise_number.to_be_bytes());
+        bytes.extend_from_slice(identifier);
+        Self(bytes)
+    }
+
+    /// DUID-LL: hardware type and link-layer address.
+    pub fn ll(htype: u16, link_layer_address: &[u8]) -> Self {
+        let mut bytes = Vec::with_capacity(4 + link_layer_address.len());
+        bytes.extend_from_slice(&Self::TYPE_LL.to_be_bytes());
+        bytes.extend_from_slice(&htype.to_be_bytes());
+        bytes.extend_from_slice(link_layer_address);
+        Self(bytes)
+    }
+
+    /// DUID-UUID: a 128-bit UUID (RFC 6355).
+    pub fn uuid(uuid: [u8; 16]) -> Self {
+        let mut bytes = Vec::with_capacity(18);
+        bytes.extend_from_slice(&Self::TYPE_UUID.to_be_bytes());
+        bytes.extend_from_slice(&uuid);
+        Self(bytes)
+    }
+
+    /// The DUID type code, or `None` if the DUID is too short to carry one.
+    pub fn duid_type(&self) -> Option<u16> {
+        self.0
+            .get(..2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl FromStr for Duid {
+    type Err = String;
+
+    /// Parses a colon-separated hex string, e.g. `00:03:00:01:00:0c:29:a8:92:f4`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = s
+            .trim()
+            .split(':')
+            .map(|byte| u8::from_str_radix(byte, 16).map_err(|e| format!("invalid DUID: {e}")))
+            .collect::<Result<Vec<_>, _>>()?;
+        // RFC 8415: a DUID is between 3 and 130 octets including the type
+        if !(3..=130).contains(&bytes.len()) {
+            return Err(format!("invalid DUID length {}", bytes.len()));
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl fmt::Display for Duid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = self
+            .0
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(":");
+        f.write_str(&hex)
+    }
+}
+
+/// Builds the RFC 4361 client identifier: type 255, IAID, then the DUID.
+pub fn encode_client_identifier(iaid: u32, duid: &Duid) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(5 + duid.as_bytes().len());
+    bytes.push(CLIENT_ID_TYPE_DUID);
+    bytes.extend_from_slice(&iaid.to_be_bytes());
+    bytes.extend_from_slice(duid.as_bytes());
+    bytes
+}
+
+/// Derives a default IAID from the last four bytes of the hardware address.
+pub fn default_iaid(mac_address: &[u8]) -> u32 {
+    let mut iaid = [0u8; 4];
+    let tail = &mac_address[mac_address.len().saturating_sub(4)..];
+    iaid[4 - tail.len()..].copy_from_slice(tail);
+    u32::from_be_bytes(iaid)
+}
+
+/// Reads the machine ID (32 hex digits) as a 16-byte UUID.
+pub fn read_machine_id(path: &Path) -> Result<[u8; 16], HeraldError> {
+    let contents = fs::read_to_string(path)?;
+    let hex = contents.trim();
+    if hex.len() != 32 || !hex.is_ascii() {
+        return Err(HeraldError::ClientIdentifier(format!(
+            "malformed machine ID in {}",
+            path.display()
+        )));
+    }
+
+    let mut uuid = [0u8; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| {
+            HeraldError::ClientIdentifier(format!("malformed machine ID in {}", path.display()))
+        })?;
+    }
+    Ok(uuid)
+}
+
+/// Hardware type used in DUID-LLT and DUID-LL, the interface's htype.
+fn duid_htype(config: &ClientConfig) -> u16 {
+    u8::from(config.hardware_type).into()
+}
+
+/// Link-layer address used in DUID-LLT and DUID-LL.
+fn link_layer_address(config: &ClientConfig) -> &[u8] {
+    link_layer_identifier(config.hardware_type, &config.mac_address)
+}
+
+/// Generates a fresh DUID for `mode`.
+fn generate_duid(mode: ClientIdMode, config: &ClientConfig) -> Result<Duid, HeraldError> {
+    match mode {
+        ClientIdMode::Mac => Err(HeraldError::ClientIdentifier(
+            "MAC client-id mode does not use a DUID".to_string(),
+        )),
+        ClientIdMode::DuidLlt => {
+            let since_epoch = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::ZERO)
+                .as_secs();
+            let time = since_epoch.saturating_sub(DUID_TIME_EPOCH) as u32;
+            Ok(Duid::llt(
+                duid_htype(config),
+                time,
+                link_layer_address(config),
+            ))
+        }
+        ClientIdMode::DuidEn => {
+            let enterprise_number = config.duid_enterprise_number.ok_or_else(|| {
+                HeraldError::ClientIdentifier(
+                    "duid-en mode requires an enterprise number".to_string(),
+                )
+            })?;
+            let identifier: [u8; 16] = rand::random();
+            Ok(Duid::en(enterprise_number, &identifier))
+        }
+        ClientIdMode::DuidLl => Ok(Duid::ll(duid_htype(config), link_layer_address(config))),
+        ClientIdMode::DuidUuid => Ok(Duid::uuid(read_machine_id(Path::new(MACHINE_ID_PATH))?)),
+    }
+}
+
+/// Loads the persisted DUID for `mode`, generating and persisting a new one if
+/// none exists or the stored DUID is of a different type.
+pub fn load_or_create_duid(config: &ClientConfig) -> Result<Duid, HeraldError> {
+    let path = &config.duid_path;
+    let mode = config.client_id_mode;
+
+    match fs::read_to_string(path) {
+        Ok(contents) => match contents.parse::<Duid>() {
+            Ok(duid) if duid.duid_type() == mode.duid_type() => {
+                tracing::debug!("Loaded DUID {} from {}", duid, path.display());
+                return Ok(duid);
+            }
+            Ok(duid) => {
+                tracing::info!(
+                    "Stored DUID {} does not match client-id mode {}, regenerating",
+                    duid,
+                    mode
+                );
+            }
+            Err(e) => {
+                tracing::warn!("Ignoring unreadable DUID in {}: {}", path.display(), e);
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(HeraldError::Io(e)),
+    }
+
+    let duid = generate_duid(mode, config)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, format!("{duid}\n"))?;
+    tracing::info!(
+        "Generated DUID {} and stored it in {}",
+        duid,
+        path.display()
+    );
+    Ok(duid)
+}
+
+/// Returns the client identifier configured for `config`, or `None` to use the
+/// hardware address based identifier.
+pub fn client_identifier(config: &ClientConfig) -> Result<Option<Vec<u8>>, HeraldError> {
+    if config.client_id_mode == ClientIdMode::Mac {
+        return Ok(None);
+    }
+
+    let duid = load_or_create_duid(config)?;
+    let iaid = config
+        .iaid
+        .unwrap_or_else(|| default_iaid(&config.mac_address));
+    Ok(Some(encode_client_identifier(iaid, &duid)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn test_config(dir: &Path, mode: ClientIdMode) -> ClientConfig {
+        let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+        let mut config = ClientConfig::new("eth0".to_string(), mac_addr);
+        config.client_id_mode = mode;
+        config.duid_path = dir.join("duid");
+        config
+    }
+
+    #[test]
+    fn test_infiniband_duid_uses_port_guid() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), ClientIdMode::DuidLl);
+        let guid = [0x00, 0x02, 0xc9, 0x03, 0x00, 0x0a, 0x0b, 0x0c];
+        let mut address = vec![0x80, 0x00, 0x04, 0x04, 0xfe, 0x80, 0, 0, 0, 0, 0, 0];
+        address.extend_from_slice(&guid);
+        config.mac_address = Bytes::from(address);
+        config.hardware_type = dhcproto::v4::HType::from(32);
+
+        let duid = load_or_create_duid(&config).unwrap();
+        assert_eq!(duid, Duid::ll(32, &guid));
+    }
+
+    #[test]
+    fn test_encode_client_identifier() {
+        let duid = Duid::ll(1, &[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+        let client_id = encode_client_identifier(0x0102_0304, &duid);
+        assert_eq!(
+            client_id,
+            [255, 1, 2, 3, 4, 0, 3, 0, 1, 0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]
+        );
+    }
+
+    #[test]
+    fn test_default_iaid() {
+        assert_eq!(
+            default_iaid(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]),
+            0x29a8_92f4
+        );
+        assert_eq!(default_iaid(&[0xab, 0xcd]), 0xabcd);
+    }
+
+    #[test]
+    fn test_duid_round_trip() {
+        let duid = Duid::en(43793, &[1, 2, 3]);
+        assert_eq!(duid.to_string(), "00:02:00:00:ab:11:01:02:03");
+        assert_eq!(duid.to_string().parse::<Duid>().unwrap(), duid);
+        assert!("00:02".parse::<Duid>().is_err());
+    }
+
+    #[test]
+    fn test_mac_mode_has_no_duid() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path(), ClientIdMode::Mac);
+        assert_eq!(client_identifier(&config).unwrap(), None);
+        assert!(!config.duid_path.exists());
+    }
+
+    #[test]
+    fn test_duid_llt_is_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path(), ClientIdMode::DuidLlt);
+
+        let first = load_or_create_duid(&config).unwrap();
+        assert_eq!(first.duid_type(), Some(Duid::TYPE_LLT));
+        let second = load_or_create_duid(&config).unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_duid_regenerated_on_mode_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), ClientIdMode::DuidLlt);
+        load_or_create_duid(&config).unwrap();
+
+        config.client_id_mode = ClientIdMode::DuidLl;
+        let duid = load_or_create_duid(&config).unwrap();
+        assert_eq!(duid, Duid::ll(1, &config.mac_address));
+    }
+
+    #[test]
+    fn test_duid_en_requires_enterprise_number() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), ClientIdMode::DuidEn);
+        assert!(load_or_create_duid(&config).is_err());
+
+        config.duid_enterprise_number = Some(43793);
+        let duid = load_or_create_duid(&config).unwrap();
+        assert_eq!(&duid.as_bytes()[..6], &[0, 2, 0, 0, 0xab, 0x11]);
+    }
+
+    #[test]
+    fn test_read_machine_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("machine-id");
+        fs::write(&path, "0123456789abcdef0123456789abcdef\n").unwrap();
+        let uuid = read_machine_id(&path).unwrap();
+        assert_eq!(uuid[0], 0x01);
+        assert_eq!(uuid[15], 0xef);
+
+        fs::write(&path, "not-a-machine-id\n").unwrap();
+        assert!(read_machine_id(&path).is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::network::SocketError;
+use crate::network::{configurator::ApplyReport, netlink::NetlinkError, SocketError};
 use std::{error::Error as StdError, io};
 use thiserror::Error;
 
@@ -11,14 +11,20 @@ pub enum HeraldError {
     Io(#[from] io::Error),
 
     #[error("DHCP protocol error")]
-    Protocol(#[from] Box<dyn StdError>),
+    Protocol(#[from] Box<dyn StdError + Send + Sync>),
 
-    #[error("Failed to parse MAC address: {0}")]
-    MacParse(String),
+    #[error("Netlink operation failed: {0}")]
+    Netlink(#[from] NetlinkError),
+
+    #[error("Lease configuration failed and was rolled back ({0})")]
+    Apply(ApplyReport),
 
     #[error("Interface '{0}' not found or has no MAC address")]
     InterfaceInvalid(String),
 
+    #[error("Failed to set up client identifier: {0}")]
+    ClientIdentifier(String),
+
     #[error("State machine reached a critical failure: {0}")]
     Critical(String),
 }
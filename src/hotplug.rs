@@ -0,0 +1,283 @@
+//! Hotplug
+//!
+//! Watches rtnetlink for interfaces appearing and disappearing and starts or
+//! stops an [`InterfaceManager`] client for those matching the configured
+//! patterns.
+
+use crate::{
+    config::ClientConfig,
+    error::HeraldError,
+    manager::InterfaceManager,
+    network::{
+        interface::Interface,
+        link::{InterfaceEvent, InterfaceWatcher},
+        netlink::NetlinkHandle,
+        netns::{self, NetNs},
+    },
+};
+use std::collections::HashMap;
+
+/// Which interfaces are managed, by shell-style glob patterns on the name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterfaceFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl InterfaceFilter {
+    pub fn new(allow: Vec<String>, deny: Vec<String>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// Whether `name` matches an allow pattern, or there are none, and no
+    /// deny pattern.
+    pub fn matches(&self, name: &str) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|p| glob_match(p, name));
+        allowed && !self.deny.iter().any(|p| glob_match(p, name))
+    }
+}
+
+/// Matches `name` against a pattern where `*` stands for any run of
+/// characters and `?` for any one character.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position after the last `*` and the name position it was tried at
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` swallow one more character
+                Some((star, start)) => {
+                    p = star;
+                    n = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Starts and stops clients as matching interfaces come and go.
+pub struct Hotplug<F> {
+    filter: InterfaceFilter,
+    make_config: F,
+    watcher: InterfaceWatcher,
+    netlink: NetlinkHandle,
+    /// Interfaces whose clients are stopped when they vanish, by index
+    managed: HashMap<u32, String>,
+}
+
+impl<F: Fn(&Interface) -> ClientConfig> Hotplug<F> {
+    /// Subscribes to link notifications in `netns`, or herald's own
+    /// namespace. `make_config` builds the configuration for each new
+    /// interface.
+    pub fn open(
+        filter: InterfaceFilter,
+        make_config: F,
+        netns: Option<&NetNs>,
+    ) -> Result<Self, HeraldError> {
+        // Subscribe before listing interfaces so none is missed
+        let (watcher, netlink) = netns::run_in(netns, || {
+            Ok((InterfaceWatcher::open()?, NetlinkHandle::open()?))
+        })?;
+        Ok(Self {
+            filter,
+            make_config,
+            watcher,
+            netlink,
+            managed: HashMap::new(),
+        })
+    }
+
+    /// Manages the matching interfaces present now and any that appear
+    /// later, until an error occurs.
+    pub async fn run(&mut self, manager: &mut InterfaceManager) -> Result<(), HeraldError> {
+        self.sync(manager).await?;
+        loop {
+            for event in self.watcher.next_events().await? {
+                match event {
+                    InterfaceEvent::New(interface) => self.appeared(manager, interface).await,
+                    InterfaceEvent::Removed(interface) => {
+                        self.vanished(manager, interface.index).await
+                    }
+                    InterfaceEvent::Overflow => self.sync(manager).await?,
+                }
+            }
+        }
+    }
+
+    /// Brings the clients in line with the interfaces that exist now
+    async fn sync(&mut self, manager: &mut InterfaceManager) -> Result<(), HeraldError> {
+        let interfaces = self.netlink.links()?;
+        let gone: Vec<u32> = self
+            .managed
+            .keys()
+            .filter(|&&index| !interfaces.iter().any(|i| i.index == index))
+            .copied()
+            .collect();
+        for index in gone {
+            self.vanished(manager, index).await;
+        }
+        for interface in interfaces {
+            self.appeared(manager, interface).await;
+        }
+        Ok(())
+    }
+
+    async fn appeared(&mut self, manager: &mut InterfaceManager, interface: Interface) {
+        match self.managed.get(&interface.index) {
+            Some(name) if *name == interface.name => return,
+            // Renamed, which most often happens right after the NIC appears
+            Some(_) => self.vanished(manager, interface.index).await,
+            None => {}
+        }
+        if interface.is_loopback() {
+            return;
+        }
+        // Started on the command line, but stopped like the others
+        if manager.contains(&interface.name) {
+            self.managed.insert(interface.index, interface.name);
+            return;
+        }
+        if !self.filter.matches(&interface.name) {
+            return;
+        }
+
+        tracing::info!("Interface {} appeared", interface.name);
+        match manager.add((self.make_config)(&interface)).await {
+            Ok(()) => {
+                self.managed.insert(interface.index, interface.name);
+            }
+            Err(e) => tracing::warn!("Failed to start client on {}: {}", interface.name, e),
+        }
+    }
+
+    async fn vanished(&mut self, manager: &mut InterfaceManager, index: u32) {
+        let Some(name) = self.managed.remove(&index) else {
+            return;
+        };
+        tracing::info!("Interface {} disappeared", name);
+        if let Err(e) = manager.remove_vanished(&name).await {
+            tracing::warn!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{applier::Backend, netlink};
+    use std::{process::Command, time::Duration};
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("eth*", "eth0"));
+        assert!(glob_match("eth*", "eth"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("enp?s*", "enp3s0"));
+        assert!(glob_match("*.10", "eth0.10"));
+        assert!(glob_match("a*b*c", "axxbyybc"));
+        assert!(!glob_match("eth*", "veth0"));
+        assert!(!glob_match("eth?", "eth10"));
+        assert!(!glob_match("a*b*c", "axxbyyb"));
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = InterfaceFilter::new(
+            vec!["eth*".to_string(), "enp*".to_string()],
+            vec!["eth9".to_string()],
+        );
+        assert!(filter.matches("eth0"));
+        assert!(filter.matches("enp3s0"));
+        assert!(!filter.matches("eth9"));
+        assert!(!filter.matches("wlan0"));
+
+        let filter =
+            InterfaceFilter::new(Vec::new(), vec!["docker*".to_string(), "veth*".to_string()]);
+        assert!(filter.matches("wlan0"));
+        assert!(!filter.matches("docker0"));
+        assert!(!filter.matches("veth1a2b"));
+    }
+
+    fn ip(args: &[&str]) -> bool {
+        Command::new("ip")
+            .args(args)
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    /// Lets hotplug handle the notifications so far
+    async fn settle<F: Fn(&Interface) -> ClientConfig>(
+        hotplug: &mut Hotplug<F>,
+        manager: &mut InterfaceManager,
+    ) {
+        let run = tokio::time::timeout(Duration::from_millis(200), hotplug.run(manager));
+        assert!(run.await.is_err());
+    }
+
+    #[test]
+    fn test_follow_interfaces_in_namespace() {
+        netlink::in_network_namespace(|| {
+            if !ip(&["link", "add", "hp0", "type", "veth", "peer", "name", "hp1"]) {
+                eprintln!("skipping: cannot create veth interfaces");
+                return;
+            }
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let filter = InterfaceFilter::new(vec!["hp*".to_string()], vec!["hp1".to_string()]);
+                let make_config = |interface: &Interface| {
+                    let mut config = ClientConfig::from_interface(interface);
+                    config.client_port = 10068;
+                    config.server_port = 10067;
+                    config.network_backend = Backend::None;
+                    config
+                };
+                let mut hotplug = Hotplug::open(filter, make_config, None).unwrap();
+                let mut manager = InterfaceManager::new();
+
+                // Given on the command line although denied
+                let hp1 = Interface::query("hp1").unwrap();
+                manager.add(make_config(&hp1)).await.unwrap();
+
+                // Present at start
+                settle(&mut hotplug, &mut manager).await;
+                assert_eq!(manager.interfaces().collect::<Vec<_>>(), ["hp0", "hp1"]);
+
+                // Added later, and renamed
+                assert!(ip(&[
+                    "link", "add", "hp2", "type", "veth", "peer", "name", "other0"
+                ]));
+                settle(&mut hotplug, &mut manager).await;
+                assert_eq!(
+                    manager.interfaces().collect::<Vec<_>>(),
+                    ["hp0", "hp1", "hp2"]
+                );
+                assert!(ip(&["link", "set", "hp2", "name", "other1"]));
+                settle(&mut hotplug, &mut manager).await;
+                assert_eq!(manager.interfaces().collect::<Vec<_>>(), ["hp0", "hp1"]);
+
+                // Removed, taking its peer along
+                assert!(ip(&["link", "del", "hp0"]));
+                settle(&mut hotplug, &mut manager).await;
+                assert!(manager.is_empty());
+            });
+        });
+    }
+}
@@ -31,10 +31,14 @@
 
 pub mod client;
 pub mod config;
+pub mod duid;
 pub mod error;
+pub mod hotplug;
+pub mod manager;
 pub mod network;
 pub mod v4;
 
-pub use client::{DhcpClient, Lease};
+pub use client::{ClientEvent, ClientStatus, DhcpClient, Lease};
 pub use config::{Args, ClientConfig};
 pub use error::HeraldError;
+pub use manager::InterfaceManager;
@@ -1,32 +1,13 @@
-mod client;
-mod config;
-mod error;
-mod network;
-mod v4;
-
-use crate::{
-    client::DhcpClient,
-    config::{Args, ClientConfig},
-    error::HeraldError,
-};
-use bytes::BufMut as _;
 use clap::Parser as _;
-use tokio::fs;
-
-async fn get_mac_address(interface: &str) -> Result<bytes::Bytes, HeraldError> {
-    let path = format!("/sys/class/net/{interface}/address");
-    let mac_str = fs::read_to_string(&path)
-        .await
-        .map_err(|_| HeraldError::InterfaceInvalid(interface.to_string()))?;
-
-    let mut bytes = bytes::BytesMut::new();
-    for byte_str in mac_str.trim().split(':') {
-        let byte = u8::from_str_radix(byte_str, 16)
-            .map_err(|_| HeraldError::MacParse(mac_str.trim().to_string()))?;
-        bytes.put_u8(byte);
-    }
-    Ok(bytes.freeze())
-}
+use herald::{
+    hotplug::{Hotplug, InterfaceFilter},
+    network::{
+        interface::{format_hardware_address, Interface},
+        netns,
+        shared::SharedSocket,
+    },
+    Args, ClientConfig, DhcpClient, InterfaceManager,
+};
 
 #[tokio::main]
 async fn main() {
@@ -34,27 +15,53 @@ async fn main() {
 
     let args = Args::parse();
 
-    let mac_addr = match get_mac_address(&args.interface).await {
-        Ok(mac) => {
-            let mac_str = mac
-                .iter()
-                .map(|b| format!("{b:02x}"))
-                .collect::<Vec<_>>()
-                .join(":");
-            tracing::info!(
-                "Found MAC address {} for interface {}",
-                mac_str,
-                &args.interface
-            );
-            mac
+    let mut configs = Vec::new();
+    for name in &args.interface {
+        match netns::run_in(args.netns.as_ref(), || Interface::query(name)) {
+            Ok(interface) => {
+                tracing::info!(
+                    "Found MAC address {} for interface {} (index {}, MTU {})",
+                    format_hardware_address(&interface.hardware_address),
+                    interface.name,
+                    interface.index,
+                    interface.mtu
+                );
+                configs.push(args.client_config(&interface));
+            }
+            Err(e) => {
+                tracing::error!("{}", e);
+                return;
+            }
         }
-        Err(e) => {
-            tracing::error!("{}", e);
-            return;
+    }
+
+    let mut shared_socket = None;
+    if args.shared_socket {
+        let port = configs.first().map_or(68, |config| config.client_port);
+        let shared = match netns::run_in(args.netns.as_ref(), || Ok(SharedSocket::open(port)?)) {
+            Ok(shared) => shared,
+            Err(e) => {
+                tracing::error!("Failed to open shared socket: {}", e);
+                return;
+            }
+        };
+        for config in &mut configs {
+            config.shared_socket = Some(shared.clone());
         }
-    };
+        shared_socket = Some(shared);
+    }
 
-    let config = ClientConfig::new(args.interface, mac_addr);
+    if args.hotplug {
+        run_hotplug(&args, configs, shared_socket).await;
+        return;
+    }
+    if configs.len() > 1 {
+        run_managed(configs).await;
+        return;
+    }
+    let Some(config) = configs.pop() else {
+        return;
+    };
 
     let mut client = match DhcpClient::new(config).await {
         Ok(c) => c,
@@ -67,9 +74,72 @@ async fn main() {
     match client.run().await {
         Ok(lease) => {
             tracing::info!("Successfully obtained lease: {:?}", lease);
+            tracing::info!("Status: {}", client.status());
+            if args.follow_link {
+                if let Err(e) = client.follow_link().await {
+                    tracing::error!("Following the link failed: {}", e);
+                }
+            }
         }
         Err(e) => {
             tracing::error!("DHCP client failed: {}", e);
         }
     }
 }
+
+/// Runs a client per interface until interrupted, then releases the leases
+async fn run_managed(configs: Vec<ClientConfig>) {
+    let mut manager = start_clients(configs).await;
+    if manager.is_empty() {
+        return;
+    }
+
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        tracing::error!("Failed to wait for interrupt: {}", e);
+    }
+    manager.shutdown().await;
+}
+
+/// Runs clients for the given interfaces and for every interface hotplug
+/// matches until interrupted, then releases the leases
+async fn run_hotplug(args: &Args, configs: Vec<ClientConfig>, shared_socket: Option<SharedSocket>) {
+    let filter = InterfaceFilter::new(args.allow.clone(), args.deny.clone());
+    let make_config = |interface: &Interface| {
+        let mut config = args.client_config(interface);
+        config.shared_socket = shared_socket.clone();
+        config
+    };
+    let mut hotplug = match Hotplug::open(filter, make_config, args.netns.as_ref()) {
+        Ok(hotplug) => hotplug,
+        Err(e) => {
+            tracing::error!("Failed to watch interfaces: {}", e);
+            return;
+        }
+    };
+
+    let mut manager = start_clients(configs).await;
+    tokio::select! {
+        result = hotplug.run(&mut manager) => {
+            if let Err(e) = result {
+                tracing::error!("Hotplug failed: {}", e);
+            }
+        }
+        result = tokio::signal::ctrl_c() => {
+            if let Err(e) = result {
+                tracing::error!("Failed to wait for interrupt: {}", e);
+            }
+        }
+    }
+    manager.shutdown().await;
+}
+
+async fn start_clients(configs: Vec<ClientConfig>) -> InterfaceManager {
+    let mut manager = InterfaceManager::new();
+    for config in configs {
+        let interface = config.interface.clone();
+        if let Err(e) = manager.add(config).await {
+            tracing::error!("Failed to start DHCP client on {}: {}", interface, e);
+        }
+    }
+    manager
+}
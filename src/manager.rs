@@ -0,0 +1,216 @@
+//! Several interfaces in one process
+//!
+//! Each managed interface gets its own [`DhcpClient`], with its own
+//! configuration, sockets and state machine, driven by a task on the shared
+//! tokio runtime. The task's log lines carry the interface name.
+
+use crate::{client::DhcpClient, config::ClientConfig, error::HeraldError};
+use std::collections::BTreeMap;
+use tokio::{sync::oneshot, task::JoinHandle};
+use tracing::Instrument as _;
+
+/// How a client is stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    /// Send DHCPRELEASE and remove the configuration
+    Release,
+    /// The interface is gone: only clean up what was configured for it
+    Forget,
+}
+
+/// A running client and the means to stop it.
+struct ManagedClient {
+    stop: oneshot::Sender<Stop>,
+    task: JoinHandle<()>,
+}
+
+/// Runs one DHCP client per interface. Interfaces can be added and removed
+/// at any time; dropping the manager stops all clients.
+#[derive(Default)]
+pub struct InterfaceManager {
+    clients: BTreeMap<String, ManagedClient>,
+}
+
+impl InterfaceManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a client for `config.interface`. It obtains a lease and then
+    /// renews it and follows the carrier until the interface is removed.
+    pub async fn add(&mut self, config: ClientConfig) -> Result<(), HeraldError> {
+        self.check_unmanaged(&config.interface)?;
+        let span = client_span(&config.interface);
+        let client = DhcpClient::new(config).instrument(span).await?;
+        self.add_client(client)
+    }
+
+    /// Starts managing a client that was already created.
+    pub fn add_client(&mut self, client: DhcpClient) -> Result<(), HeraldError> {
+        let interface = client.interface().to_string();
+        self.check_unmanaged(&interface)?;
+
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(manage(client, stopped).instrument(client_span(&interface)));
+        tracing::info!("Managing interface {}", interface);
+        self.clients.insert(interface, ManagedClient { stop, task });
+        Ok(())
+    }
+
+    /// Stops the client for `interface`, releasing its lease.
+    pub async fn remove(&mut self, interface: &str) -> Result<(), HeraldError> {
+        self.stop(interface, Stop::Release).await
+    }
+
+    /// Stops the client for an interface that no longer exists. No
+    /// DHCPRELEASE can be sent, but what was configured for the lease, such
+    /// as DNS servers, is cleaned up.
+    pub async fn remove_vanished(&mut self, interface: &str) -> Result<(), HeraldError> {
+        self.stop(interface, Stop::Forget).await
+    }
+
+    async fn stop(&mut self, interface: &str, stop: Stop) -> Result<(), HeraldError> {
+        let managed = self.clients.remove(interface).ok_or_else(|| {
+            HeraldError::Critical(format!("interface {interface} is not managed"))
+        })?;
+
+        // The task may have ended already, dropping its end
+        let _ = managed.stop.send(stop);
+        managed.task.await.map_err(|e| {
+            HeraldError::Critical(format!("client for {interface} did not stop cleanly: {e}"))
+        })?;
+        tracing::info!("Stopped managing interface {}", interface);
+        Ok(())
+    }
+
+    /// Stops all clients, releasing their leases.
+    pub async fn shutdown(&mut self) {
+        let interfaces: Vec<String> = self.clients.keys().cloned().collect();
+        for interface in interfaces {
+            if let Err(e) = self.remove(&interface).await {
+                tracing::warn!("{}", e);
+            }
+        }
+    }
+
+    /// Whether a client runs for `interface`
+    pub fn contains(&self, interface: &str) -> bool {
+        self.clients.contains_key(interface)
+    }
+
+    /// Names of the managed interfaces, in order
+    pub fn interfaces(&self) -> impl Iterator<Item = &str> {
+        self.clients.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    fn check_unmanaged(&self, interface: &str) -> Result<(), HeraldError> {
+        if self.contains(interface) {
+            return Err(HeraldError::Critical(format!(
+                "interface {interface} is already managed"
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn client_span(interface: &str) -> tracing::Span {
+    tracing::info_span!("client", interface = %interface)
+}
+
+/// Drives `client` until `stop` fires, then releases or forgets the lease.
+/// A dropped sender counts as a release.
+async fn manage(mut client: DhcpClient, mut stop: oneshot::Receiver<Stop>) {
+    let stopped = tokio::select! {
+        result = bind_and_follow(&mut client) => {
+            if let Err(e) = result {
+                tracing::error!("DHCP client failed: {}", e);
+            }
+            None
+        }
+        stop = &mut stop => Some(stop.unwrap_or(Stop::Release)),
+    };
+    // Whatever configuration was applied stays until the interface is removed
+    let stop = match stopped {
+        Some(stop) => stop,
+        None => stop.await.unwrap_or(Stop::Release),
+    };
+
+    let result = match stop {
+        Stop::Release => client.release().await,
+        Stop::Forget => client.forget(),
+    };
+    if let Err(e) = result {
+        tracing::warn!("Failed to release lease: {}", e);
+    }
+}
+
+async fn bind_and_follow(client: &mut DhcpClient) -> Result<(), HeraldError> {
+    client.run().await?;
+    tracing::info!("Status: {}", client.status());
+    client.follow_link().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{applier::Backend, netlink};
+    use bytes::Bytes;
+    use std::time::Duration;
+
+    fn config(interface: &str) -> ClientConfig {
+        let mut config = ClientConfig::new(
+            interface.to_string(),
+            Bytes::from_static(&[0x02, 0, 0, 0, 0, 0x01]),
+        );
+        config.client_port = 10068;
+        config.server_port = 10067;
+        config.network_backend = Backend::None;
+        config
+    }
+
+    #[test]
+    fn test_add_and_remove_in_namespace() {
+        netlink::in_network_namespace(|| {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(2)
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let mut manager = InterfaceManager::new();
+                assert!(manager.is_empty());
+
+                // lo has no carrier here, so the client waits for one
+                manager.add(config("lo")).await.unwrap();
+                assert!(manager.contains("lo"));
+                assert_eq!(manager.interfaces().collect::<Vec<_>>(), ["lo"]);
+                assert!(manager.add(config("lo")).await.is_err());
+
+                let removed = tokio::time::timeout(Duration::from_secs(1), manager.remove("lo"));
+                removed.await.unwrap().unwrap();
+                assert!(manager.is_empty());
+                assert!(manager.remove("lo").await.is_err());
+
+                // The interface can be managed again once removed
+                manager.add(config("lo")).await.unwrap();
+                manager.shutdown().await;
+                assert!(manager.is_empty());
+            });
+        });
+    }
+
+    #[tokio::test]
+    async fn test_missing_interface() {
+        let mut manager = InterfaceManager::new();
+        assert!(manager.add(config("herald-missing0")).await.is_err());
+        assert!(manager.is_empty());
+    }
+}
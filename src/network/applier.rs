@@ -0,0 +1,236 @@
+//! Pluggable backends that apply a lease to the host
+
+use super::{
+    configurator::{
+        ApplyReport, DryRunOps, Iproute2Ops, NetlinkOps, NetworkConfigurator, SystemOps,
+    },
+    hook::HookApplier,
+    netns,
+};
+use crate::{client::Lease, config::ClientConfig, error::HeraldError};
+use std::{
+    fmt,
+    net::Ipv4Addr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+/// Applies lease settings to the host and takes them away again.
+pub trait LeaseApplier: Send + Sync {
+    /// Configure the host for a newly bound lease, reporting each step
+    fn apply(&mut self, lease: &Lease) -> Result<ApplyReport, HeraldError>;
+    /// Bring the configuration in line with a renewed or rebound lease
+    fn update(&mut self, old: &Lease, new: &Lease) -> Result<ApplyReport, HeraldError>;
+    /// Undo the configuration of a released or expired lease
+    fn remove(&mut self, lease: &Lease) -> Result<(), HeraldError>;
+    /// Configure an IPv4 link-local address
+    fn apply_link_local(&mut self, addr: Ipv4Addr) -> Result<(), HeraldError>;
+    /// Remove an IPv4 link-local address
+    fn remove_link_local(&mut self, addr: Ipv4Addr) -> Result<(), HeraldError>;
+}
+
+/// How a lease is applied to the host.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Run the iproute2 `ip` command
+    #[default]
+    Iproute2,
+    /// Talk to the kernel over rtnetlink
+    Netlink,
+    /// Only log the commands that would be run
+    DryRun,
+    /// Leave the host alone
+    None,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "iproute2" => Ok(Backend::Iproute2),
+            "netlink" => Ok(Backend::Netlink),
+            "dry-run" => Ok(Backend::DryRun),
+            "none" => Ok(Backend::None),
+            _ => Err(format!(
+                "unknown backend '{s}' (expected iproute2, netlink, dry-run or none)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backend::Iproute2 => "iproute2",
+            Backend::Netlink => "netlink",
+            Backend::DryRun => "dry-run",
+            Backend::None => "none",
+        })
+    }
+}
+
+/// Creates the applier selected by `config.network_backend`, followed by
+/// the hook script if one is configured. Network backends start by removing
+/// what an earlier run left on the interface.
+pub fn from_config(config: &ClientConfig) -> Result<Box<dyn LeaseApplier>, HeraldError> {
+    let interface = config.interface.clone();
+    let ops: Box<dyn SystemOps> = match config.network_backend {
+        Backend::Iproute2 => Box::new(Iproute2Ops::new(interface).in_netns(config.netns.clone())),
+        Backend::Netlink => Box::new(netns::run_in(config.netns.as_ref(), || {
+            NetlinkOps::new(interface)
+        })?),
+        Backend::DryRun => Box::new(DryRunOps::new(interface)),
+        Backend::None => return Ok(with_hook(config, Box::new(NoopApplier))),
+    };
+    let configurator = NetworkConfigurator::from_config(config, ops);
+    configurator.remove_stale();
+    Ok(with_hook(config, Box::new(configurator)))
+}
+
+/// Wraps `applier` to run the hook script of `config`, if any
+fn with_hook(config: &ClientConfig, applier: Box<dyn LeaseApplier>) -> Box<dyn LeaseApplier> {
+    match config.hook {
+        Some(ref script) => Box::new(HookApplier::new(applier, script.clone(), &config.interface)),
+        None => applier,
+    }
+}
+
+/// Applier that leaves the host untouched.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopApplier;
+
+impl LeaseApplier for NoopApplier {
+    fn apply(&mut self, _lease: &Lease) -> Result<ApplyReport, HeraldError> {
+        Ok(ApplyReport::default())
+    }
+
+    fn update(&mut self, _old: &Lease, _new: &Lease) -> Result<ApplyReport, HeraldError> {
+        Ok(ApplyReport::default())
+    }
+
+    fn remove(&mut self, _lease: &Lease) -> Result<(), HeraldError> {
+        Ok(())
+    }
+
+    fn apply_link_local(&mut self, _addr: Ipv4Addr) -> Result<(), HeraldError> {
+        Ok(())
+    }
+
+    fn remove_link_local(&mut self, _addr: Ipv4Addr) -> Result<(), HeraldError> {
+        Ok(())
+    }
+}
+
+/// A call made on a `RecordingApplier`
+#[derive(Debug, Clone)]
+pub enum AppliedChange {
+    Apply(Box<Lease>),
+    Update { old: Box<Lease>, new: Box<Lease> },
+    Remove(Box<Lease>),
+    ApplyLinkLocal(Ipv4Addr),
+    RemoveLinkLocal(Ipv4Addr),
+}
+
+/// Applier that records every call, for assertions in tests.
+///
+/// Clones share the same record, so a clone can be kept to inspect the calls
+/// made through an applier handed to a client.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingApplier {
+    changes: Arc<Mutex<Vec<AppliedChange>>>,
+}
+
+impl RecordingApplier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Calls made so far, oldest first
+    pub fn changes(&self) -> Vec<AppliedChange> {
+        self.changes.lock().expect("poisoned").clone()
+    }
+
+    fn record(&self, change: AppliedChange) -> Result<(), HeraldError> {
+        self.changes.lock().expect("poisoned").push(change);
+        Ok(())
+    }
+}
+
+impl LeaseApplier for RecordingApplier {
+    fn apply(&mut self, lease: &Lease) -> Result<ApplyReport, HeraldError> {
+        self.record(AppliedChange::Apply(Box::new(lease.clone())))?;
+        Ok(ApplyReport::default())
+    }
+
+    fn update(&mut self, old: &Lease, new: &Lease) -> Result<ApplyReport, HeraldError> {
+        self.record(AppliedChange::Update {
+            old: Box::new(old.clone()),
+            new: Box::new(new.clone()),
+        })?;
+        Ok(ApplyReport::default())
+    }
+
+    fn remove(&mut self, lease: &Lease) -> Result<(), HeraldError> {
+        self.record(AppliedChange::Remove(Box::new(lease.clone())))
+    }
+
+    fn apply_link_local(&mut self, addr: Ipv4Addr) -> Result<(), HeraldError> {
+        self.record(AppliedChange::ApplyLinkLocal(addr))
+    }
+
+    fn remove_link_local(&mut self, addr: Ipv4Addr) -> Result<(), HeraldError> {
+        self.record(AppliedChange::RemoveLinkLocal(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend_round_trip() {
+        for backend in [
+            Backend::Iproute2,
+            Backend::Netlink,
+            Backend::DryRun,
+            Backend::None,
+        ] {
+            assert_eq!(backend.to_string().parse::<Backend>(), Ok(backend));
+        }
+        assert!("ifconfig".parse::<Backend>().is_err());
+    }
+
+    #[test]
+    fn test_recording_applier_shares_changes() {
+        let recorder = RecordingApplier::new();
+        let mut applier: Box<dyn LeaseApplier> = Box::new(recorder.clone());
+        let lease = Lease::new(Ipv4Addr::new(192, 168, 1, 100));
+        let link_local = Ipv4Addr::new(169, 254, 1, 2);
+
+        applier.apply_link_local(link_local).unwrap();
+        applier.apply(&lease).unwrap();
+        applier.update(&lease, &lease).unwrap();
+        applier.remove(&lease).unwrap();
+
+        let changes = recorder.changes();
+        assert_eq!(changes.len(), 4);
+        assert!(matches!(changes[0], AppliedChange::ApplyLinkLocal(addr) if addr == link_local));
+        assert!(
+            matches!(changes[1], AppliedChange::Apply(ref l) if l.offered_ip == lease.offered_ip)
+        );
+        assert!(matches!(changes[2], AppliedChange::Update { .. }));
+        assert!(matches!(changes[3], AppliedChange::Remove(_)));
+    }
+
+    #[test]
+    fn test_none_backend_is_noop() {
+        let mut config = ClientConfig::new("herald-none0".to_string(), bytes::Bytes::new());
+        config.network_backend = Backend::None;
+        let mut applier = from_config(&config).unwrap();
+
+        applier
+            .apply(&Lease::new(Ipv4Addr::new(192, 168, 1, 100)))
+            .unwrap();
+    }
+}
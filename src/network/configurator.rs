@@ -1,160 +1,1319 @@
-use crate::{client::Lease, error::HeraldError};
-use std::process::Command;
+use super::{
+    applier::LeaseApplier,
+    interface::Interface,
+    ipv4ll::{LINK_LOCAL_BROADCAST, LINK_LOCAL_PREFIX_LEN},
+    netlink::{
+        Address, NetlinkHandle, Route, Rule, Scope, INFINITE_LIFETIME, MAIN_TABLE, MAX_LABEL_LEN,
+        RTPROT_DHCP,
+    },
+    netns::{self, NetNs},
+    ntp::NtpExport,
+    resolv::{DnsStrategy, ResolvConf, DEFAULT_DNS_PRIORITY, NETNS_STATE_DIR},
+    timezone::TimezoneLink,
+};
+use crate::{client::Lease, config::ClientConfig, error::HeraldError};
+use std::{fmt, net::Ipv4Addr, ops::RangeInclusive, path::Path, process::Command};
 use tracing::{info, warn};
 
+/// Smallest interface MTU accepted from a lease.
+///
+/// RFC 2132 allows values down to 68, but every IPv4 host must be able to
+/// reassemble 576-byte datagrams, so smaller values are treated as bogus.
+pub const MIN_INTERFACE_MTU: u16 = 576;
+
+/// Metric of the default route via the first router in option 3.
+///
+/// Each further router gets the next higher metric, so the kernel prefers the
+/// routers in the order the server listed them.
+pub const DEFAULT_ROUTE_METRIC: u32 = 1024;
+
+/// Most routers in option 3 that get a default route, as many as fit in a
+/// single option.
+///
+/// This bounds the metrics herald uses, so stale routes can be told apart
+/// from those of other DHCP clients on the interface.
+const MAX_DEFAULT_ROUTES: u32 = 63;
+
+/// Priority of the source-address rule used for policy routing, ahead of
+/// the main table's rule at 32766.
+pub const POLICY_RULE_PRIORITY: u32 = 10000;
+
+/// Parses a routing table for policy routing, rejecting the reserved
+/// default, main and local tables.
+pub fn parse_route_table(s: &str) -> Result<u32, String> {
+    let table: u32 = s.parse().map_err(|e| format!("invalid table '{s}': {e}"))?;
+    match table {
+        0 | 253..=255 => Err(format!("table {table} is reserved")),
+        table => Ok(table),
+    }
+}
+
+/// A step of applying a lease, in the order the steps run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyStep {
+    Mtu,
+    Address,
+    Routes,
+    Dns,
+    Ntp,
+    /// Runs once the other steps have succeeded; a failure is logged but
+    /// does not roll the lease back
+    Timezone,
+}
+
+impl fmt::Display for ApplyStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ApplyStep::Mtu => "MTU",
+            ApplyStep::Address => "address",
+            ApplyStep::Routes => "routes",
+            ApplyStep::Dns => "DNS",
+            ApplyStep::Ntp => "NTP",
+            ApplyStep::Timezone => "timezone",
+        })
+    }
+}
+
+/// Outcome of one step of applying a lease.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepStatus {
+    Applied,
+    /// The lease had nothing to change for this step
+    Skipped,
+    Failed(String),
+    /// Applied, then undone because a later step failed
+    RolledBack,
+    RollbackFailed(String),
+}
+
+impl fmt::Display for StepStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StepStatus::Applied => f.write_str("applied"),
+            StepStatus::Skipped => f.write_str("skipped"),
+            StepStatus::Failed(e) => write!(f, "failed: {e}"),
+            StepStatus::RolledBack => f.write_str("rolled back"),
+            StepStatus::RollbackFailed(e) => write!(f, "rollback failed: {e}"),
+        }
+    }
+}
+
+/// Per-step outcome of applying a lease.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApplyReport {
+    pub steps: Vec<(ApplyStep, StepStatus)>,
+}
+
+impl ApplyReport {
+    /// Status of `step`, if it ran
+    pub fn status(&self, step: ApplyStep) -> Option<&StepStatus> {
+        self.steps
+            .iter()
+            .find(|(s, _)| *s == step)
+            .map(|(_, status)| status)
+    }
+
+    /// The step that failed and caused the rollback
+    pub fn failed_step(&self) -> Option<ApplyStep> {
+        self.steps
+            .iter()
+            .find(|(step, status)| {
+                *step != ApplyStep::Timezone && matches!(status, StepStatus::Failed(_))
+            })
+            .map(|(step, _)| *step)
+    }
+}
+
+impl fmt::Display for ApplyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (step, status)) in self.steps.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{step}: {status}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Host state from before a lease was applied, for rolling it back.
+struct Snapshot {
+    original_mtu: Option<u32>,
+    /// MTU before the MTU step changed it
+    mtu: Option<u32>,
+    address: Option<Address>,
+    routes: Vec<Route>,
+    rule: Option<Rule>,
+    /// DNS servers before the DNS step changed them; `Some(None)` if there were none
+    dns: Option<Option<Vec<Ipv4Addr>>>,
+}
+
+/// Changes to the host that a `NetworkConfigurator` is built from.
+///
+/// Link, address and route operations differ per backend. The file based
+/// settings default to changing the host directly.
+pub trait SystemOps: Send + Sync {
+    /// Set the interface MTU
+    fn set_mtu(&self, mtu: u32) -> Result<(), HeraldError>;
+    /// Bring the interface up
+    fn set_link_up(&self) -> Result<(), HeraldError>;
+    /// Add or replace an address on the interface
+    fn add_address(&self, address: &Address) -> Result<(), HeraldError>;
+    /// Remove an address from the interface
+    fn delete_address(&self, address: Ipv4Addr, prefix_len: u8) -> Result<(), HeraldError>;
+    /// Add or replace a route through the interface
+    fn replace_route(&self, route: &Route) -> Result<(), HeraldError>;
+    /// Remove a route through the interface
+    fn delete_route(&self, route: &Route) -> Result<(), HeraldError>;
+    /// Add a routing policy rule
+    fn add_rule(&self, rule: &Rule) -> Result<(), HeraldError>;
+    /// Remove a routing policy rule
+    fn delete_rule(&self, rule: &Rule) -> Result<(), HeraldError>;
+    /// Remove the addresses labelled `label` from the interface
+    fn flush_addresses(&self, label: &str) -> Result<(), HeraldError>;
+    /// Remove the DHCP protocol routes through the interface with a metric
+    /// in `metrics` from `table`
+    fn flush_routes(&self, table: u32, metrics: RangeInclusive<u32>) -> Result<(), HeraldError>;
+
+    /// Publish the interface's DNS servers to the resolver
+    fn set_dns(&self, dns: &ResolvConf, servers: &[Ipv4Addr]) -> Result<(), HeraldError> {
+        dns.apply(servers)
+    }
+
+    /// Withdraw the interface's DNS servers from the resolver
+    fn clear_dns(&self, dns: &ResolvConf) -> Result<(), HeraldError> {
+        dns.clear()
+    }
+
+    /// Export NTP servers to the time daemon
+    fn export_ntp(&self, export: &NtpExport, servers: &[Ipv4Addr]) -> Result<(), HeraldError> {
+        export.apply(servers)
+    }
+
+    /// Remove exported NTP servers
+    fn clear_ntp(&self, export: &NtpExport) -> Result<(), HeraldError> {
+        export.clear()
+    }
+
+    /// Point the local time at a tz database zone
+    fn link_timezone(&self, link: &TimezoneLink, zone: &str) -> Result<(), HeraldError> {
+        link.apply(zone)
+    }
+}
+
+/// Applies changes by running the iproute2 `ip` command.
+pub struct Iproute2Ops {
+    interface: String,
+    netns: Option<NetNs>,
+}
+
+impl Iproute2Ops {
+    pub fn new(interface: String) -> Self {
+        Self {
+            interface,
+            netns: None,
+        }
+    }
+
+    /// Runs `ip` inside `netns` instead of herald's own namespace.
+    pub fn in_netns(mut self, netns: Option<NetNs>) -> Self {
+        self.netns = netns;
+        self
+    }
+
+    fn run_ip(&self, args: &[String], action: &str) -> Result<(), HeraldError> {
+        self.output_ip(args, action).map(drop)
+    }
+
+    /// Run `ip`, returning what it printed.
+    fn output_ip(&self, args: &[String], action: &str) -> Result<String, HeraldError> {
+        let mut command = Command::new("ip");
+        command.args(args);
+        if let Some(netns) = &self.netns {
+            netns.apply_to(&mut command)?;
+        }
+        let output = command.output().map_err(HeraldError::Io)?;
+
+        if !output.status.success() {
+            return Err(HeraldError::Critical(format!(
+                "Failed to {}: {}",
+                action,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+impl SystemOps for Iproute2Ops {
+    fn set_mtu(&self, mtu: u32) -> Result<(), HeraldError> {
+        self.run_ip(&mtu_args(&self.interface, mtu), "set MTU")
+    }
+
+    fn set_link_up(&self) -> Result<(), HeraldError> {
+        self.run_ip(&link_up_args(&self.interface), "bring interface up")
+    }
+
+    fn add_address(&self, address: &Address) -> Result<(), HeraldError> {
+        self.run_ip(
+            &add_address_args(&self.interface, address),
+            "configure IP address",
+        )
+    }
+
+    fn delete_address(&self, address: Ipv4Addr, prefix_len: u8) -> Result<(), HeraldError> {
+        self.run_ip(
+            &delete_address_args(&self.interface, address, prefix_len),
+            "remove IP address",
+        )
+    }
+
+    fn replace_route(&self, route: &Route) -> Result<(), HeraldError> {
+        self.run_ip(
+            &route_args("replace", &self.interface, route),
+            "configure route",
+        )
+    }
+
+    fn delete_route(&self, route: &Route) -> Result<(), HeraldError> {
+        self.run_ip(&route_args("del", &self.interface, route), "remove route")
+    }
+
+    fn add_rule(&self, rule: &Rule) -> Result<(), HeraldError> {
+        // A rule left behind by an earlier run is as good as a new one
+        match self.run_ip(&rule_args("add", rule), "add routing rule") {
+            Err(HeraldError::Critical(message)) if message.ends_with("File exists") => Ok(()),
+            result => result,
+        }
+    }
+
+    fn delete_rule(&self, rule: &Rule) -> Result<(), HeraldError> {
+        self.run_ip(&rule_args("del", rule), "remove routing rule")
+    }
+
+    fn flush_addresses(&self, label: &str) -> Result<(), HeraldError> {
+        self.run_ip(
+            &flush_address_args(&self.interface, label),
+            "flush IP addresses",
+        )
+    }
+
+    fn flush_routes(&self, table: u32, metrics: RangeInclusive<u32>) -> Result<(), HeraldError> {
+        // `ip route flush` only matches a single metric, so list the routes
+        // and delete those in range one by one
+        let routes = self.output_ip(&show_routes_args(&self.interface, table), "list routes")?;
+        for route in routes.lines().filter_map(|line| parse_route(line, table)) {
+            if metrics.contains(&route.metric.unwrap_or(0)) {
+                self.delete_route(&route)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Applies changes over rtnetlink.
+pub struct NetlinkOps {
+    interface: String,
+    handle: NetlinkHandle,
+}
+
+impl NetlinkOps {
+    pub fn new(interface: String) -> Result<Self, HeraldError> {
+        Ok(Self {
+            interface,
+            handle: NetlinkHandle::open()?,
+        })
+    }
+
+    /// Index of the interface, looked up per request in case it was recreated
+    fn index(&self) -> Result<u32, HeraldError> {
+        Ok(self.handle.link_index(&self.interface)?)
+    }
+}
+
+impl SystemOps for NetlinkOps {
+    fn set_mtu(&self, mtu: u32) -> Result<(), HeraldError> {
+        Ok(self.handle.set_link_mtu(self.index()?, mtu)?)
+    }
+
+    fn set_link_up(&self) -> Result<(), HeraldError> {
+        Ok(self.handle.set_link_up(self.index()?)?)
+    }
+
+    fn add_address(&self, address: &Address) -> Result<(), HeraldError> {
+        Ok(self.handle.add_address(self.index()?, address)?)
+    }
+
+    fn delete_address(&self, address: Ipv4Addr, prefix_len: u8) -> Result<(), HeraldError> {
+        Ok(self
+            .handle
+            .delete_address(self.index()?, address, prefix_len)?)
+    }
+
+    fn replace_route(&self, route: &Route) -> Result<(), HeraldError> {
+        Ok(self.handle.replace_route(self.index()?, route)?)
+    }
+
+    fn delete_route(&self, route: &Route) -> Result<(), HeraldError> {
+        Ok(self.handle.delete_route(self.index()?, route)?)
+    }
+
+    fn add_rule(&self, rule: &Rule) -> Result<(), HeraldError> {
+        // A rule left behind by an earlier run is as good as a new one
+        match self.handle.add_rule(rule) {
+            Err(e) if e.errno() == Some(libc::EEXIST) => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    fn delete_rule(&self, rule: &Rule) -> Result<(), HeraldError> {
+        Ok(self.handle.delete_rule(rule)?)
+    }
+
+    fn flush_addresses(&self, label: &str) -> Result<(), HeraldError> {
+        Ok(self.handle.flush_addresses(self.index()?, label)?)
+    }
+
+    fn flush_routes(&self, table: u32, metrics: RangeInclusive<u32>) -> Result<(), HeraldError> {
+        Ok(self
+            .handle
+            .flush_routes(self.index()?, table, RTPROT_DHCP, metrics)?)
+    }
+}
+
+/// Logs the iproute2 commands and file changes it would make instead of
+/// changing the host.
+pub struct DryRunOps {
+    interface: String,
+}
+
+impl DryRunOps {
+    pub fn new(interface: String) -> Self {
+        Self { interface }
+    }
+
+    fn log(&self, args: &[String]) -> Result<(), HeraldError> {
+        info!("[dry-run] ip {}", args.join(" "));
+        Ok(())
+    }
+}
+
+impl SystemOps for DryRunOps {
+    fn set_mtu(&self, mtu: u32) -> Result<(), HeraldError> {
+        self.log(&mtu_args(&self.interface, mtu))
+    }
+
+    fn set_link_up(&self) -> Result<(), HeraldError> {
+        self.log(&link_up_args(&self.interface))
+    }
+
+    fn add_address(&self, address: &Address) -> Result<(), HeraldError> {
+        self.log(&add_address_args(&self.interface, address))
+    }
+
+    fn delete_address(&self, address: Ipv4Addr, prefix_len: u8) -> Result<(), HeraldError> {
+        self.log(&delete_address_args(&self.interface, address, prefix_len))
+    }
+
+    fn replace_route(&self, route: &Route) -> Result<(), HeraldError> {
+        self.log(&route_args("replace", &self.interface, route))
+    }
+
+    fn delete_route(&self, route: &Route) -> Result<(), HeraldError> {
+        self.log(&route_args("del", &self.interface, route))
+    }
+
+    fn add_rule(&self, rule: &Rule) -> Result<(), HeraldError> {
+        self.log(&rule_args("add", rule))
+    }
+
+    fn delete_rule(&self, rule: &Rule) -> Result<(), HeraldError> {
+        self.log(&rule_args("del", rule))
+    }
+
+    fn flush_addresses(&self, label: &str) -> Result<(), HeraldError> {
+        self.log(&flush_address_args(&self.interface, label))
+    }
+
+    fn flush_routes(&self, table: u32, metrics: RangeInclusive<u32>) -> Result<(), HeraldError> {
+        info!(
+            "[dry-run] remove DHCP routes via {} with metric {}-{} from table {}",
+            self.interface,
+            metrics.start(),
+            metrics.end(),
+            table
+        );
+        Ok(())
+    }
+
+    fn set_dns(&self, _dns: &ResolvConf, servers: &[Ipv4Addr]) -> Result<(), HeraldError> {
+        info!("[dry-run] set DNS servers {:?}", servers);
+        Ok(())
+    }
+
+    fn clear_dns(&self, _dns: &ResolvConf) -> Result<(), HeraldError> {
+        info!("[dry-run] clear DNS servers");
+        Ok(())
+    }
+
+    fn export_ntp(&self, export: &NtpExport, servers: &[Ipv4Addr]) -> Result<(), HeraldError> {
+        info!(
+            "[dry-run] export NTP servers {:?} to {}",
+            servers,
+            export.path().display()
+        );
+        Ok(())
+    }
+
+    fn clear_ntp(&self, export: &NtpExport) -> Result<(), HeraldError> {
+        info!("[dry-run] remove {}", export.path().display());
+        Ok(())
+    }
+
+    fn link_timezone(&self, _link: &TimezoneLink, zone: &str) -> Result<(), HeraldError> {
+        info!("[dry-run] set timezone to {}", zone);
+        Ok(())
+    }
+}
+
 /// Network configurator for applying DHCP lease settings to network interfaces
 pub struct NetworkConfigurator {
     interface: String,
+    /// Namespace the interface lives in, if not herald's own
+    netns: Option<NetNs>,
+    /// How changes are made to the host
+    ops: Box<dyn SystemOps>,
+    /// MTU the interface had before a lease changed it
+    original_mtu: Option<u32>,
+    /// Where NTP servers are exported, if enabled
+    ntp_export: Option<NtpExport>,
+    /// Local time link updated from option 101, if enabled
+    timezone: Option<TimezoneLink>,
+    /// How DNS servers reach the resolver
+    dns: ResolvConf,
+    /// DNS servers herald published for the current lease
+    installed_dns: Option<Vec<Ipv4Addr>>,
+    /// Address herald installed for the current lease
+    installed_address: Option<Address>,
+    /// Routes herald installed for the current lease
+    installed_routes: Vec<Route>,
+    /// Policy rule herald installed for the current lease
+    installed_rule: Option<Rule>,
+    /// Metric of the default route via the first router
+    route_metric: u32,
+    /// Table for source-based policy routing, if enabled
+    route_table: Option<u32>,
 }
 
 impl NetworkConfigurator {
+    /// Creates a configurator that uses iproute2.
     pub fn new(interface: String) -> Self {
-        Self { interface }
+        let ops = Box::new(Iproute2Ops::new(interface.clone()));
+        Self::with_ops(interface, ops)
+    }
+
+    pub fn with_ops(interface: String, ops: Box<dyn SystemOps>) -> Self {
+        let dns = ResolvConf::new(DnsStrategy::default(), &interface, DEFAULT_DNS_PRIORITY);
+        Self {
+            interface,
+            netns: None,
+            ops,
+            original_mtu: None,
+            ntp_export: None,
+            timezone: None,
+            dns,
+            installed_dns: None,
+            installed_address: None,
+            installed_routes: Vec::new(),
+            installed_rule: None,
+            route_metric: DEFAULT_ROUTE_METRIC,
+            route_table: None,
+        }
+    }
+
+    /// Creates a configurator that makes changes through `ops`, with the
+    /// routing, DNS, NTP export and timezone settings from `config`.
+    ///
+    /// Inside another network namespace the time daemon and the clock still
+    /// belong to the host, so NTP export and the timezone are not applied.
+    pub fn from_config(config: &ClientConfig, ops: Box<dyn SystemOps>) -> Self {
+        let mut configurator = Self::with_ops(config.interface.clone(), ops);
+        configurator.netns = config.netns.clone();
+        configurator.route_metric = config.route_metric;
+        configurator.route_table = config.route_table;
+
+        if let Some(ref netns) = config.netns {
+            if config.ntp_format.is_some() || config.apply_timezone {
+                warn!(
+                    "Not exporting NTP servers or timezone from network namespace {}",
+                    netns
+                );
+            }
+            configurator.dns = netns_resolv_conf(config, netns);
+            return configurator;
+        }
+
+        configurator.ntp_export = config.ntp_format.map(|format| {
+            NtpExport::new(format, config.ntp_directory.as_deref(), &config.interface)
+        });
+        configurator.timezone = config.apply_timezone.then(TimezoneLink::default);
+        configurator.dns =
+            ResolvConf::new(config.dns_strategy, &config.interface, config.dns_priority);
+        configurator
+    }
+
+    /// Remove the addresses and routes an earlier run left on the interface,
+    /// recognised by the label, route protocol and metrics herald gives them.
+    /// Every DHCP route in herald's own table is removed.
+    pub fn remove_stale(&self) {
+        if let Some(label) = address_label(&self.interface) {
+            if let Err(e) = self.ops.flush_addresses(&label) {
+                warn!("Failed to remove stale addresses: {}", e);
+            }
+        }
+        let metrics = self.route_metric..=self.route_metric.saturating_add(MAX_DEFAULT_ROUTES - 1);
+        let tables = self.route_table.map(|table| (table, 0..=u32::MAX));
+        for (table, metrics) in std::iter::once((MAIN_TABLE, metrics)).chain(tables) {
+            if let Err(e) = self.ops.flush_routes(table, metrics) {
+                warn!("Failed to remove stale routes: {}", e);
+            }
+        }
+    }
+
+    /// Apply the lease settings as a transaction, changing only what differs
+    /// from what herald installed before. If a step fails, the steps before it
+    /// are rolled back and the report is returned in `HeraldError::Apply`.
+    fn apply_settings(&mut self, lease: &Lease) -> Result<ApplyReport, HeraldError> {
+        let mut snapshot = Snapshot {
+            original_mtu: self.original_mtu,
+            mtu: None,
+            address: self.installed_address.clone(),
+            routes: self.installed_routes.clone(),
+            rule: self.installed_rule,
+            dns: None,
+        };
+        let mut report = ApplyReport::default();
+
+        for step in [
+            ApplyStep::Mtu,
+            ApplyStep::Address,
+            ApplyStep::Routes,
+            ApplyStep::Dns,
+            ApplyStep::Ntp,
+        ] {
+            match self.apply_step(step, lease, &mut snapshot) {
+                Ok(true) => report.steps.push((step, StepStatus::Applied)),
+                Ok(false) => report.steps.push((step, StepStatus::Skipped)),
+                Err(e) => {
+                    warn!("Failed to apply {}: {}", step, e);
+                    report.steps.push((step, StepStatus::Failed(e.to_string())));
+                    self.rollback(&mut report, &snapshot);
+                    return Err(HeraldError::Apply(report));
+                }
+            }
+        }
+
+        // Apply the tz database timezone (option 101) if enabled
+        let status = match (&self.timezone, &lease.tz_database_name) {
+            (Some(timezone), Some(zone)) => match self.ops.link_timezone(timezone, zone) {
+                Ok(()) => StepStatus::Applied,
+                Err(e) => {
+                    warn!("Failed to apply timezone: {}", e);
+                    StepStatus::Failed(e.to_string())
+                }
+            },
+            _ => StepStatus::Skipped,
+        };
+        report.steps.push((ApplyStep::Timezone, status));
+
+        Ok(report)
+    }
+
+    /// Run one step, recording what it replaces in `snapshot`. Returns
+    /// whether the step changed anything.
+    fn apply_step(
+        &mut self,
+        step: ApplyStep,
+        lease: &Lease,
+        snapshot: &mut Snapshot,
+    ) -> Result<bool, HeraldError> {
+        match step {
+            // Configure interface MTU before the address so routes pick it up
+            ApplyStep::Mtu => {
+                let Some(mtu) = lease.interface_mtu else {
+                    return Ok(false);
+                };
+                if mtu >= MIN_INTERFACE_MTU {
+                    snapshot.mtu = Some(self.read_mtu()?);
+                }
+                self.configure_mtu(mtu)?;
+                Ok(snapshot.mtu.is_some())
+            }
+            // Configure IP address and subnet mask
+            ApplyStep::Address => self.configure_ip_address(lease),
+            // Configure static host routes (option 33) and a default route
+            // through every router, in order of preference, plus the policy
+            // routing rule if enabled
+            ApplyStep::Routes => {
+                let before = (self.installed_routes.clone(), self.installed_rule);
+                self.configure_routes(self.lease_routes(lease))?;
+                self.configure_rule(self.lease_rule(lease))?;
+                Ok((self.installed_routes.clone(), self.installed_rule) != before)
+            }
+            // Publish DNS servers, or withdraw ours if the lease has none
+            ApplyStep::Dns => {
+                if lease.dns_servers == self.installed_dns {
+                    return Ok(false);
+                }
+                snapshot.dns = Some(self.installed_dns.clone());
+                self.configure_dns_servers(lease.dns_servers.as_deref())?;
+                Ok(true)
+            }
+            // Export NTP servers to the time daemon if enabled
+            ApplyStep::Ntp => {
+                let Some(ref ntp_export) = self.ntp_export else {
+                    return Ok(false);
+                };
+                match lease.ntp_servers {
+                    Some(ref servers) if !servers.is_empty() => {
+                        self.ops.export_ntp(ntp_export, servers)?
+                    }
+                    _ => self.ops.clear_ntp(ntp_export)?,
+                }
+                Ok(true)
+            }
+            ApplyStep::Timezone => Ok(false),
+        }
+    }
+
+    /// Undo the applied steps in `report`, and whatever the failed step
+    /// managed to change, in reverse order
+    fn rollback(&mut self, report: &mut ApplyReport, snapshot: &Snapshot) {
+        for (step, status) in report.steps.iter_mut().rev() {
+            if !matches!(status, StepStatus::Applied | StepStatus::Failed(_)) {
+                continue;
+            }
+
+            info!("Rolling back {} on interface {}", step, self.interface);
+            let result = self.undo_step(*step, snapshot);
+            match (status, result) {
+                (status @ StepStatus::Applied, Ok(())) => *status = StepStatus::RolledBack,
+                (status @ StepStatus::Applied, Err(e)) => {
+                    warn!("Failed to roll back {}: {}", step, e);
+                    *status = StepStatus::RollbackFailed(e.to_string());
+                }
+                (_, Err(e)) => warn!("Failed to undo partial {} changes: {}", step, e),
+                (_, Ok(())) => {}
+            }
+        }
+
+        // Herald's previous routes need the previous address back in place
+        if let Err(e) = self.configure_routes(snapshot.routes.clone()) {
+            warn!("Failed to restore routes: {}", e);
+        }
+        if let Err(e) = self.configure_rule(snapshot.rule) {
+            warn!("Failed to restore routing rule: {}", e);
+        }
+    }
+
+    /// Restore the state a step replaced
+    fn undo_step(&mut self, step: ApplyStep, snapshot: &Snapshot) -> Result<(), HeraldError> {
+        match step {
+            ApplyStep::Mtu => {
+                if let Some(mtu) = snapshot.mtu {
+                    self.ops.set_mtu(mtu)?;
+                }
+                self.original_mtu = snapshot.original_mtu;
+            }
+            ApplyStep::Address => {
+                self.remove_routes();
+                if let Some(current) = self.installed_address.take() {
+                    let previous = snapshot.address.as_ref();
+                    if previous.map(|p| (p.address, p.prefix_len))
+                        != Some((current.address, current.prefix_len))
+                    {
+                        self.delete_address(&current);
+                    }
+                }
+                if let Some(ref previous) = snapshot.address {
+                    self.ops.add_address(previous)?;
+                    self.installed_address = Some(previous.clone());
+                }
+            }
+            ApplyStep::Routes => {
+                self.remove_routes();
+                self.configure_rule(None)?;
+            }
+            ApplyStep::Dns => {
+                if let Some(ref previous) = snapshot.dns {
+                    self.configure_dns_servers(previous.as_deref())?;
+                }
+            }
+            // The last steps of the transaction are never rolled back
+            ApplyStep::Ntp | ApplyStep::Timezone => {}
+        }
+        Ok(())
+    }
+
+    /// Configure the interface MTU, remembering the original value for restoration
+    fn configure_mtu(&mut self, mtu: u16) -> Result<(), HeraldError> {
+        if mtu < MIN_INTERFACE_MTU {
+            warn!(
+                "Ignoring interface MTU {} below minimum of {}",
+                mtu, MIN_INTERFACE_MTU
+            );
+            return Ok(());
+        }
+
+        if self.original_mtu.is_none() {
+            self.original_mtu = Some(self.read_mtu()?);
+        }
+
+        info!("Configuring MTU {} on interface {}", mtu, self.interface);
+        self.ops.set_mtu(mtu as u32)
+    }
+
+    /// Restore the MTU the interface had before the lease was applied
+    fn restore_mtu(&mut self) -> Result<(), HeraldError> {
+        if let Some(mtu) = self.original_mtu.take() {
+            info!("Restoring MTU {} on interface {}", mtu, self.interface);
+            self.ops.set_mtu(mtu)?;
+        }
+        Ok(())
+    }
+
+    /// Read the current interface MTU
+    fn read_mtu(&self) -> Result<u32, HeraldError> {
+        let name = self.interface.as_str();
+        netns::run_in(self.netns.as_ref(), || Interface::query(name)).map(|interface| interface.mtu)
+    }
+
+    /// The address and prefix of a lease, labelled as herald's. It expires
+    /// with the lease, so the kernel removes it if herald stops renewing.
+    fn lease_address(&self, lease: &Lease) -> Address {
+        let netmask = lease
+            .subnet_mask
+            .unwrap_or_else(|| "255.255.255.0".parse().expect("Invalid default netmask"));
+
+        Address {
+            address: lease.offered_ip,
+            prefix_len: self.netmask_to_cidr(netmask),
+            broadcast: lease.broadcast_address,
+            scope: Scope::Universe,
+            label: address_label(&self.interface),
+            valid_lifetime: address_lifetime(lease),
+            preferred_lifetime: address_lifetime(lease),
+        }
+    }
+
+    /// Configure IP address and subnet mask, replacing the address herald
+    /// installed for an earlier lease. Returns whether anything changed.
+    fn configure_ip_address(&mut self, lease: &Lease) -> Result<bool, HeraldError> {
+        let address = self.lease_address(lease);
+        // An address with a lifetime is added again to restart it
+        if self.installed_address.as_ref() == Some(&address) && address.valid_lifetime.is_none() {
+            return Ok(false);
+        }
+
+        // Removing a primary address also removes the secondaries in its
+        // subnet and the routes through it, so the old address and its routes
+        // go first. Adding the same address and prefix replaces it in place.
+        if let Some(previous) = self.installed_address.take() {
+            if (previous.address, previous.prefix_len) != (address.address, address.prefix_len) {
+                self.remove_routes();
+                self.delete_address(&previous);
+            }
+        }
+
+        info!(
+            "Configuring IP address {}/{} on interface {}",
+            address.address, address.prefix_len, self.interface
+        );
+
+        // Add the new IP address, with the server's broadcast address (option 28) if given
+        self.ops.add_address(&address)?;
+
+        // Bring the interface up
+        if let Err(e) = self.ops.set_link_up() {
+            warn!("Failed to bring interface up: {}", e);
+        }
+
+        self.installed_address = Some(address);
+        Ok(true)
+    }
+
+    /// Bring herald's routes in line with `routes`, removing the ones no
+    /// longer wanted and adding the new ones
+    fn configure_routes(&mut self, routes: Vec<Route>) -> Result<(), HeraldError> {
+        let (kept, stale): (Vec<_>, Vec<_>) = std::mem::take(&mut self.installed_routes)
+            .into_iter()
+            .partition(|route| routes.contains(route));
+        self.installed_routes = kept;
+
+        for route in stale {
+            self.delete_route(&route);
+        }
+
+        for route in routes {
+            if self.installed_routes.contains(&route) {
+                continue;
+            }
+
+            match route.gateway {
+                Some(gateway) if route.prefix_len == 0 => info!(
+                    "Configuring default route via {} metric {}",
+                    gateway,
+                    route.metric.unwrap_or_default()
+                ),
+                _ => info!(
+                    "Configuring static route to {} via {:?}",
+                    route.destination, route.gateway
+                ),
+            }
+
+            self.ops.replace_route(&route)?;
+            self.installed_routes.push(route);
+        }
+        Ok(())
+    }
+
+    /// Remove every route herald installed
+    fn remove_routes(&mut self) {
+        for route in std::mem::take(&mut self.installed_routes) {
+            self.delete_route(&route);
+        }
+    }
+
+    /// Replace herald's policy routing rule with `rule`
+    fn configure_rule(&mut self, rule: Option<Rule>) -> Result<(), HeraldError> {
+        if self.installed_rule == rule {
+            return Ok(());
+        }
+
+        if let Some(installed) = self.installed_rule.take() {
+            if let Err(e) = self.ops.delete_rule(&installed) {
+                warn!("Failed to remove routing rule {:?}: {}", installed, e);
+            }
+        }
+
+        if let Some(rule) = rule {
+            info!(
+                "Configuring policy rule from {} lookup table {}",
+                rule.source, rule.table
+            );
+            self.ops.add_rule(&rule)?;
+            self.installed_rule = Some(rule);
+        }
+
+        Ok(())
+    }
+
+    /// Routes herald installs for a lease: static host routes (option 33)
+    /// followed by a default route per router, repeated in the policy
+    /// routing table with the leased subnet if enabled
+    fn lease_routes(&self, lease: &Lease) -> Vec<Route> {
+        let static_routes = lease.static_routes.iter().flatten();
+        let static_routes = static_routes
+            .filter(|(destination, router)| {
+                // RFC 2132: the default route is not allowed in option 33
+                if destination.is_unspecified() {
+                    warn!("Ignoring static route to 0.0.0.0 via {}", router);
+                }
+                !destination.is_unspecified()
+            })
+            .map(|(destination, router)| host_route(*destination, *router, self.route_metric));
+        let routers = lease.routers.as_deref().unwrap_or_default();
+        if routers.len() > MAX_DEFAULT_ROUTES as usize {
+            warn!(
+                "Ignoring routers after the first {} of {}",
+                MAX_DEFAULT_ROUTES,
+                routers.len()
+            );
+        }
+        let mut routes: Vec<_> = static_routes
+            .chain(default_routes(routers, self.route_metric))
+            .collect();
+
+        if let Some(table) = self.route_table {
+            let address = self.lease_address(lease);
+            let mask = u32::MAX.checked_shl(32 - u32::from(address.prefix_len));
+            let subnet = Route {
+                destination: Ipv4Addr::from(u32::from(address.address) & mask.unwrap_or(0)),
+                prefix_len: address.prefix_len,
+                gateway: None,
+                metric: None,
+                protocol: RTPROT_DHCP,
+                table,
+            };
+            let table_routes = routes.iter().map(|route| Route { table, ..*route });
+            routes = routes
+                .iter()
+                .copied()
+                .chain(std::iter::once(subnet))
+                .chain(table_routes)
+                .collect();
+        }
+
+        routes
+    }
+
+    /// Rule sending traffic from the leased address to the policy routing table
+    fn lease_rule(&self, lease: &Lease) -> Option<Rule> {
+        self.route_table.map(|table| Rule {
+            source: lease.offered_ip,
+            source_len: 32,
+            table,
+            priority: Some(POLICY_RULE_PRIORITY),
+            protocol: RTPROT_DHCP,
+        })
+    }
+
+    /// Remove a route herald installed. Failures are only logged, since the
+    /// kernel removes routes along with the address they depend on.
+    fn delete_route(&self, route: &Route) {
+        if let Err(e) = self.ops.delete_route(route) {
+            warn!("Failed to remove route {:?}: {}", route, e);
+        }
+    }
+
+    /// Remove an address herald installed, logging failures
+    fn delete_address(&self, address: &Address) {
+        info!(
+            "Removing IP address {}/{} from interface {}",
+            address.address, address.prefix_len, self.interface
+        );
+        if let Err(e) = self.ops.delete_address(address.address, address.prefix_len) {
+            warn!("Failed to remove address {}: {}", address.address, e);
+        }
+    }
+
+    /// Publish DNS servers through the configured strategy, or withdraw
+    /// herald's if there are none
+    fn configure_dns_servers(
+        &mut self,
+        dns_servers: Option<&[Ipv4Addr]>,
+    ) -> Result<(), HeraldError> {
+        match dns_servers {
+            Some(servers) => {
+                info!("Configuring DNS servers: {:?}", servers);
+                self.ops.set_dns(&self.dns, servers)?;
+            }
+            None => self.ops.clear_dns(&self.dns)?,
+        }
+        self.installed_dns = dns_servers.map(<[Ipv4Addr]>::to_vec);
+        Ok(())
+    }
+
+    /// Convert subnet mask to CIDR notation
+    fn netmask_to_cidr(&self, netmask: Ipv4Addr) -> u8 {
+        let mask_bytes = netmask.octets();
+        let mask_u32 = u32::from_be_bytes(mask_bytes);
+        mask_u32.count_ones() as u8
     }
+}
 
+impl LeaseApplier for NetworkConfigurator {
     /// Apply the DHCP lease configuration to the network interface
-    pub fn apply_lease(&self, lease: &Lease) -> Result<(), HeraldError> {
+    fn apply(&mut self, lease: &Lease) -> Result<ApplyReport, HeraldError> {
         info!(
             "Applying DHCP lease configuration to interface {}",
             self.interface
         );
+        self.apply_settings(lease)
+    }
+
+    /// Update the configuration for a renewed lease. Only the addresses and
+    /// routes that changed are touched.
+    fn update(&mut self, _old: &Lease, new: &Lease) -> Result<ApplyReport, HeraldError> {
+        info!(
+            "Updating DHCP lease configuration on interface {}",
+            self.interface
+        );
+        self.apply_settings(new)
+    }
 
-        // Configure IP address and subnet mask
-        self.configure_ip_address(lease)?;
+    /// Remove the settings applied for a lease that was released or has expired
+    fn remove(&mut self, _lease: &Lease) -> Result<(), HeraldError> {
+        info!(
+            "Removing DHCP lease configuration from interface {}",
+            self.interface
+        );
 
-        // Configure default gateway if provided
-        if let Some(ref routers) = lease.routers {
-            if let Some(gateway) = routers.first() {
-                self.configure_default_route(*gateway)?;
-            }
+        self.remove_routes();
+        if let Err(e) = self.configure_rule(None) {
+            warn!("Failed to remove routing rule: {}", e);
+        }
+        if let Some(address) = self.installed_address.take() {
+            self.delete_address(&address);
         }
 
-        // Configure DNS servers if provided
-        if let Some(ref dns_servers) = lease.dns_servers {
-            self.configure_dns_servers(dns_servers)?;
+        // The interface may be gone, which must not keep DNS and NTP behind
+        if let Err(e) = self.restore_mtu() {
+            warn!("Failed to restore MTU: {}", e);
         }
 
-        info!("Successfully applied DHCP lease configuration");
-        Ok(())
+        // Both run even if the other fails; the first error is returned
+        let dns = match self.installed_dns.take() {
+            Some(_) => self.ops.clear_dns(&self.dns),
+            None => Ok(()),
+        };
+        let ntp = match self.ntp_export {
+            Some(ref ntp_export) => self.ops.clear_ntp(ntp_export),
+            None => Ok(()),
+        };
+        dns.and(ntp)
     }
 
-    /// Configure IP address and subnet mask using ip command
-    fn configure_ip_address(&self, lease: &Lease) -> Result<(), HeraldError> {
-        let ip_addr = lease.offered_ip;
-        let netmask = lease
-            .subnet_mask
-            .unwrap_or_else(|| "255.255.255.0".parse().expect("Invalid default netmask"));
+    /// Add an IPv4 link-local address claimed while no DHCP server answers
+    fn apply_link_local(&mut self, addr: Ipv4Addr) -> Result<(), HeraldError> {
+        info!(
+            "Configuring link-local address {} on interface {}",
+            addr, self.interface
+        );
 
-        // Calculate CIDR notation from subnet mask
-        let cidr = self.netmask_to_cidr(netmask);
+        self.ops.add_address(&Address {
+            address: addr,
+            prefix_len: LINK_LOCAL_PREFIX_LEN,
+            broadcast: Some(LINK_LOCAL_BROADCAST),
+            scope: Scope::Link,
+            label: address_label(&self.interface),
+            valid_lifetime: None,
+            preferred_lifetime: None,
+        })
+    }
 
+    /// Remove an IPv4 link-local address added by `apply_link_local`
+    fn remove_link_local(&mut self, addr: Ipv4Addr) -> Result<(), HeraldError> {
         info!(
-            "Configuring IP address {}/{} on interface {}",
-            ip_addr, cidr, self.interface
+            "Removing link-local address {} from interface {}",
+            addr, self.interface
         );
 
-        // First, flush existing IP addresses on the interface
-        let flush_output = Command::new("ip")
-            .args(["addr", "flush", "dev", &self.interface])
-            .output()
-            .map_err(HeraldError::Io)?;
+        self.ops.delete_address(addr, LINK_LOCAL_PREFIX_LEN)
+    }
+}
 
-        if !flush_output.status.success() {
-            warn!(
-                "Failed to flush existing IP addresses: {}",
-                String::from_utf8_lossy(&flush_output.stderr)
-            );
-        }
+/// Owned iproute2 arguments from string slices
+fn to_args(args: &[&str]) -> Vec<String> {
+    args.iter().map(ToString::to_string).collect()
+}
 
-        // Add the new IP address
-        let output = Command::new("ip")
-            .args([
-                "addr",
-                "add",
-                &format!("{ip_addr}/{cidr}"),
-                "dev",
-                &self.interface,
-            ])
-            .output()
-            .map_err(HeraldError::Io)?;
+fn mtu_args(interface: &str, mtu: u32) -> Vec<String> {
+    to_args(&["link", "set", "dev", interface, "mtu", &mtu.to_string()])
+}
 
-        if !output.status.success() {
-            return Err(HeraldError::Critical(format!(
-                "Failed to configure IP address: {}",
-                String::from_utf8_lossy(&output.stderr)
-            )));
-        }
+fn link_up_args(interface: &str) -> Vec<String> {
+    to_args(&["link", "set", "dev", interface, "up"])
+}
 
-        // Bring the interface up
-        let up_output = Command::new("ip")
-            .args(["link", "set", "dev", &self.interface, "up"])
-            .output()
-            .map_err(HeraldError::Io)?;
+fn add_address_args(interface: &str, address: &Address) -> Vec<String> {
+    // `replace` so that re-applying a renewed lease is not an error
+    let mut args = to_args(&[
+        "addr",
+        "replace",
+        &format!("{}/{}", address.address, address.prefix_len),
+    ]);
+    if let Some(broadcast) = address.broadcast {
+        args.extend(to_args(&["broadcast", &broadcast.to_string()]));
+    }
+    if address.scope == Scope::Link {
+        args.extend(to_args(&["scope", "link"]));
+    }
+    if let Some(ref label) = address.label {
+        args.extend(to_args(&["label", label]));
+    }
+    if address.valid_lifetime.is_some() || address.preferred_lifetime.is_some() {
+        let lifetime = |secs: Option<u32>| secs.map_or("forever".to_string(), |s| s.to_string());
+        args.extend(to_args(&[
+            "valid_lft",
+            &lifetime(address.valid_lifetime),
+            "preferred_lft",
+            &lifetime(address.preferred_lifetime),
+        ]));
+    }
+    args.extend(to_args(&["dev", interface]));
+    args
+}
 
-        if !up_output.status.success() {
-            warn!(
-                "Failed to bring interface up: {}",
-                String::from_utf8_lossy(&up_output.stderr)
-            );
-        }
+fn delete_address_args(interface: &str, address: Ipv4Addr, prefix_len: u8) -> Vec<String> {
+    to_args(&[
+        "addr",
+        "del",
+        &format!("{address}/{prefix_len}"),
+        "dev",
+        interface,
+    ])
+}
 
-        Ok(())
+fn route_args(command: &str, interface: &str, route: &Route) -> Vec<String> {
+    let destination = if route.prefix_len == 0 {
+        "default".to_string()
+    } else {
+        format!("{}/{}", route.destination, route.prefix_len)
+    };
+    let mut args = to_args(&["route", command, &destination]);
+    if let Some(gateway) = route.gateway {
+        args.extend(to_args(&["via", &gateway.to_string()]));
     }
+    args.extend(to_args(&["dev", interface]));
+    args.extend(to_args(&["proto", &protocol_name(route.protocol)]));
+    if let Some(metric) = route.metric {
+        args.extend(to_args(&["metric", &metric.to_string()]));
+    }
+    if route.table != MAIN_TABLE {
+        args.extend(to_args(&["table", &route.table.to_string()]));
+    }
+    args
+}
 
-    /// Configure default route using ip command
-    fn configure_default_route(&self, gateway: std::net::Ipv4Addr) -> Result<(), HeraldError> {
-        info!("Configuring default route via {}", gateway);
-
-        // Delete existing default routes
-        let _ = Command::new("ip")
-            .args(["route", "del", "default"])
-            .output();
+fn flush_address_args(interface: &str, label: &str) -> Vec<String> {
+    to_args(&["addr", "flush", "dev", interface, "label", label])
+}
 
-        // Add new default route
-        let output = Command::new("ip")
-            .args(["route", "add", "default", "via", &gateway.to_string()])
-            .output()
-            .map_err(HeraldError::Io)?;
+fn show_routes_args(interface: &str, table: u32) -> Vec<String> {
+    to_args(&[
+        "-4",
+        "route",
+        "show",
+        "dev",
+        interface,
+        "proto",
+        &protocol_name(RTPROT_DHCP),
+        "table",
+        &table.to_string(),
+    ])
+}
 
-        if !output.status.success() {
-            warn!(
-                "Failed to configure default route: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
+/// Parse a line of `ip route show` filtered by device and protocol, such as
+/// `default via 10.0.0.1 metric 1024`
+fn parse_route(line: &str, table: u32) -> Option<Route> {
+    let mut words = line.split_whitespace();
+    let (destination, prefix_len) = match words.next()? {
+        "default" => (Ipv4Addr::UNSPECIFIED, 0),
+        prefix => match prefix.split_once('/') {
+            Some((address, len)) => (address.parse().ok()?, len.parse().ok()?),
+            None => (prefix.parse().ok()?, 32),
+        },
+    };
+    let mut route = Route {
+        destination,
+        prefix_len,
+        gateway: None,
+        metric: None,
+        protocol: RTPROT_DHCP,
+        table,
+    };
+    while let Some(word) = words.next() {
+        match word {
+            "via" => route.gateway = Some(words.next()?.parse().ok()?),
+            "metric" => route.metric = Some(words.next()?.parse().ok()?),
+            _ => {}
         }
+    }
+    Some(route)
+}
 
-        Ok(())
+fn rule_args(command: &str, rule: &Rule) -> Vec<String> {
+    let mut args = to_args(&[
+        "rule",
+        command,
+        "from",
+        &format!("{}/{}", rule.source, rule.source_len),
+        "lookup",
+        &rule.table.to_string(),
+    ]);
+    if let Some(priority) = rule.priority {
+        args.extend(to_args(&["priority", &priority.to_string()]));
+    }
+    args.extend(to_args(&["proto", &protocol_name(rule.protocol)]));
+    args
+}
+
+/// Name of a route protocol as understood by iproute2
+fn protocol_name(protocol: u8) -> String {
+    match protocol {
+        libc::RTPROT_BOOT => "boot".to_string(),
+        libc::RTPROT_STATIC => "static".to_string(),
+        RTPROT_DHCP => "dhcp".to_string(),
+        other => other.to_string(),
     }
+}
 
-    /// Configure DNS servers by updating /etc/resolv.conf
-    fn configure_dns_servers(&self, dns_servers: &[std::net::Ipv4Addr]) -> Result<(), HeraldError> {
-        info!("Configuring DNS servers: {:?}", dns_servers);
+/// Host route from option 33, with herald's base `metric`
+fn host_route(destination: Ipv4Addr, router: Ipv4Addr, metric: u32) -> Route {
+    Route {
+        destination,
+        prefix_len: 32,
+        gateway: Some(router),
+        metric: Some(metric),
+        protocol: RTPROT_DHCP,
+        table: MAIN_TABLE,
+    }
+}
 
-        // Create resolv.conf content
-        let mut resolv_conf = String::new();
-        resolv_conf.push_str("# Generated by herald DHCP client\n");
+/// Default routes via each router, with metrics rising from `base`
+fn default_routes(routers: &[Ipv4Addr], base: u32) -> impl Iterator<Item = Route> + '_ {
+    default_route_metrics(routers, base)
+        .into_iter()
+        .map(|(gateway, metric)| Route {
+            metric: Some(metric),
+            protocol: RTPROT_DHCP,
+            ..Route::default_via(gateway)
+        })
+}
 
-        for dns in dns_servers {
-            resolv_conf.push_str(&format!("nameserver {dns}\n"));
+/// Resolver configuration for an interface in `netns`. Nameservers are
+/// merged into the namespace's own resolv.conf, which `ip netns exec` mounts
+/// over the host's; namespaces without a name have none, so DNS is left alone.
+fn netns_resolv_conf(config: &ClientConfig, netns: &NetNs) -> ResolvConf {
+    match (netns.name(), netns.etc_dir()) {
+        (Some(name), Some(etc)) if config.dns_strategy != DnsStrategy::None => {
+            ResolvConf::new(DnsStrategy::Merge, &config.interface, config.dns_priority).with_paths(
+                &etc.join("resolv.conf"),
+                &Path::new(NETNS_STATE_DIR).join(name),
+            )
         }
+        _ => ResolvConf::new(DnsStrategy::None, &config.interface, config.dns_priority),
+    }
+}
 
-        // Write to /etc/resolv.conf
-        std::fs::write("/etc/resolv.conf", resolv_conf).map_err(HeraldError::Io)?;
+/// Address lifetime for a lease: its duration, or `None` for an infinite lease
+fn address_lifetime(lease: &Lease) -> Option<u32> {
+    let secs = lease.lease_duration?.as_secs();
+    (secs < u64::from(INFINITE_LIFETIME)).then_some(secs as u32)
+}
 
-        Ok(())
-    }
+/// Label for herald's addresses on `interface`, if it fits in `IFNAMSIZ`.
+///
+/// The kernel requires labels to start with the interface name.
+fn address_label(interface: &str) -> Option<String> {
+    let label = format!("{interface}:dhcp");
+    (label.len() <= MAX_LABEL_LEN).then_some(label)
+}
 
-    /// Convert subnet mask to CIDR notation
-    fn netmask_to_cidr(&self, netmask: std::net::Ipv4Addr) -> u8 {
-        let mask_bytes = netmask.octets();
-        let mask_u32 = u32::from_be_bytes(mask_bytes);
-        mask_u32.count_ones() as u8
-    }
+/// Pair each router with its default route metric, starting from `base`, in
+/// the order given by the server. Routers past `MAX_DEFAULT_ROUTES` are left
+/// out.
+fn default_route_metrics(routers: &[Ipv4Addr], base: u32) -> Vec<(Ipv4Addr, u32)> {
+    routers
+        .iter()
+        .take(MAX_DEFAULT_ROUTES as usize)
+        .zip(base..)
+        .map(|(router, metric)| (*router, metric))
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
 
     #[test]
     fn test_netmask_to_cidr() {
@@ -177,4 +1336,692 @@ mod tests {
             32
         );
     }
+
+    #[test]
+    fn test_mtu_below_minimum_is_ignored() {
+        let mut configurator = NetworkConfigurator::new("test".to_string());
+
+        configurator.configure_mtu(MIN_INTERFACE_MTU - 1).unwrap();
+        assert_eq!(configurator.original_mtu, None);
+        configurator.restore_mtu().unwrap();
+    }
+
+    #[test]
+    fn test_default_route_metrics_increase() {
+        let routers = [
+            "192.168.1.1".parse().unwrap(),
+            "192.168.1.2".parse().unwrap(),
+            "192.168.1.3".parse().unwrap(),
+        ];
+
+        let metrics = default_route_metrics(&routers, DEFAULT_ROUTE_METRIC);
+        assert_eq!(
+            metrics,
+            vec![
+                (routers[0], DEFAULT_ROUTE_METRIC),
+                (routers[1], DEFAULT_ROUTE_METRIC + 1),
+                (routers[2], DEFAULT_ROUTE_METRIC + 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_iproute2_arguments() {
+        let mut address = Address::new(Ipv4Addr::new(169, 254, 3, 4), 16);
+        address.broadcast = Some(LINK_LOCAL_BROADCAST);
+        address.scope = Scope::Link;
+        address.label = address_label("eth0");
+        assert_eq!(
+            add_address_args("eth0", &address).join(" "),
+            "addr replace 169.254.3.4/16 broadcast 169.254.255.255 scope link label eth0:dhcp dev eth0"
+        );
+
+        let route = default_routes(&[Ipv4Addr::new(192, 168, 1, 1)], DEFAULT_ROUTE_METRIC)
+            .next()
+            .unwrap();
+        assert_eq!(
+            route_args("replace", "eth0", &route).join(" "),
+            "route replace default via 192.168.1.1 dev eth0 proto dhcp metric 1024"
+        );
+        assert_eq!(
+            route_args(
+                "del",
+                "eth0",
+                &host_route(
+                    Ipv4Addr::new(10, 0, 0, 1),
+                    Ipv4Addr::new(192, 168, 1, 1),
+                    DEFAULT_ROUTE_METRIC
+                )
+            )
+            .join(" "),
+            "route del 10.0.0.1/32 via 192.168.1.1 dev eth0 proto dhcp metric 1024"
+        );
+    }
+
+    #[test]
+    fn test_parse_route() {
+        let route = parse_route("default via 10.0.0.1 metric 1025 linkdown", 101).unwrap();
+        assert_eq!(
+            route_args("del", "eth0", &route).join(" "),
+            "route del default via 10.0.0.1 dev eth0 proto dhcp metric 1025 table 101"
+        );
+        let route = parse_route("10.0.0.7 via 10.0.0.1 metric 1024", MAIN_TABLE).unwrap();
+        assert_eq!(
+            (route.destination, route.prefix_len),
+            (Ipv4Addr::new(10, 0, 0, 7), 32)
+        );
+        let route = parse_route("10.0.0.0/24 scope link", 101).unwrap();
+        assert_eq!(
+            (route.prefix_len, route.gateway, route.metric),
+            (24, None, None)
+        );
+        assert_eq!(parse_route("", MAIN_TABLE), None);
+    }
+
+    #[test]
+    fn test_address_lifetime_follows_lease() {
+        let configurator = NetworkConfigurator::new("eth0".to_string());
+        let mut lease = Lease::new(Ipv4Addr::new(192, 168, 1, 100));
+        assert_eq!(configurator.lease_address(&lease).valid_lifetime, None);
+
+        lease.lease_duration = Some(Duration::from_secs(3600));
+        let address = configurator.lease_address(&lease);
+        assert_eq!(address.valid_lifetime, Some(3600));
+        assert_eq!(address.preferred_lifetime, Some(3600));
+        assert_eq!(
+            add_address_args("eth0", &address).join(" "),
+            "addr replace 192.168.1.100/24 label eth0:dhcp valid_lft 3600 preferred_lft 3600 dev eth0"
+        );
+
+        // 0xffffffff is an infinite lease (RFC 2131)
+        lease.lease_duration = Some(Duration::from_secs(u32::MAX.into()));
+        assert_eq!(configurator.lease_address(&lease).valid_lifetime, None);
+    }
+
+    #[test]
+    fn test_renewal_refreshes_address_lifetime() {
+        let ops = MockOps::default();
+        let mut configurator =
+            NetworkConfigurator::with_ops("eth0".to_string(), Box::new(ops.clone()));
+        let mut lease = Lease::new(Ipv4Addr::new(192, 168, 1, 100));
+        lease.lease_duration = Some(Duration::from_secs(600));
+
+        configurator.apply(&lease).unwrap();
+        ops.take();
+        configurator.update(&lease, &lease).unwrap();
+        assert_eq!(
+            ops.take(),
+            ["addr replace 192.168.1.100/24 label eth0:dhcp valid_lft 600 preferred_lft 600 dev eth0"]
+        );
+    }
+
+    #[test]
+    fn test_policy_routing_table_and_rule() {
+        let ops = MockOps::default();
+        let mut configurator =
+            NetworkConfigurator::with_ops("eth0".to_string(), Box::new(ops.clone()));
+        configurator.route_metric = 2048;
+        configurator.route_table = Some(101);
+
+        let mut lease = Lease::new(Ipv4Addr::new(10, 0, 0, 5));
+        lease.routers = Some(vec![Ipv4Addr::new(10, 0, 0, 1)]);
+        configurator.apply(&lease).unwrap();
+        assert_eq!(
+            ops.take(),
+            [
+                "addr replace 10.0.0.5/24 label eth0:dhcp dev eth0",
+                "route replace default via 10.0.0.1 dev eth0 proto dhcp metric 2048",
+                "route replace 10.0.0.0/24 dev eth0 proto dhcp table 101",
+                "route replace default via 10.0.0.1 dev eth0 proto dhcp metric 2048 table 101",
+                "rule add from 10.0.0.5/32 lookup 101 priority 10000 proto dhcp",
+            ]
+        );
+
+        // Renewing changes nothing; releasing removes only herald's entries
+        configurator.update(&lease, &lease).unwrap();
+        assert!(ops.take().is_empty());
+        configurator.remove(&lease).unwrap();
+        assert_eq!(
+            ops.take(),
+            [
+                "route del default via 10.0.0.1 dev eth0 proto dhcp metric 2048",
+                "route del 10.0.0.0/24 dev eth0 proto dhcp table 101",
+                "route del default via 10.0.0.1 dev eth0 proto dhcp metric 2048 table 101",
+                "rule del from 10.0.0.5/32 lookup 101 priority 10000 proto dhcp",
+                "addr del 10.0.0.5/24 dev eth0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_netns_leaves_host_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ClientConfig::new("eth0".to_string(), bytes::Bytes::new());
+        config.netns = Some("blue".parse().unwrap());
+        config.ntp_format = Some(crate::network::ntp::NtpFormat::Chrony);
+        config.ntp_directory = Some(dir.path().to_path_buf());
+        config.apply_timezone = true;
+        config.dns_strategy = DnsStrategy::Resolvconf;
+
+        let ops = MockOps::default();
+        let mut configurator = NetworkConfigurator::from_config(&config, Box::new(ops.clone()));
+        assert_eq!(
+            configurator.dns.path(),
+            Path::new("/etc/netns/blue/resolv.conf")
+        );
+
+        let mut lease = Lease::new(Ipv4Addr::new(10, 0, 0, 5));
+        lease.ntp_servers = Some(vec![Ipv4Addr::new(10, 0, 0, 1)]);
+        lease.tz_database_name = Some("Europe/Berlin".to_string());
+        let report = configurator.apply_settings(&lease).unwrap();
+        assert_eq!(report.status(ApplyStep::Ntp), Some(&StepStatus::Skipped));
+        assert_eq!(
+            report.status(ApplyStep::Timezone),
+            Some(&StepStatus::Skipped)
+        );
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // A namespace given by path has no resolv.conf of its own
+        config.netns = Some("/proc/42/ns/net".parse().unwrap());
+        let configurator = NetworkConfigurator::from_config(&config, Box::new(ops));
+        assert_eq!(configurator.dns.strategy(), DnsStrategy::None);
+    }
+
+    #[test]
+    fn test_uplinks_keep_their_default_routes() {
+        use clap::Parser;
+        let uplink = |name: &str, index| Interface {
+            name: name.to_string(),
+            index,
+            hardware_type: libc::ARPHRD_ETHER,
+            hardware_address: bytes::Bytes::from_static(&[2, 0, 0, 0, 0, 1]),
+            mtu: 1500,
+            flags: 0,
+        };
+        let default_routes = |args: &[&str]| {
+            let args = crate::Args::parse_from(args);
+            [uplink("eth0", 2), uplink("eth1", 3)].map(|interface| {
+                let ops = MockOps::default();
+                let config = args.client_config(&interface);
+                let mut configurator =
+                    NetworkConfigurator::from_config(&config, Box::new(ops.clone()));
+                let mut lease = Lease::new(Ipv4Addr::new(10, 0, interface.index as u8, 5));
+                lease.routers = Some(vec![Ipv4Addr::new(10, 0, interface.index as u8, 1)]);
+                configurator.apply(&lease).unwrap();
+                ops.take()
+                    .into_iter()
+                    .filter(|op| op.starts_with("route replace default"))
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        // Common settings are offset by the interface index
+        let [eth0, eth1] =
+            default_routes(&["herald", "-i", "eth0", "-i", "eth1", "--route-table", "100"]);
+        assert_eq!(
+            eth0,
+            [
+                "route replace default via 10.0.2.1 dev eth0 proto dhcp metric 1026",
+                "route replace default via 10.0.2.1 dev eth0 proto dhcp metric 1026 table 102",
+            ]
+        );
+        assert_eq!(
+            eth1,
+            [
+                "route replace default via 10.0.3.1 dev eth0 proto dhcp metric 1027",
+                "route replace default via 10.0.3.1 dev eth0 proto dhcp metric 1027 table 103",
+            ]
+        );
+
+        // Settings for one interface are taken as they are
+        let [eth0, eth1] = default_routes(&[
+            "herald",
+            "-i",
+            "eth0",
+            "-i",
+            "eth1",
+            "--route-metric",
+            "100",
+            "--route-metric",
+            "eth1=50",
+        ]);
+        assert_eq!(
+            eth0,
+            ["route replace default via 10.0.2.1 dev eth0 proto dhcp metric 102"]
+        );
+        assert_eq!(
+            eth1,
+            ["route replace default via 10.0.3.1 dev eth0 proto dhcp metric 50"]
+        );
+    }
+
+    #[test]
+    fn test_parse_route_table() {
+        assert_eq!(parse_route_table("100"), Ok(100));
+        assert_eq!(parse_route_table("1000"), Ok(1000));
+        assert!(parse_route_table("254").is_err());
+        assert!(parse_route_table("0").is_err());
+        assert!(parse_route_table("main").is_err());
+    }
+
+    #[test]
+    fn test_address_label_fits_ifnamsiz() {
+        assert_eq!(address_label("eth0"), Some("eth0:dhcp".to_string()));
+        assert_eq!(address_label("wlp0s20f0u1u2"), None);
+    }
+
+    #[test]
+    fn test_dry_run_leaves_host_alone() {
+        // The interface does not exist, so any real change would fail
+        let interface = "herald-dry0".to_string();
+        let ops = Box::new(DryRunOps::new(interface.clone()));
+        let mut configurator = NetworkConfigurator::with_ops(interface, ops);
+
+        let mut lease = Lease::new(Ipv4Addr::new(192, 168, 1, 100));
+        lease.routers = Some(vec![Ipv4Addr::new(192, 168, 1, 1)]);
+        lease.dns_servers = Some(vec![Ipv4Addr::new(192, 168, 1, 1)]);
+
+        configurator.apply(&lease).unwrap();
+        configurator.update(&lease, &lease).unwrap();
+        configurator.remove(&lease).unwrap();
+    }
+
+    /// Records the iproute2 commands it is asked to run, and fails clearing
+    /// DNS servers or setting others than 192.168.1.1 once `fail_dns` is set
+    #[derive(Clone, Default)]
+    struct MockOps {
+        commands: Arc<Mutex<Vec<String>>>,
+        fail_dns: Arc<AtomicBool>,
+        fail_routes: Arc<AtomicBool>,
+    }
+
+    impl MockOps {
+        fn log(&self, args: Vec<String>) -> Result<(), HeraldError> {
+            self.commands.lock().unwrap().push(args.join(" "));
+            Ok(())
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut self.commands.lock().unwrap())
+        }
+    }
+
+    impl SystemOps for MockOps {
+        fn set_mtu(&self, mtu: u32) -> Result<(), HeraldError> {
+            self.log(mtu_args("eth0", mtu))
+        }
+
+        fn set_link_up(&self) -> Result<(), HeraldError> {
+            Ok(())
+        }
+
+        fn add_address(&self, address: &Address) -> Result<(), HeraldError> {
+            self.log(add_address_args("eth0", address))
+        }
+
+        fn delete_address(&self, address: Ipv4Addr, prefix_len: u8) -> Result<(), HeraldError> {
+            self.log(delete_address_args("eth0", address, prefix_len))
+        }
+
+        fn replace_route(&self, route: &Route) -> Result<(), HeraldError> {
+            if self.fail_routes.load(Ordering::Relaxed) {
+                return Err(HeraldError::Critical("Network is unreachable".to_string()));
+            }
+            self.log(route_args("replace", "eth0", route))
+        }
+
+        fn delete_route(&self, route: &Route) -> Result<(), HeraldError> {
+            self.log(route_args("del", "eth0", route))
+        }
+
+        fn add_rule(&self, rule: &Rule) -> Result<(), HeraldError> {
+            self.log(rule_args("add", rule))
+        }
+
+        fn delete_rule(&self, rule: &Rule) -> Result<(), HeraldError> {
+            self.log(rule_args("del", rule))
+        }
+
+        fn flush_addresses(&self, label: &str) -> Result<(), HeraldError> {
+            self.log(flush_address_args("eth0", label))
+        }
+
+        fn flush_routes(
+            &self,
+            table: u32,
+            metrics: RangeInclusive<u32>,
+        ) -> Result<(), HeraldError> {
+            self.log(vec![format!(
+                "flush routes table {table} metric {metrics:?}"
+            )])
+        }
+
+        fn set_dns(&self, _dns: &ResolvConf, servers: &[Ipv4Addr]) -> Result<(), HeraldError> {
+            if self.fail_dns.load(Ordering::Relaxed) && servers != [Ipv4Addr::new(192, 168, 1, 1)] {
+                return Err(HeraldError::Critical("read-only file system".to_string()));
+            }
+            self.commands
+                .lock()
+                .unwrap()
+                .push(format!("set dns: {servers:?}"));
+            Ok(())
+        }
+
+        fn clear_dns(&self, _dns: &ResolvConf) -> Result<(), HeraldError> {
+            if self.fail_dns.load(Ordering::Relaxed) {
+                return Err(HeraldError::Critical("read-only file system".to_string()));
+            }
+            self.commands.lock().unwrap().push("clear dns".to_string());
+            Ok(())
+        }
+
+        fn clear_ntp(&self, _export: &NtpExport) -> Result<(), HeraldError> {
+            self.commands.lock().unwrap().push("clear ntp".to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_remove_clears_ntp_when_dns_fails() {
+        let ops = MockOps::default();
+        let mut configurator =
+            NetworkConfigurator::with_ops("eth0".to_string(), Box::new(ops.clone()));
+        configurator.ntp_export = Some(NtpExport::new(
+            crate::network::ntp::NtpFormat::Chrony,
+            None,
+            "eth0",
+        ));
+        let mut lease = Lease::new(Ipv4Addr::new(192, 168, 1, 100));
+        lease.dns_servers = Some(vec![Ipv4Addr::new(192, 168, 1, 1)]);
+        configurator.apply(&lease).unwrap();
+        ops.take();
+
+        ops.fail_dns.store(true, Ordering::Relaxed);
+        let Err(HeraldError::Critical(e)) = configurator.remove(&lease) else {
+            panic!("expected clearing DNS to fail");
+        };
+        assert_eq!(e, "read-only file system");
+        assert!(ops.take().contains(&"clear ntp".to_string()));
+    }
+
+    #[test]
+    fn test_failed_route_rolls_back_address() {
+        let ops = MockOps::default();
+        let mut configurator =
+            NetworkConfigurator::with_ops("eth0".to_string(), Box::new(ops.clone()));
+        ops.fail_routes.store(true, Ordering::Relaxed);
+
+        let mut lease = Lease::new(Ipv4Addr::new(10, 0, 0, 5));
+        lease.routers = Some(vec![Ipv4Addr::new(10, 0, 0, 1)]);
+        let Err(HeraldError::Apply(report)) = configurator.apply(&lease) else {
+            panic!("expected the routes step to fail");
+        };
+        assert_eq!(report.failed_step(), Some(ApplyStep::Routes));
+        assert_eq!(
+            report.status(ApplyStep::Address),
+            Some(&StepStatus::RolledBack)
+        );
+        assert_eq!(
+            ops.take(),
+            [
+                "addr replace 10.0.0.5/24 label eth0:dhcp dev eth0",
+                "addr del 10.0.0.5/24 dev eth0",
+            ]
+        );
+        assert_eq!(configurator.installed_address, None);
+        assert!(configurator.installed_routes.is_empty());
+
+        // The report of a successful apply reaches the caller
+        ops.fail_routes.store(false, Ordering::Relaxed);
+        let report = configurator.apply(&lease).unwrap();
+        assert_eq!(report.status(ApplyStep::Routes), Some(&StepStatus::Applied));
+    }
+
+    #[test]
+    fn test_failed_step_rolls_back_earlier_steps() {
+        let ops = MockOps::default();
+        let mut configurator =
+            NetworkConfigurator::with_ops("eth0".to_string(), Box::new(ops.clone()));
+
+        let mut first = Lease::new(Ipv4Addr::new(192, 168, 1, 100));
+        first.routers = Some(vec![Ipv4Addr::new(192, 168, 1, 1)]);
+        first.dns_servers = Some(vec![Ipv4Addr::new(192, 168, 1, 1)]);
+        configurator.apply(&first).unwrap();
+        ops.take();
+
+        // The new lease moves the address, but its DNS step fails
+        ops.fail_dns.store(true, Ordering::Relaxed);
+        let mut second = Lease::new(Ipv4Addr::new(10, 0, 0, 5));
+        second.routers = Some(vec![Ipv4Addr::new(10, 0, 0, 1)]);
+        second.dns_servers = Some(vec![Ipv4Addr::new(10, 0, 0, 1)]);
+
+        let Err(HeraldError::Apply(report)) = configurator.apply(&second) else {
+            panic!("expected the DNS step to fail");
+        };
+        assert_eq!(report.failed_step(), Some(ApplyStep::Dns));
+        assert_eq!(report.status(ApplyStep::Mtu), Some(&StepStatus::Skipped));
+        assert_eq!(
+            report.status(ApplyStep::Address),
+            Some(&StepStatus::RolledBack)
+        );
+        assert_eq!(
+            report.status(ApplyStep::Routes),
+            Some(&StepStatus::RolledBack)
+        );
+        assert_eq!(report.status(ApplyStep::Ntp), None);
+
+        let commands = ops.take();
+        let rollback_start = commands
+            .iter()
+            .position(|c| c.starts_with("set dns"))
+            .unwrap();
+        assert_eq!(
+            commands[rollback_start..],
+            [
+                "set dns: [192.168.1.1]",
+                "route del default via 10.0.0.1 dev eth0 proto dhcp metric 1024",
+                "addr del 10.0.0.5/24 dev eth0",
+                "addr replace 192.168.1.100/24 label eth0:dhcp dev eth0",
+                "route replace default via 192.168.1.1 dev eth0 proto dhcp metric 1024",
+            ]
+        );
+        assert_eq!(
+            configurator.installed_address,
+            Some(configurator.lease_address(&first))
+        );
+        assert_eq!(
+            configurator.installed_routes,
+            configurator.lease_routes(&first)
+        );
+    }
+
+    #[test]
+    fn test_reapply_policy_routing_in_namespace() {
+        crate::network::netlink::in_network_namespace(|| {
+            let netlink = NetlinkHandle::open().unwrap();
+            let mut lease = Lease::new(Ipv4Addr::new(10, 1, 2, 3));
+            lease.routers = Some(vec![Ipv4Addr::new(10, 1, 2, 1)]);
+
+            let backends: [fn() -> Box<dyn SystemOps>; 2] = [
+                || Box::new(NetlinkOps::new("lo".to_string()).unwrap()),
+                || Box::new(Iproute2Ops::new("lo".to_string())),
+            ];
+            for ops in backends {
+                // A restarted herald finds the rule of the previous run
+                for _ in 0..2 {
+                    let mut configurator = NetworkConfigurator::with_ops("lo".to_string(), ops());
+                    configurator.route_table = Some(101);
+                    if let Err(e) = configurator.apply_settings(&lease) {
+                        panic!("applying again failed: {e}");
+                    }
+                }
+                let rules = netlink.rules().unwrap();
+                assert_eq!(rules.len(), 1);
+                netlink.delete_rule(&rules[0]).unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_remove_stale_in_namespace() {
+        crate::network::netlink::in_network_namespace(|| {
+            let netlink = NetlinkHandle::open().unwrap();
+            let lo = netlink.link_index("lo").unwrap();
+            netlink.set_link_up(lo).unwrap();
+
+            let backends: [fn() -> Box<dyn SystemOps>; 2] = [
+                || Box::new(NetlinkOps::new("lo".to_string()).unwrap()),
+                || Box::new(Iproute2Ops::new("lo".to_string())),
+            ];
+            for ops in backends {
+                // Left behind by a run that did not get to remove its lease
+                let mut stale = NetworkConfigurator::with_ops("lo".to_string(), ops());
+                stale.route_table = Some(101);
+                let mut lease = Lease::new(Ipv4Addr::new(10, 1, 2, 3));
+                lease.routers = Some(vec![Ipv4Addr::new(10, 1, 2, 1)]);
+                lease.static_routes = Some(vec![(
+                    Ipv4Addr::new(192, 168, 7, 7),
+                    Ipv4Addr::new(10, 1, 2, 1),
+                )]);
+                stale.apply_settings(&lease).unwrap();
+
+                // Not herald's, including another DHCP client's route
+                let foreign_address = Address::new(Ipv4Addr::new(10, 9, 0, 1), 24);
+                netlink.add_address(lo, &foreign_address).unwrap();
+                let foreign_route = Route::default_via(Ipv4Addr::new(10, 9, 0, 254));
+                netlink.replace_route(lo, &foreign_route).unwrap();
+                let mut other_client_route = Route::default_via(Ipv4Addr::new(10, 9, 0, 253));
+                other_client_route.protocol = RTPROT_DHCP;
+                other_client_route.metric = Some(100);
+                netlink.replace_route(lo, &other_client_route).unwrap();
+
+                let mut configurator = NetworkConfigurator::with_ops("lo".to_string(), ops());
+                configurator.route_table = Some(101);
+                configurator.remove_stale();
+
+                let addresses = netlink.addresses(lo).unwrap();
+                assert!(!addresses.iter().any(|a| a.address == lease.offered_ip));
+                assert!(addresses
+                    .iter()
+                    .any(|a| a.address == foreign_address.address));
+                for table in [MAIN_TABLE, 101] {
+                    assert!(!netlink
+                        .routes(table)
+                        .unwrap()
+                        .iter()
+                        .any(|(_, route)| route.protocol == RTPROT_DHCP
+                            && *route != other_client_route));
+                }
+                let routes = netlink.routes(MAIN_TABLE).unwrap();
+                assert!(routes.contains(&(lo, foreign_route)));
+                assert!(routes.contains(&(lo, other_client_route)));
+
+                // Nothing left to remove is not an error
+                let ops = ops();
+                ops.flush_addresses("lo:dhcp").unwrap();
+                ops.flush_routes(101, 0..=u32::MAX).unwrap();
+
+                netlink
+                    .delete_address(lo, foreign_address.address, foreign_address.prefix_len)
+                    .unwrap();
+                netlink.delete_route(lo, &other_client_route).unwrap();
+                for rule in netlink.rules().unwrap() {
+                    netlink.delete_rule(&rule).unwrap();
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_netlink_backend_in_namespace() {
+        crate::network::netlink::in_network_namespace(|| {
+            let ops = Box::new(NetlinkOps::new("lo".to_string()).unwrap());
+            let mut configurator = NetworkConfigurator::with_ops("lo".to_string(), ops);
+            let netlink = NetlinkHandle::open().unwrap();
+            let lo = netlink.link_index("lo").unwrap();
+
+            // An address and default route that herald does not own
+            netlink.set_link_up(lo).unwrap();
+            let foreign_address = Address::new(Ipv4Addr::new(10, 9, 0, 1), 24);
+            netlink.add_address(lo, &foreign_address).unwrap();
+            let mut foreign_route = Route::default_via(Ipv4Addr::new(10, 9, 0, 254));
+            foreign_route.metric = Some(10);
+            netlink.replace_route(lo, &foreign_route).unwrap();
+
+            let mut lease = Lease::new(Ipv4Addr::new(10, 1, 2, 3));
+            lease.subnet_mask = Some(Ipv4Addr::new(255, 255, 255, 0));
+            lease.broadcast_address = Some(Ipv4Addr::new(10, 1, 2, 255));
+            lease.routers = Some(vec![Ipv4Addr::new(10, 1, 2, 1), Ipv4Addr::new(10, 1, 2, 2)]);
+
+            configurator.apply_settings(&lease).unwrap();
+            let addresses = netlink.addresses(lo).unwrap();
+            let address = addresses
+                .iter()
+                .find(|address| address.address == lease.offered_ip)
+                .unwrap();
+            assert_eq!(address.prefix_len, 24);
+            assert_eq!(address.broadcast, lease.broadcast_address);
+            assert_eq!(address.label.as_deref(), Some("lo:dhcp"));
+            assert!(addresses
+                .iter()
+                .any(|address| address.address == foreign_address.address));
+
+            let herald_routes = || -> Vec<_> {
+                netlink
+                    .routes(MAIN_TABLE)
+                    .unwrap()
+                    .into_iter()
+                    .filter(|(_, route)| route.protocol == RTPROT_DHCP)
+                    .map(|(_, route)| (route.gateway.unwrap(), route.metric.unwrap()))
+                    .collect()
+            };
+            let has_foreign_route = || {
+                netlink
+                    .routes(MAIN_TABLE)
+                    .unwrap()
+                    .contains(&(lo, foreign_route))
+            };
+            let routers = lease.routers.clone().unwrap();
+            assert_eq!(
+                herald_routes(),
+                default_route_metrics(&routers, DEFAULT_ROUTE_METRIC)
+            );
+            assert!(has_foreign_route());
+
+            // A renewal with one router dropped only removes that route
+            let mut renewed = lease.clone();
+            renewed.routers = Some(vec![routers[0]]);
+            configurator.apply_settings(&renewed).unwrap();
+            assert_eq!(
+                herald_routes(),
+                default_route_metrics(&routers[..1], DEFAULT_ROUTE_METRIC)
+            );
+            assert!(has_foreign_route());
+
+            // A new address replaces herald's old one but no other
+            let mut moved = renewed.clone();
+            moved.offered_ip = Ipv4Addr::new(10, 1, 2, 4);
+            configurator.apply_settings(&moved).unwrap();
+            let addresses: Vec<_> = netlink
+                .addresses(lo)
+                .unwrap()
+                .into_iter()
+                .map(|address| address.address)
+                .collect();
+            assert!(addresses.contains(&moved.offered_ip));
+            assert!(!addresses.contains(&lease.offered_ip));
+            assert!(addresses.contains(&foreign_address.address));
+
+            configurator.remove(&moved).unwrap();
+            assert!(herald_routes().is_empty());
+            assert!(has_foreign_route());
+            let addresses = netlink.addresses(lo).unwrap();
+            assert!(!addresses
+                .iter()
+                .any(|address| address.address == moved.offered_ip));
+            assert!(addresses
+                .iter()
+                .any(|address| address.address == foreign_address.address));
+        });
+    }
 }
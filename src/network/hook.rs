@@ -0,0 +1,172 @@
+//! Hook scripts
+//!
+//! A user script is run after each change to the lease configuration, with
+//! the reason and the lease in its environment, so that other programs can
+//! act on settings herald does not apply itself, such as the captive portal.
+
+use super::{applier::LeaseApplier, configurator::ApplyReport};
+use crate::{client::Lease, error::HeraldError};
+use std::{net::Ipv4Addr, path::PathBuf, process::Command};
+use tracing::{info, warn};
+
+/// Applier that runs a hook script after the applier it wraps.
+pub struct HookApplier {
+    inner: Box<dyn LeaseApplier>,
+    script: PathBuf,
+    interface: String,
+}
+
+impl HookApplier {
+    pub fn new(inner: Box<dyn LeaseApplier>, script: PathBuf, interface: &str) -> Self {
+        Self {
+            inner,
+            script,
+            interface: interface.to_string(),
+        }
+    }
+
+    /// Run the script for `reason`. A failing script is logged but does not
+    /// undo the configuration.
+    fn run(&self, reason: &str, lease: &Lease) {
+        info!("Running hook {} ({})", self.script.display(), reason);
+        let status = Command::new(&self.script)
+            .env("HERALD_REASON", reason)
+            .env("HERALD_INTERFACE", &self.interface)
+            .envs(lease_environment(lease))
+            .status();
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("Hook {} failed: {}", self.script.display(), status),
+            Err(e) => warn!("Failed to run hook {}: {}", self.script.display(), e),
+        }
+    }
+}
+
+impl LeaseApplier for HookApplier {
+    fn apply(&mut self, lease: &Lease) -> Result<ApplyReport, HeraldError> {
+        let report = self.inner.apply(lease)?;
+        self.run("BOUND", lease);
+        Ok(report)
+    }
+
+    fn update(&mut self, old: &Lease, new: &Lease) -> Result<ApplyReport, HeraldError> {
+        let report = self.inner.update(old, new)?;
+        self.run("RENEW", new);
+        Ok(report)
+    }
+
+    fn remove(&mut self, lease: &Lease) -> Result<(), HeraldError> {
+        let result = self.inner.remove(lease);
+        self.run("RELEASE", lease);
+        result
+    }
+
+    fn apply_link_local(&mut self, addr: Ipv4Addr) -> Result<(), HeraldError> {
+        self.inner.apply_link_local(addr)
+    }
+
+    fn remove_link_local(&mut self, addr: Ipv4Addr) -> Result<(), HeraldError> {
+        self.inner.remove_link_local(addr)
+    }
+}
+
+/// The lease as `HERALD_*` variables; lists are separated by spaces.
+pub fn lease_environment(lease: &Lease) -> Vec<(&'static str, String)> {
+    fn list(addresses: &[Ipv4Addr]) -> String {
+        addresses
+            .iter()
+            .map(Ipv4Addr::to_string)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    let mut env = vec![("HERALD_IP_ADDRESS", lease.offered_ip.to_string())];
+    let optional = [
+        (
+            "HERALD_SUBNET_MASK",
+            lease.subnet_mask.map(|m| m.to_string()),
+        ),
+        ("HERALD_ROUTERS", lease.routers.as_deref().map(list)),
+        ("HERALD_DNS_SERVERS", lease.dns_servers.as_deref().map(list)),
+        ("HERALD_NTP_SERVERS", lease.ntp_servers.as_deref().map(list)),
+        (
+            "HERALD_LEASE_TIME",
+            lease.lease_duration.map(|d| d.as_secs().to_string()),
+        ),
+        (
+            "HERALD_SERVER_IDENTIFIER",
+            lease.server_identifier.map(|s| s.to_string()),
+        ),
+        (
+            "HERALD_CAPTIVE_PORTAL",
+            lease.captive_portal.as_ref().map(|uri| uri.to_string()),
+        ),
+    ];
+    env.extend(
+        optional
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value?))),
+    );
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::applier::{AppliedChange, RecordingApplier};
+    use std::os::unix::fs::PermissionsExt;
+
+    fn portal_lease() -> Lease {
+        let mut lease = Lease::new(Ipv4Addr::new(192, 168, 1, 100));
+        lease.routers = Some(vec![
+            Ipv4Addr::new(192, 168, 1, 1),
+            Ipv4Addr::new(192, 168, 1, 2),
+        ]);
+        lease.captive_portal = Some("https://portal.example.com/api".parse().unwrap());
+        lease
+    }
+
+    #[test]
+    fn test_lease_environment() {
+        let env = lease_environment(&portal_lease());
+        assert_eq!(
+            env,
+            [
+                ("HERALD_IP_ADDRESS", "192.168.1.100".to_string()),
+                ("HERALD_ROUTERS", "192.168.1.1 192.168.1.2".to_string()),
+                (
+                    "HERALD_CAPTIVE_PORTAL",
+                    "https://portal.example.com/api".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hook_sees_captive_portal() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("env");
+        let script = dir.path().join("hook");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$HERALD_REASON $HERALD_INTERFACE $HERALD_CAPTIVE_PORTAL\" >> {}\n",
+                output.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let recorder = RecordingApplier::new();
+        let mut applier = HookApplier::new(Box::new(recorder.clone()), script, "eth0");
+        let lease = portal_lease();
+        applier.apply(&lease).unwrap();
+        applier.remove(&lease).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "BOUND eth0 https://portal.example.com/api\nRELEASE eth0 https://portal.example.com/api\n"
+        );
+        assert!(matches!(recorder.changes()[0], AppliedChange::Apply(_)));
+    }
+}
@@ -0,0 +1,182 @@
+//! Network interface information
+//!
+//! Looks up the index, hardware type and address, MTU and flags of an
+//! interface with RTM_GETLINK, falling back to the `SIOCGIF*` ioctls where
+//! netlink is unavailable. Neither needs sysfs.
+
+use super::netlink::NetlinkHandle;
+use crate::error::HeraldError;
+use bytes::Bytes;
+use dhcproto::v4;
+use std::{
+    ffi::CString,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+/// Bytes of the hardware address returned by SIOCGIFHWADDR.
+const IOCTL_HWADDR_LEN: usize = 14;
+
+/// A network interface as reported by the kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    pub index: u32,
+    /// ARP hardware type (`ARPHRD_*`)
+    pub hardware_type: u16,
+    pub hardware_address: Bytes,
+    pub mtu: u32,
+    /// Interface flags (`IFF_*`)
+    pub flags: u32,
+}
+
+impl Interface {
+    /// Looks up the interface called `name`.
+    pub fn query(name: &str) -> Result<Self, HeraldError> {
+        let netlink = NetlinkHandle::open().and_then(|netlink| netlink.link(name));
+        match netlink {
+            Ok(interface) => Ok(interface),
+            Err(e) => {
+                tracing::debug!("RTM_GETLINK failed for {}, using ioctls: {}", name, e);
+                Self::query_ioctl(name)
+            }
+        }
+    }
+
+    /// Looks up the interface with SIOCGIFINDEX, SIOCGIFHWADDR, SIOCGIFMTU
+    /// and SIOCGIFFLAGS. Hardware addresses longer than 14 bytes are cut
+    /// short, which is why netlink is tried first.
+    fn query_ioctl(name: &str) -> Result<Self, HeraldError> {
+        let invalid = || HeraldError::InterfaceInvalid(name.to_string());
+        let c_name = CString::new(name).map_err(|_| invalid())?;
+        if c_name.as_bytes_with_nul().len() > libc::IFNAMSIZ {
+            return Err(invalid());
+        }
+
+        let raw = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if raw < 0 {
+            return Err(HeraldError::Io(io::Error::last_os_error()));
+        }
+        // The descriptor was just created and is owned by nothing else
+        let socket = unsafe { OwnedFd::from_raw_fd(raw) };
+
+        let ioctl = |request: libc::c_ulong| -> Result<libc::ifreq, HeraldError> {
+            let mut ifreq: libc::ifreq = unsafe { std::mem::zeroed() };
+            for (dst, &src) in ifreq.ifr_name.iter_mut().zip(c_name.as_bytes()) {
+                *dst = src as libc::c_char;
+            }
+            if unsafe { libc::ioctl(socket.as_raw_fd(), request as _, &mut ifreq) } < 0 {
+                let err = io::Error::last_os_error();
+                return Err(match err.raw_os_error() {
+                    Some(libc::ENODEV) => invalid(),
+                    _ => HeraldError::Io(err),
+                });
+            }
+            Ok(ifreq)
+        };
+
+        let index = unsafe { ioctl(libc::SIOCGIFINDEX)?.ifr_ifru.ifru_ifindex } as u32;
+        let hwaddr = unsafe { ioctl(libc::SIOCGIFHWADDR)?.ifr_ifru.ifru_hwaddr };
+        let mtu = unsafe { ioctl(libc::SIOCGIFMTU)?.ifr_ifru.ifru_mtu } as u32;
+        let flags = unsafe { ioctl(libc::SIOCGIFFLAGS)?.ifr_ifru.ifru_flags } as u16 as u32;
+
+        let hardware_type = hwaddr.sa_family;
+        let len = match hardware_type {
+            libc::ARPHRD_ETHER | libc::ARPHRD_IEEE802 | libc::ARPHRD_LOOPBACK => 6,
+            _ => IOCTL_HWADDR_LEN,
+        };
+        let hardware_address: Vec<u8> = hwaddr.sa_data[..len].iter().map(|&b| b as u8).collect();
+
+        Ok(Self {
+            name: name.to_string(),
+            index,
+            hardware_type,
+            hardware_address: Bytes::from(hardware_address),
+            mtu,
+            flags,
+        })
+    }
+
+    /// Whether the interface is administratively up
+    pub fn is_up(&self) -> bool {
+        self.flags & libc::IFF_UP as u32 != 0
+    }
+
+    /// Whether this is a loopback interface
+    pub fn is_loopback(&self) -> bool {
+        self.flags & libc::IFF_LOOPBACK as u32 != 0
+    }
+
+    /// DHCP hardware type (htype). ARP hardware types below 256 share the
+    /// IANA numbering; others, such as loopback, have no DHCP equivalent.
+    pub fn dhcp_hardware_type(&self) -> Option<v4::HType> {
+        u8::try_from(self.hardware_type)
+            .ok()
+            .filter(|&htype| htype != 0)
+            .map(v4::HType::from)
+    }
+}
+
+/// Formats a hardware address as colon separated hex.
+pub fn format_hardware_address(address: &[u8]) -> String {
+    address
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_loopback_in_namespace() {
+        crate::network::netlink::in_network_namespace(|| {
+            let lo = Interface::query("lo").unwrap();
+            assert_eq!(lo.index, 1);
+            assert_eq!(lo.hardware_type, libc::ARPHRD_LOOPBACK);
+            assert_eq!(lo.hardware_address, Bytes::from_static(&[0; 6]));
+            assert!(lo.is_loopback());
+            assert!(!lo.is_up());
+            assert_eq!(lo.dhcp_hardware_type(), None);
+
+            // Both lookups agree
+            let mut ioctl = Interface::query_ioctl("lo").unwrap();
+            ioctl.flags = lo.flags;
+            assert_eq!(ioctl, lo);
+        });
+    }
+
+    #[test]
+    fn test_missing_interface() {
+        assert!(matches!(
+            Interface::query("herald-missing0"),
+            Err(HeraldError::InterfaceInvalid(_))
+        ));
+        assert!(matches!(
+            Interface::query_ioctl("herald-missing0"),
+            Err(HeraldError::InterfaceInvalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_dhcp_hardware_type() {
+        let mut interface = Interface {
+            name: "eth0".to_string(),
+            index: 2,
+            hardware_type: libc::ARPHRD_ETHER,
+            hardware_address: Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]),
+            mtu: 1500,
+            flags: 0,
+        };
+        assert_eq!(interface.dhcp_hardware_type(), Some(v4::HType::Eth));
+        assert_eq!(
+            format_hardware_address(&interface.hardware_address),
+            "00:0c:29:a8:92:f4"
+        );
+
+        interface.hardware_type = libc::ARPHRD_INFINIBAND;
+        assert_eq!(interface.dhcp_hardware_type(), Some(v4::HType::from(32)));
+    }
+}
@@ -0,0 +1,543 @@
+//! IPv4 link-local address autoconfiguration (RFC 3927)
+//!
+//! When no DHCP server answers, a 169.254/16 address is picked from a
+//! generator seeded with the MAC address, probed for conflicts with ARP and
+//! announced before it is used. A background task does this while DHCP
+//! discovery goes on, and defends the address until it is dropped.
+
+use crate::{
+    error::HeraldError,
+    network::{
+        netns::{self, NetNs},
+        packet, SocketError,
+    },
+};
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+use std::{
+    io,
+    net::Ipv4Addr,
+    os::fd::{AsRawFd, OwnedFd},
+    time::Duration,
+};
+use tokio::{
+    io::{unix::AsyncFd, Interest},
+    sync::mpsc,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+/// Initial random delay before the first probe.
+pub const PROBE_WAIT: Duration = Duration::from_secs(1);
+/// Number of probes sent for a candidate address.
+pub const PROBE_NUM: u32 = 3;
+/// Minimum delay between probes.
+pub const PROBE_MIN: Duration = Duration::from_secs(1);
+/// Maximum delay between probes.
+pub const PROBE_MAX: Duration = Duration::from_secs(2);
+/// Delay after the last probe before the address is claimed.
+pub const ANNOUNCE_WAIT: Duration = Duration::from_secs(2);
+/// Number of announcements sent for a claimed address.
+pub const ANNOUNCE_NUM: u32 = 2;
+/// Delay between announcements.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+/// Conflicts after which probing is rate limited.
+pub const MAX_CONFLICTS: u32 = 10;
+/// Delay between candidates once `MAX_CONFLICTS` has been reached.
+pub const RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(60);
+/// A conflict this soon after defending the address makes the host give it up.
+pub const DEFEND_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Prefix length of the link-local network.
+pub const LINK_LOCAL_PREFIX_LEN: u8 = 16;
+/// Broadcast address of the link-local network.
+pub const LINK_LOCAL_BROADCAST: Ipv4Addr = Ipv4Addr::new(169, 254, 255, 255);
+
+/// ARP operation code for requests.
+const ARP_REQUEST: u16 = 1;
+/// Length of an Ethernet/IPv4 ARP packet.
+const ARP_PACKET_LEN: usize = 28;
+
+/// Generates candidate link-local addresses.
+///
+/// The generator is seeded from the MAC address so that a host picks the
+/// same sequence of addresses every time, as RFC 3927 section 2.1 suggests.
+#[derive(Debug)]
+pub struct AddressGenerator {
+    rng: StdRng,
+}
+
+impl AddressGenerator {
+    pub fn new(mac: &[u8]) -> Self {
+        let seed = mac
+            .iter()
+            .fold(0u64, |seed, &byte| (seed << 8) | u64::from(byte));
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Next candidate in 169.254.1.0 - 169.254.254.255.
+    pub fn next_address(&mut self) -> Ipv4Addr {
+        let host: u16 = self.rng.random_range(0x0100..=0xfeff);
+        let [high, low] = host.to_be_bytes();
+        Ipv4Addr::new(169, 254, high, low)
+    }
+
+    /// Random delay in `min..max`, used to desynchronize hosts.
+    fn jitter(&mut self, min: Duration, max: Duration) -> Duration {
+        if max <= min {
+            return min;
+        }
+        self.rng.random_range(min..max)
+    }
+}
+
+/// An Ethernet/IPv4 ARP packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub operation: u16,
+    pub sender_mac: [u8; 6],
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: [u8; 6],
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// ARP probe for `addr`: a request with an unspecified sender address.
+    pub fn probe(mac: [u8; 6], addr: Ipv4Addr) -> Self {
+        Self {
+            operation: ARP_REQUEST,
+            sender_mac: mac,
+            sender_ip: Ipv4Addr::UNSPECIFIED,
+            target_mac: [0; 6],
+            target_ip: addr,
+        }
+    }
+
+    /// ARP announcement for `addr`: a request with sender and target set to `addr`.
+    pub fn announcement(mac: [u8; 6], addr: Ipv4Addr) -> Self {
+        Self {
+            operation: ARP_REQUEST,
+            sender_mac: mac,
+            sender_ip: addr,
+            target_mac: [0; 6],
+            target_ip: addr,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; ARP_PACKET_LEN] {
+        let mut packet = [0u8; ARP_PACKET_LEN];
+        packet[0..2].copy_from_slice(&1u16.to_be_bytes()); // htype Ethernet
+        packet[2..4].copy_from_slice(&(libc::ETH_P_IP as u16).to_be_bytes());
+        packet[4] = 6;
+        packet[5] = 4;
+        packet[6..8].copy_from_slice(&self.operation.to_be_bytes());
+        packet[8..14].copy_from_slice(&self.sender_mac);
+        packet[14..18].copy_from_slice(&self.sender_ip.octets());
+        packet[18..24].copy_from_slice(&self.target_mac);
+        packet[24..28].copy_from_slice(&self.target_ip.octets());
+        packet
+    }
+
+    /// Parses an Ethernet/IPv4 ARP packet, ignoring any trailing padding.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let data = data.get(..ARP_PACKET_LEN)?;
+        if data[0..2] != 1u16.to_be_bytes()
+            || data[2..4] != (libc::ETH_P_IP as u16).to_be_bytes()
+            || data[4] != 6
+            || data[5] != 4
+        {
+            return None;
+        }
+
+        let ip = |range: std::ops::Range<usize>| {
+            let octets: [u8; 4] = data[range].try_into().ok()?;
+            Some(Ipv4Addr::from(octets))
+        };
+        Some(Self {
+            operation: u16::from_be_bytes([data[6], data[7]]),
+            sender_mac: data[8..14].try_into().ok()?,
+            sender_ip: ip(14..18)?,
+            target_mac: data[18..24].try_into().ok()?,
+            target_ip: ip(24..28)?,
+        })
+    }
+
+    /// Whether another host sent this packet from `addr` (RFC 3927
+    /// section 2.5).
+    pub fn uses(&self, own_mac: [u8; 6], addr: Ipv4Addr) -> bool {
+        self.sender_mac != own_mac && self.sender_ip == addr
+    }
+
+    /// Whether this packet from another host shows that `addr` is in use or
+    /// being probed (RFC 3927 section 2.2.1).
+    pub fn conflicts_with(&self, own_mac: [u8; 6], addr: Ipv4Addr) -> bool {
+        self.uses(own_mac, addr)
+            || (self.sender_mac != own_mac
+                && self.operation == ARP_REQUEST
+                && self.sender_ip.is_unspecified()
+                && self.target_ip == addr)
+    }
+}
+
+/// A change to the link-local address of a `LinkLocalClaim`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkLocalEvent {
+    /// The address is free and announced, and may be configured
+    Claimed(Ipv4Addr),
+    /// Another host took the address, which must be removed; a new one is
+    /// being claimed
+    Lost(Ipv4Addr),
+}
+
+/// A link-local address claimed and then defended by a background task,
+/// which stops when this is dropped.
+pub struct LinkLocalClaim {
+    task: JoinHandle<()>,
+    events: mpsc::UnboundedReceiver<LinkLocalEvent>,
+}
+
+impl LinkLocalClaim {
+    /// Starts claiming an address on `interface`, which lives in `netns` if
+    /// given.
+    pub fn start(interface: &str, mac: &[u8], netns: Option<&NetNs>) -> Result<Self, HeraldError> {
+        let mac: [u8; 6] = mac
+            .try_into()
+            .map_err(|_| HeraldError::InterfaceInvalid(interface.to_string()))?;
+        let socket = netns::run_in(netns, || ArpSocket::open(interface))?;
+        let (sender, events) = mpsc::unbounded_channel();
+        let interface = interface.to_string();
+        let task = tokio::spawn(async move {
+            if let Err(e) = maintain(&socket, mac, &sender).await {
+                error!("Link-local address on {} abandoned: {}", interface, e);
+            }
+        });
+        Ok(Self { task, events })
+    }
+
+    /// The next change to the address, or `None` once the task has failed.
+    pub async fn next_event(&mut self) -> Option<LinkLocalEvent> {
+        self.events.recv().await
+    }
+}
+
+impl Drop for LinkLocalClaim {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Claims an address and defends it, claiming another whenever it is lost.
+/// Returns once nobody listens to `events` any more.
+async fn maintain(
+    socket: &ArpSocket,
+    mac: [u8; 6],
+    events: &mpsc::UnboundedSender<LinkLocalEvent>,
+) -> Result<(), HeraldError> {
+    let mut generator = AddressGenerator::new(&mac);
+    let mut conflicts = 0;
+
+    loop {
+        let addr = claim(socket, &mut generator, mac, &mut conflicts).await?;
+        if events.send(LinkLocalEvent::Claimed(addr)).is_err() {
+            return Ok(());
+        }
+
+        socket.defend(mac, addr).await?;
+        warn!("Link-local address {} lost to another host", addr);
+        conflicts += 1;
+        if events.send(LinkLocalEvent::Lost(addr)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Picks, probes and announces a link-local address.
+///
+/// Keeps trying new candidates until one is free, rate limiting after
+/// `MAX_CONFLICTS` conflicts.
+async fn claim(
+    socket: &ArpSocket,
+    generator: &mut AddressGenerator,
+    mac: [u8; 6],
+    conflicts: &mut u32,
+) -> Result<Ipv4Addr, HeraldError> {
+    loop {
+        let candidate = generator.next_address();
+        if *conflicts >= MAX_CONFLICTS {
+            time::sleep(RATE_LIMIT_INTERVAL).await;
+        }
+
+        info!("Probing link-local address {}", candidate);
+        if probe(socket, generator, mac, candidate).await? {
+            announce(socket, mac, candidate).await?;
+            return Ok(candidate);
+        }
+
+        *conflicts += 1;
+        warn!("Link-local address {} is in use, trying another", candidate);
+    }
+}
+
+/// Probes `candidate`, returning whether it is free.
+async fn probe(
+    socket: &ArpSocket,
+    generator: &mut AddressGenerator,
+    mac: [u8; 6],
+    candidate: Ipv4Addr,
+) -> Result<bool, HeraldError> {
+    let wait = generator.jitter(Duration::ZERO, PROBE_WAIT);
+    if socket.conflict_within(mac, candidate, wait).await? {
+        return Ok(false);
+    }
+
+    for n in 1..=PROBE_NUM {
+        socket.send(&ArpPacket::probe(mac, candidate)).await?;
+        let wait = if n == PROBE_NUM {
+            ANNOUNCE_WAIT
+        } else {
+            generator.jitter(PROBE_MIN, PROBE_MAX)
+        };
+        if socket.conflict_within(mac, candidate, wait).await? {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Announces a claimed address so neighbours update stale ARP entries.
+async fn announce(socket: &ArpSocket, mac: [u8; 6], addr: Ipv4Addr) -> Result<(), HeraldError> {
+    for n in 1..=ANNOUNCE_NUM {
+        socket.send(&ArpPacket::announcement(mac, addr)).await?;
+        if n < ANNOUNCE_NUM {
+            time::sleep(ANNOUNCE_INTERVAL).await;
+        }
+    }
+    Ok(())
+}
+
+/// `AF_PACKET` datagram socket for ARP on a single interface.
+struct ArpSocket {
+    fd: AsyncFd<OwnedFd>,
+    ifindex: i32,
+}
+
+impl ArpSocket {
+    fn open(interface: &str) -> Result<Self, HeraldError> {
+        let (fd, ifindex) = packet::open(interface, libc::SOCK_DGRAM, libc::ETH_P_ARP as u16)?;
+        Ok(Self {
+            fd: AsyncFd::new(fd).map_err(SocketError::ConvertToTokio)?,
+            ifindex,
+        })
+    }
+
+    /// Broadcasts `packet` on the interface.
+    async fn send(&self, packet: &ArpPacket) -> io::Result<()> {
+        let bytes = packet.to_bytes();
+        let addr = packet::link_layer_address(self.ifindex, libc::ETH_P_ARP as u16, [0xff; 6]);
+        self.fd
+            .async_io(Interest::WRITABLE, |fd| {
+                let ret = unsafe {
+                    libc::sendto(
+                        fd.as_raw_fd(),
+                        bytes.as_ptr() as *const libc::c_void,
+                        bytes.len(),
+                        0,
+                        &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                        std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+                    )
+                };
+                if ret < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            })
+            .await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fd
+            .async_io(Interest::READABLE, |fd| {
+                let ret = unsafe {
+                    libc::recv(
+                        fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if ret < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(ret as usize)
+                }
+            })
+            .await
+    }
+
+    /// Defends `addr` with an announcement when another host uses it, until
+    /// a second conflict arrives within `DEFEND_INTERVAL` of the defense and
+    /// the address has to be given up (RFC 3927 section 2.5).
+    async fn defend(&self, mac: [u8; 6], addr: Ipv4Addr) -> io::Result<()> {
+        let mut defended_at: Option<Instant> = None;
+        let mut buf = [0u8; 64];
+        loop {
+            let len = self.recv(&mut buf).await?;
+            let Some(packet) = ArpPacket::parse(&buf[..len]) else {
+                continue;
+            };
+            if !packet.uses(mac, addr) {
+                continue;
+            }
+
+            debug!("ARP conflict for {}: {:?}", addr, packet);
+            match defended_at {
+                Some(at) if at.elapsed() < DEFEND_INTERVAL => return Ok(()),
+                _ => {
+                    info!("Defending link-local address {}", addr);
+                    self.send(&ArpPacket::announcement(mac, addr)).await?;
+                    defended_at = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    /// Listens for `duration`, returning whether another host claimed or
+    /// probed `addr` in the meantime.
+    async fn conflict_within(
+        &self,
+        mac: [u8; 6],
+        addr: Ipv4Addr,
+        duration: Duration,
+    ) -> io::Result<bool> {
+        let deadline = Instant::now() + duration;
+        let mut buf = [0u8; 64];
+        loop {
+            match time::timeout_at(deadline, self.recv(&mut buf)).await {
+                Ok(Ok(len)) => {
+                    if let Some(packet) = ArpPacket::parse(&buf[..len]) {
+                        if packet.conflicts_with(mac, addr) {
+                            debug!("ARP conflict for {}: {:?}", addr, packet);
+                            return Ok(true);
+                        }
+                    }
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(false),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::netlink;
+    use std::process::Command;
+
+    const MAC: [u8; 6] = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55];
+    const OTHER_MAC: [u8; 6] = [0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb];
+
+    #[test]
+    fn test_generator_is_seeded_from_mac() {
+        let mut first = AddressGenerator::new(&MAC);
+        let mut second = AddressGenerator::new(&MAC);
+        for _ in 0..100 {
+            let addr = first.next_address();
+            assert_eq!(addr, second.next_address());
+
+            let [a, b, c, _] = addr.octets();
+            assert_eq!((a, b), (169, 254));
+            assert!((1..=254).contains(&c));
+        }
+    }
+
+    #[test]
+    fn test_arp_packet_roundtrip() {
+        let addr = Ipv4Addr::new(169, 254, 10, 20);
+        let probe = ArpPacket::probe(MAC, addr);
+        let mut bytes = probe.to_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 18]); // Ethernet padding
+        assert_eq!(ArpPacket::parse(&bytes), Some(probe));
+        assert_eq!(ArpPacket::parse(&bytes[..20]), None);
+    }
+
+    #[test]
+    fn test_conflict_detection() {
+        let addr = Ipv4Addr::new(169, 254, 10, 20);
+
+        // Our own probes and announcements are not conflicts
+        assert!(!ArpPacket::probe(MAC, addr).conflicts_with(MAC, addr));
+        assert!(!ArpPacket::announcement(MAC, addr).conflicts_with(MAC, addr));
+
+        // Another host using or probing the address is
+        assert!(ArpPacket::announcement(OTHER_MAC, addr).conflicts_with(MAC, addr));
+        assert!(ArpPacket::probe(OTHER_MAC, addr).conflicts_with(MAC, addr));
+
+        // Unrelated traffic is not
+        let other = Ipv4Addr::new(169, 254, 10, 21);
+        assert!(!ArpPacket::announcement(OTHER_MAC, other).conflicts_with(MAC, addr));
+
+        // Once claimed, only hosts sending from the address need defending
+        assert!(ArpPacket::announcement(OTHER_MAC, addr).uses(MAC, addr));
+        assert!(!ArpPacket::probe(OTHER_MAC, addr).uses(MAC, addr));
+        assert!(!ArpPacket::announcement(MAC, addr).uses(MAC, addr));
+    }
+
+    fn ip(args: &[&str]) -> bool {
+        Command::new("ip")
+            .args(args)
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    #[test]
+    fn test_defend_in_namespace() {
+        netlink::in_network_namespace(|| {
+            if !ip(&["link", "add", "ll0", "type", "veth", "peer", "name", "ll1"]) {
+                eprintln!("skipping: cannot create veth interfaces");
+                return;
+            }
+            assert!(ip(&["link", "set", "ll0", "up"]) && ip(&["link", "set", "ll1", "up"]));
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let ours = ArpSocket::open("ll0").unwrap();
+                let theirs = ArpSocket::open("ll1").unwrap();
+                let addr = Ipv4Addr::new(169, 254, 10, 20);
+                let defend = ours.defend(MAC, addr);
+                tokio::pin!(defend);
+
+                // The first conflict is answered with an announcement
+                let conflict = ArpPacket::announcement(OTHER_MAC, addr);
+                theirs.send(&conflict).await.unwrap();
+                let defended = async {
+                    let mut buf = [0u8; 64];
+                    loop {
+                        let len = theirs.recv(&mut buf).await.unwrap();
+                        if ArpPacket::parse(&buf[..len]) == Some(ArpPacket::announcement(MAC, addr))
+                        {
+                            break;
+                        }
+                    }
+                };
+                tokio::select! {
+                    _ = &mut defend => panic!("gave up the address after one conflict"),
+                    result = time::timeout(Duration::from_secs(2), defended) => result.unwrap(),
+                }
+
+                // A second one right after makes us give the address up
+                theirs.send(&conflict).await.unwrap();
+                time::timeout(Duration::from_secs(2), defend)
+                    .await
+                    .unwrap()
+                    .unwrap();
+            });
+        });
+    }
+}
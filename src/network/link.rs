@@ -0,0 +1,228 @@
+//! Carrier and interface monitoring
+//!
+//! Listens for rtnetlink link notifications (`RTMGRP_LINK`) so the client
+//! notices when the cable is unplugged or the Wi-Fi association drops, and
+//! when the link comes back, and so hotplug sees interfaces come and go.
+
+use super::{
+    interface::Interface,
+    netlink::{self, NetlinkHandle, RECV_BUFFER_LEN},
+};
+use crate::error::HeraldError;
+use std::{
+    io,
+    os::fd::{AsRawFd, OwnedFd},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tokio::io::{unix::AsyncFd, Interest};
+
+/// A change of the interface's carrier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkEvent {
+    CarrierUp,
+    CarrierDown,
+}
+
+/// Follows the carrier of one interface.
+pub struct LinkMonitor {
+    fd: AsyncFd<OwnedFd>,
+    netlink: NetlinkHandle,
+    index: u32,
+    carrier: AtomicBool,
+}
+
+impl LinkMonitor {
+    /// Subscribes to link notifications for `interface`.
+    pub fn open(interface: &str) -> Result<Self, HeraldError> {
+        // Subscribe before reading the current state so no change is missed
+        let fd = netlink::open_socket(libc::SOCK_NONBLOCK, libc::RTMGRP_LINK as u32)?;
+        let netlink = NetlinkHandle::open()?;
+        let index = netlink.link_index(interface)?;
+        let carrier = netlink.has_carrier(index)?;
+
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            netlink,
+            index,
+            carrier: AtomicBool::new(carrier),
+        })
+    }
+
+    /// Whether the interface had a carrier at the last notification
+    pub fn has_carrier(&self) -> bool {
+        self.carrier.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the carrier to change.
+    pub async fn next_event(&self) -> Result<LinkEvent, HeraldError> {
+        let mut buf = vec![0u8; RECV_BUFFER_LEN];
+        loop {
+            let carrier = match recv(&self.fd, &mut buf).await {
+                Ok(len) => match self.carrier_in(&buf[..len]) {
+                    Some(carrier) => carrier,
+                    None => continue,
+                },
+                // Notifications were dropped, so ask for the current state
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    tracing::warn!("Link notifications overflowed, querying the carrier");
+                    self.netlink.has_carrier(self.index)?
+                }
+                Err(e) => return Err(HeraldError::Io(e)),
+            };
+
+            if carrier != self.carrier.swap(carrier, Ordering::Relaxed) {
+                return Ok(if carrier {
+                    LinkEvent::CarrierUp
+                } else {
+                    LinkEvent::CarrierDown
+                });
+            }
+        }
+    }
+
+    /// Waits until the interface has a carrier.
+    pub async fn wait_for_carrier(&self) -> Result<(), HeraldError> {
+        while !self.has_carrier() {
+            self.next_event().await?;
+        }
+        Ok(())
+    }
+
+    /// Latest carrier of our interface in a batch of notifications
+    fn carrier_in(&self, buf: &[u8]) -> Option<bool> {
+        netlink::messages(buf)
+            .filter_map(|(header, payload)| match header.kind {
+                libc::RTM_NEWLINK => netlink::link_state(payload),
+                libc::RTM_DELLINK => netlink::link_state(payload).map(|(index, _)| (index, false)),
+                _ => None,
+            })
+            .filter(|&(index, _)| index == self.index)
+            .last()
+            .map(|(_, carrier)| carrier)
+    }
+}
+
+/// An interface appearing, changing or disappearing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterfaceEvent {
+    /// RTM_NEWLINK: the interface was added or its state changed
+    New(Interface),
+    /// RTM_DELLINK: the interface is gone
+    Removed(Interface),
+    /// Notifications were dropped; the interfaces must be read again
+    Overflow,
+}
+
+/// Follows all interfaces.
+pub struct InterfaceWatcher {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl InterfaceWatcher {
+    /// Subscribes to link notifications.
+    pub fn open() -> Result<Self, HeraldError> {
+        let fd = netlink::open_socket(libc::SOCK_NONBLOCK, libc::RTMGRP_LINK as u32)?;
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    /// Waits for the next batch of notifications.
+    pub async fn next_events(&self) -> Result<Vec<InterfaceEvent>, HeraldError> {
+        let mut buf = vec![0u8; RECV_BUFFER_LEN];
+        let len = match recv(&self.fd, &mut buf).await {
+            Ok(len) => len,
+            Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                tracing::warn!("Link notifications overflowed");
+                return Ok(vec![InterfaceEvent::Overflow]);
+            }
+            Err(e) => return Err(HeraldError::Io(e)),
+        };
+
+        Ok(netlink::messages(&buf[..len])
+            .filter_map(|(header, payload)| {
+                let interface = netlink::parse_link(payload)?;
+                match header.kind {
+                    libc::RTM_NEWLINK => Some(InterfaceEvent::New(interface)),
+                    libc::RTM_DELLINK => Some(InterfaceEvent::Removed(interface)),
+                    _ => None,
+                }
+            })
+            .collect())
+    }
+}
+
+/// Reads one datagram from a netlink socket
+async fn recv(fd: &AsyncFd<OwnedFd>, buf: &mut [u8]) -> io::Result<usize> {
+    fd.async_io(Interest::READABLE, |fd| {
+        let ret = unsafe {
+            libc::recv(
+                fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_carrier_changes_in_namespace() {
+        netlink::in_network_namespace(|| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let netlink = NetlinkHandle::open().unwrap();
+                let lo = netlink.link_index("lo").unwrap();
+                let monitor = LinkMonitor::open("lo").unwrap();
+                assert!(!monitor.has_carrier());
+
+                netlink.set_link_up(lo).unwrap();
+                let event = tokio::time::timeout(Duration::from_secs(1), monitor.next_event());
+                assert_eq!(event.await.unwrap().unwrap(), LinkEvent::CarrierUp);
+                assert!(monitor.has_carrier());
+
+                netlink.set_link_down(lo).unwrap();
+                let event = tokio::time::timeout(Duration::from_secs(1), monitor.next_event());
+                assert_eq!(event.await.unwrap().unwrap(), LinkEvent::CarrierDown);
+            });
+        });
+    }
+
+    #[test]
+    fn test_interface_events_in_namespace() {
+        netlink::in_network_namespace(|| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let netlink = NetlinkHandle::open().unwrap();
+                let watcher = InterfaceWatcher::open().unwrap();
+                let lo = netlink.link("lo").unwrap();
+                assert_eq!(netlink.links().unwrap(), std::slice::from_ref(&lo));
+
+                netlink.set_link_up(lo.index).unwrap();
+                let events = tokio::time::timeout(Duration::from_secs(1), watcher.next_events());
+                let events = events.await.unwrap().unwrap();
+                assert!(events.iter().any(|event| matches!(
+                    event,
+                    InterfaceEvent::New(interface) if interface.name == "lo" && interface.is_up()
+                )));
+            });
+        });
+    }
+}
@@ -1,4 +1,16 @@
+pub mod applier;
 pub mod configurator;
+pub mod hook;
+pub mod interface;
+pub mod ipv4ll;
+pub mod link;
+pub mod netlink;
+pub mod netns;
+pub mod ntp;
+pub mod packet;
+pub mod resolv;
+pub mod shared;
+pub mod timezone;
 
 use std::{io, net::UdpSocket as StdUdpSocket};
 use thiserror::Error;
@@ -26,9 +38,15 @@ pub enum SocketError {
     #[error("Failed to set SO_REUSEADDR on socket")]
     SetReuseAddress(#[source] io::Error),
 
+    #[error("Failed to enable IP_PKTINFO on socket")]
+    SetPktInfo(#[source] io::Error),
+
     #[error("Failed to set socket to non-blocking mode")]
     SetNonBlocking(#[source] io::Error),
 
+    #[error("Failed to attach packet filter to socket")]
+    AttachFilter(#[source] io::Error),
+
     #[error("Failed to convert socket to TokioUdpSocket")]
     ConvertToTokio(#[source] io::Error),
 
@@ -99,23 +117,6 @@ pub fn new_tokio_socket_bound_to_device(
         .bind(&addr.into())
         .map_err(SocketError::BindSocket)?;
 
-    // Enable SO_REUSEPORT to allow multiple processes to bind to the same port
-    #[cfg(target_os = "linux")]
-    {
-        let ret = unsafe {
-            libc::setsockopt(
-                socket2.as_raw_fd(),
-                libc::SOL_SOCKET,
-                libc::SO_REUSEPORT,
-                &1i32 as *const i32 as *const libc::c_void,
-                std::mem::size_of::<i32>() as libc::socklen_t,
-            )
-        };
-        if ret < 0 {
-            tracing::warn!("Failed to set SO_REUSEPORT: {}", io::Error::last_os_error());
-        }
-    }
-
     // Convert to a standard socket, then into a Tokio socket.
     let std_socket: StdUdpSocket = socket2.into();
     std_socket
@@ -0,0 +1,1006 @@
+//! Native rtnetlink backend
+//!
+//! Talks to the kernel over a `NETLINK_ROUTE` socket instead of running
+//! iproute2, so it works on images without `ip` and reports the kernel's
+//! error codes instead of stderr text.
+
+use super::interface::Interface;
+use bytes::Bytes;
+use std::{
+    ffi::CString,
+    io,
+    net::Ipv4Addr,
+    ops::RangeInclusive,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::atomic::{AtomicU32, Ordering},
+};
+use thiserror::Error;
+
+/// Length of `struct nlmsghdr`.
+const NLMSG_HEADER_LEN: usize = 16;
+/// Length of `struct ifinfomsg`.
+const IFINFOMSG_LEN: usize = 16;
+/// Length of `struct ifaddrmsg`.
+const IFADDRMSG_LEN: usize = 8;
+/// Length of `struct rtmsg`.
+const RTMSG_LEN: usize = 12;
+/// Length of `struct fib_rule_hdr`.
+const FIB_RULE_HDR_LEN: usize = 12;
+/// Receive buffer size, large enough for a full dump batch.
+pub(super) const RECV_BUFFER_LEN: usize = 32 * 1024;
+/// Longest interface or address label, without the trailing NUL.
+pub const MAX_LABEL_LEN: usize = libc::IFNAMSIZ - 1;
+
+/// Route protocol of routes installed by a DHCP client (`proto dhcp`).
+pub const RTPROT_DHCP: u8 = 16;
+/// The main routing table.
+pub const MAIN_TABLE: u32 = libc::RT_TABLE_MAIN as u32;
+
+// Routing rule attributes and actions from linux/fib_rules.h
+const FRA_SRC: u16 = 2;
+const FRA_PRIORITY: u16 = 6;
+const FRA_TABLE: u16 = 15;
+const FRA_PROTOCOL: u16 = 21;
+const FR_ACT_TO_TBL: u8 = 1;
+
+/// Address lifetime the kernel treats as forever.
+pub const INFINITE_LIFETIME: u32 = u32::MAX;
+/// Length of `struct ifa_cacheinfo`.
+const IFA_CACHEINFO_LEN: usize = 16;
+
+/// Errors from the netlink backend.
+#[derive(Error, Debug)]
+pub enum NetlinkError {
+    #[error("Netlink socket error")]
+    Io(#[from] io::Error),
+
+    #[error("Interface '{0}' not found")]
+    InterfaceNotFound(String),
+
+    #[error("Kernel rejected {operation}: {}", io::Error::from_raw_os_error(*.errno))]
+    Kernel { operation: &'static str, errno: i32 },
+
+    #[error("Malformed netlink reply")]
+    Malformed,
+}
+
+impl NetlinkError {
+    /// The kernel's errno for a rejected request.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            NetlinkError::Kernel { errno, .. } => Some(*errno),
+            _ => None,
+        }
+    }
+}
+
+/// Scope of an address or route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Universe,
+    Link,
+    Host,
+}
+
+impl Scope {
+    fn as_raw(self) -> u8 {
+        match self {
+            Scope::Universe => libc::RT_SCOPE_UNIVERSE,
+            Scope::Link => libc::RT_SCOPE_LINK,
+            Scope::Host => libc::RT_SCOPE_HOST,
+        }
+    }
+}
+
+/// An IPv4 address assigned to an interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub broadcast: Option<Ipv4Addr>,
+    pub scope: Scope,
+    /// Address label (`IFA_LABEL`); the kernel reports the interface name if unset
+    pub label: Option<String>,
+    /// Seconds until the kernel removes the address; `None` is forever
+    pub valid_lifetime: Option<u32>,
+    /// Seconds until the address is deprecated; `None` is forever
+    pub preferred_lifetime: Option<u32>,
+}
+
+impl Address {
+    pub fn new(address: Ipv4Addr, prefix_len: u8) -> Self {
+        Self {
+            address,
+            prefix_len,
+            broadcast: None,
+            scope: Scope::Universe,
+            label: None,
+            valid_lifetime: None,
+            preferred_lifetime: None,
+        }
+    }
+}
+
+/// An IPv4 route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub destination: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub metric: Option<u32>,
+    /// Route protocol (`rtm_protocol`), identifying who installed the route
+    pub protocol: u8,
+    /// Routing table the route is in
+    pub table: u32,
+}
+
+impl Route {
+    /// Default route via `gateway`.
+    pub fn default_via(gateway: Ipv4Addr) -> Self {
+        Self {
+            destination: Ipv4Addr::UNSPECIFIED,
+            prefix_len: 0,
+            gateway: Some(gateway),
+            metric: None,
+            protocol: libc::RTPROT_BOOT,
+            table: MAIN_TABLE,
+        }
+    }
+}
+
+/// A routing policy rule sending traffic from `source` to `table`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    pub source: Ipv4Addr,
+    pub source_len: u8,
+    pub table: u32,
+    pub priority: Option<u32>,
+    /// Rule protocol (`FRA_PROTOCOL`), identifying who installed the rule
+    pub protocol: u8,
+}
+
+/// A `NETLINK_ROUTE` socket in the network namespace of the calling thread.
+pub struct NetlinkHandle {
+    fd: OwnedFd,
+    seq: AtomicU32,
+}
+
+impl NetlinkHandle {
+    pub fn open() -> Result<Self, NetlinkError> {
+        Ok(Self {
+            fd: open_socket(0, 0)?,
+            seq: AtomicU32::new(0),
+        })
+    }
+
+    /// Index of the interface called `name`, in the namespace the socket
+    /// was opened in.
+    pub fn link_index(&self, name: &str) -> Result<u32, NetlinkError> {
+        Ok(self.link(name)?.index)
+    }
+
+    /// Index, type, hardware address, MTU and flags of the interface called
+    /// `name` (RTM_GETLINK).
+    pub fn link(&self, name: &str) -> Result<Interface, NetlinkError> {
+        let not_found = || NetlinkError::InterfaceNotFound(name.to_string());
+        let c_name = CString::new(name).map_err(|_| not_found())?;
+        let mut msg = Message::new(libc::RTM_GETLINK, libc::NLM_F_ACK);
+        msg.push(&ifinfomsg(0, 0, 0));
+        msg.push_attr(libc::IFLA_IFNAME, c_name.as_bytes_with_nul());
+
+        let payloads = self.dump(msg).map_err(|e| match e.errno() {
+            Some(libc::ENODEV) => not_found(),
+            _ => e,
+        })?;
+        let payload = payloads.first().ok_or_else(not_found)?;
+        let mut interface = parse_link(payload).ok_or(NetlinkError::Malformed)?;
+        if interface.name.is_empty() {
+            interface.name = name.to_string();
+        }
+        Ok(interface)
+    }
+
+    /// Every interface (RTM_GETLINK dump).
+    pub fn links(&self) -> Result<Vec<Interface>, NetlinkError> {
+        let mut msg = Message::new(libc::RTM_GETLINK, libc::NLM_F_DUMP);
+        msg.push(&ifinfomsg(0, 0, 0));
+        self.dump(msg)?
+            .iter()
+            .map(|payload| parse_link(payload).ok_or(NetlinkError::Malformed))
+            .collect()
+    }
+
+    /// Sets the interface administratively up (RTM_NEWLINK).
+    pub fn set_link_up(&self, index: u32) -> Result<(), NetlinkError> {
+        let mut msg = Message::new(libc::RTM_NEWLINK, 0);
+        msg.push(&ifinfomsg(index, libc::IFF_UP as u32, libc::IFF_UP as u32));
+        self.request("RTM_NEWLINK", msg)
+    }
+
+    /// Sets the interface administratively down (RTM_NEWLINK).
+    pub fn set_link_down(&self, index: u32) -> Result<(), NetlinkError> {
+        let mut msg = Message::new(libc::RTM_NEWLINK, 0);
+        msg.push(&ifinfomsg(index, 0, libc::IFF_UP as u32));
+        self.request("RTM_NEWLINK", msg)
+    }
+
+    /// Whether the interface is up and has a carrier (RTM_GETLINK).
+    pub fn has_carrier(&self, index: u32) -> Result<bool, NetlinkError> {
+        let mut msg = Message::new(libc::RTM_GETLINK, libc::NLM_F_ACK);
+        msg.push(&ifinfomsg(index, 0, 0));
+        self.dump(msg)?
+            .iter()
+            .find_map(|payload| link_state(payload))
+            .map(|(_, carrier)| carrier)
+            .ok_or(NetlinkError::Malformed)
+    }
+
+    /// Sets the interface MTU (RTM_NEWLINK).
+    pub fn set_link_mtu(&self, index: u32, mtu: u32) -> Result<(), NetlinkError> {
+        let mut msg = Message::new(libc::RTM_NEWLINK, 0);
+        msg.push(&ifinfomsg(index, 0, 0));
+        msg.push_attr(libc::IFLA_MTU, &mtu.to_ne_bytes());
+        self.request("RTM_NEWLINK", msg)
+    }
+
+    /// Adds or replaces an address (RTM_NEWADDR).
+    pub fn add_address(&self, index: u32, address: &Address) -> Result<(), NetlinkError> {
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_REPLACE;
+        let mut msg = Message::new(libc::RTM_NEWADDR, flags);
+        msg.push(&ifaddrmsg(
+            index,
+            address.prefix_len,
+            address.scope.as_raw(),
+        ));
+        msg.push_attr(libc::IFA_LOCAL, &address.address.octets());
+        msg.push_attr(libc::IFA_ADDRESS, &address.address.octets());
+        if let Some(broadcast) = address.broadcast {
+            msg.push_attr(libc::IFA_BROADCAST, &broadcast.octets());
+        }
+        if let Some(ref label) = address.label {
+            let label = CString::new(label.as_str()).map_err(|_| NetlinkError::Malformed)?;
+            msg.push_attr(libc::IFA_LABEL, label.as_bytes_with_nul());
+        }
+        if address.valid_lifetime.is_some() || address.preferred_lifetime.is_some() {
+            msg.push_attr(
+                libc::IFA_CACHEINFO,
+                &ifa_cacheinfo(address.preferred_lifetime, address.valid_lifetime),
+            );
+        }
+        self.request("RTM_NEWADDR", msg)
+    }
+
+    /// Removes an address (RTM_DELADDR).
+    pub fn delete_address(
+        &self,
+        index: u32,
+        address: Ipv4Addr,
+        prefix_len: u8,
+    ) -> Result<(), NetlinkError> {
+        let mut msg = Message::new(libc::RTM_DELADDR, 0);
+        msg.push(&ifaddrmsg(index, prefix_len, 0));
+        msg.push_attr(libc::IFA_LOCAL, &address.octets());
+        msg.push_attr(libc::IFA_ADDRESS, &address.octets());
+        self.request("RTM_DELADDR", msg)
+    }
+
+    /// IPv4 addresses of the interface (RTM_GETADDR dump).
+    pub fn addresses(&self, index: u32) -> Result<Vec<Address>, NetlinkError> {
+        let mut msg = Message::new(libc::RTM_GETADDR, libc::NLM_F_DUMP);
+        msg.push(&ifaddrmsg(0, 0, 0));
+
+        let mut addresses = Vec::new();
+        for payload in self.dump(msg)? {
+            let header = payload
+                .get(..IFADDRMSG_LEN)
+                .ok_or(NetlinkError::Malformed)?;
+            let if_index = u32::from_ne_bytes(header[4..8].try_into().unwrap());
+            if header[0] != libc::AF_INET as u8 || if_index != index {
+                continue;
+            }
+
+            let mut address = None;
+            let mut broadcast = None;
+            let mut label = None;
+            let mut lifetimes = (None, None);
+            for (kind, value) in attributes(&payload[IFADDRMSG_LEN..]) {
+                match kind {
+                    libc::IFA_LOCAL => address = ipv4(value),
+                    libc::IFA_ADDRESS if address.is_none() => address = ipv4(value),
+                    libc::IFA_BROADCAST => broadcast = ipv4(value),
+                    libc::IFA_LABEL => label = string_attr(value),
+                    libc::IFA_CACHEINFO => lifetimes = lifetimes_attr(value),
+                    _ => {}
+                }
+            }
+            if let Some(address) = address {
+                addresses.push(Address {
+                    address,
+                    prefix_len: header[1],
+                    broadcast,
+                    scope: match header[3] {
+                        libc::RT_SCOPE_LINK => Scope::Link,
+                        libc::RT_SCOPE_HOST => Scope::Host,
+                        _ => Scope::Universe,
+                    },
+                    label,
+                    preferred_lifetime: lifetimes.0,
+                    valid_lifetime: lifetimes.1,
+                });
+            }
+        }
+        Ok(addresses)
+    }
+
+    /// Removes the IPv4 addresses labelled `label` from the interface.
+    pub fn flush_addresses(&self, index: u32, label: &str) -> Result<(), NetlinkError> {
+        for address in self.addresses(index)? {
+            if address.label.as_deref() == Some(label) {
+                self.delete_address(index, address.address, address.prefix_len)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds or replaces a route through the interface (RTM_NEWROUTE).
+    pub fn replace_route(&self, index: u32, route: &Route) -> Result<(), NetlinkError> {
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_REPLACE;
+        let mut msg = Message::new(libc::RTM_NEWROUTE, flags);
+        let scope = if route.gateway.is_some() {
+            Scope::Universe
+        } else {
+            Scope::Link
+        };
+        msg.push(&rtmsg(
+            route.prefix_len,
+            route.table,
+            route.protocol,
+            scope.as_raw(),
+        ));
+        msg.push_attr(libc::RTA_TABLE, &route.table.to_ne_bytes());
+        if route.prefix_len > 0 {
+            msg.push_attr(libc::RTA_DST, &route.destination.octets());
+        }
+        if let Some(gateway) = route.gateway {
+            msg.push_attr(libc::RTA_GATEWAY, &gateway.octets());
+        }
+        msg.push_attr(libc::RTA_OIF, &index.to_ne_bytes());
+        if let Some(metric) = route.metric {
+            msg.push_attr(libc::RTA_PRIORITY, &metric.to_ne_bytes());
+        }
+        self.request("RTM_NEWROUTE", msg)
+    }
+
+    /// Removes a route (RTM_DELROUTE). Only a route installed with the same
+    /// protocol matches.
+    pub fn delete_route(&self, index: u32, route: &Route) -> Result<(), NetlinkError> {
+        let mut msg = Message::new(libc::RTM_DELROUTE, 0);
+        msg.push(&rtmsg(
+            route.prefix_len,
+            route.table,
+            route.protocol,
+            libc::RT_SCOPE_NOWHERE,
+        ));
+        msg.push_attr(libc::RTA_TABLE, &route.table.to_ne_bytes());
+        if route.prefix_len > 0 {
+            msg.push_attr(libc::RTA_DST, &route.destination.octets());
+        }
+        if let Some(gateway) = route.gateway {
+            msg.push_attr(libc::RTA_GATEWAY, &gateway.octets());
+        }
+        msg.push_attr(libc::RTA_OIF, &index.to_ne_bytes());
+        if let Some(metric) = route.metric {
+            msg.push_attr(libc::RTA_PRIORITY, &metric.to_ne_bytes());
+        }
+        self.request("RTM_DELROUTE", msg)
+    }
+
+    /// IPv4 routes in `table` (RTM_GETROUTE dump), with their outgoing
+    /// interface index.
+    pub fn routes(&self, table: u32) -> Result<Vec<(u32, Route)>, NetlinkError> {
+        let mut msg = Message::new(libc::RTM_GETROUTE, libc::NLM_F_DUMP);
+        msg.push(&rtmsg(0, 0, 0, 0));
+
+        let mut routes = Vec::new();
+        for payload in self.dump(msg)? {
+            let header = payload.get(..RTMSG_LEN).ok_or(NetlinkError::Malformed)?;
+            if header[0] != libc::AF_INET as u8 {
+                continue;
+            }
+
+            let mut route = Route {
+                destination: Ipv4Addr::UNSPECIFIED,
+                prefix_len: header[1],
+                gateway: None,
+                metric: None,
+                protocol: header[5],
+                table: u32::from(header[4]),
+            };
+            let mut oif = 0;
+            for (kind, value) in attributes(&payload[RTMSG_LEN..]) {
+                match kind {
+                    libc::RTA_DST => route.destination = ipv4(value).unwrap_or(route.destination),
+                    libc::RTA_GATEWAY => route.gateway = ipv4(value),
+                    libc::RTA_PRIORITY => route.metric = u32_attr(value),
+                    libc::RTA_OIF => oif = u32_attr(value).unwrap_or(0),
+                    libc::RTA_TABLE => route.table = u32_attr(value).unwrap_or(route.table),
+                    _ => {}
+                }
+            }
+            if route.table == table {
+                routes.push((oif, route));
+            }
+        }
+        Ok(routes)
+    }
+
+    /// Removes the IPv4 routes of `protocol` with a metric in `metrics`
+    /// through the interface from `table`.
+    pub fn flush_routes(
+        &self,
+        index: u32,
+        table: u32,
+        protocol: u8,
+        metrics: RangeInclusive<u32>,
+    ) -> Result<(), NetlinkError> {
+        for (oif, route) in self.routes(table)? {
+            if oif == index
+                && route.protocol == protocol
+                && metrics.contains(&route.metric.unwrap_or(0))
+            {
+                self.delete_route(oif, &route)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a routing policy rule (RTM_NEWRULE). Adding a rule that
+    /// already exists fails with `EEXIST`.
+    pub fn add_rule(&self, rule: &Rule) -> Result<(), NetlinkError> {
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+        let mut msg = Message::new(libc::RTM_NEWRULE, flags);
+        push_rule(&mut msg, rule);
+        self.request("RTM_NEWRULE", msg)
+    }
+
+    /// Removes a routing policy rule (RTM_DELRULE).
+    pub fn delete_rule(&self, rule: &Rule) -> Result<(), NetlinkError> {
+        let mut msg = Message::new(libc::RTM_DELRULE, 0);
+        push_rule(&mut msg, rule);
+        self.request("RTM_DELRULE", msg)
+    }
+
+    /// IPv4 routing policy rules that select a table by source address
+    /// (RTM_GETRULE dump).
+    pub fn rules(&self) -> Result<Vec<Rule>, NetlinkError> {
+        let mut msg = Message::new(libc::RTM_GETRULE, libc::NLM_F_DUMP);
+        msg.push(&fib_rule_hdr(0, 0));
+
+        let mut rules = Vec::new();
+        for payload in self.dump(msg)? {
+            let header = payload
+                .get(..FIB_RULE_HDR_LEN)
+                .ok_or(NetlinkError::Malformed)?;
+            if header[0] != libc::AF_INET as u8 || header[7] != FR_ACT_TO_TBL {
+                continue;
+            }
+
+            let mut rule = Rule {
+                source: Ipv4Addr::UNSPECIFIED,
+                source_len: header[2],
+                table: u32::from(header[4]),
+                priority: None,
+                protocol: 0,
+            };
+            for (kind, value) in attributes(&payload[FIB_RULE_HDR_LEN..]) {
+                match kind {
+                    FRA_SRC => rule.source = ipv4(value).unwrap_or(rule.source),
+                    FRA_TABLE => rule.table = u32_attr(value).unwrap_or(rule.table),
+                    FRA_PRIORITY => rule.priority = u32_attr(value),
+                    FRA_PROTOCOL => rule.protocol = value.first().copied().unwrap_or(0),
+                    _ => {}
+                }
+            }
+            if rule.source_len > 0 {
+                rules.push(rule);
+            }
+        }
+        Ok(rules)
+    }
+
+    fn next_seq(&self) -> u32 {
+        self.seq.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+    }
+
+    fn send(&self, msg: Message) -> Result<u32, NetlinkError> {
+        let seq = self.next_seq();
+        let bytes = msg.finish(seq);
+        let ret = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                bytes.as_ptr() as *const libc::c_void,
+                bytes.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(seq)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> Result<usize, NetlinkError> {
+        let ret = unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(ret as usize)
+    }
+
+    /// Sends a request and waits for the kernel's acknowledgement.
+    fn request(&self, operation: &'static str, msg: Message) -> Result<(), NetlinkError> {
+        let seq = self.send(msg.with_flags(libc::NLM_F_ACK))?;
+        let mut buf = vec![0u8; RECV_BUFFER_LEN];
+        loop {
+            let len = self.recv(&mut buf)?;
+            for (header, payload) in messages(&buf[..len]) {
+                if header.seq != seq || header.kind != libc::NLMSG_ERROR as u16 {
+                    continue;
+                }
+                return match error_code(payload)? {
+                    0 => Ok(()),
+                    errno => Err(NetlinkError::Kernel { operation, errno }),
+                };
+            }
+        }
+    }
+
+    /// Sends a dump request and collects the payloads of every reply.
+    fn dump(&self, msg: Message) -> Result<Vec<Vec<u8>>, NetlinkError> {
+        let seq = self.send(msg)?;
+        let mut buf = vec![0u8; RECV_BUFFER_LEN];
+        let mut payloads = Vec::new();
+        loop {
+            let len = self.recv(&mut buf)?;
+            for (header, payload) in messages(&buf[..len]) {
+                if header.seq != seq {
+                    continue;
+                }
+                match header.kind as i32 {
+                    libc::NLMSG_DONE => return Ok(payloads),
+                    libc::NLMSG_ERROR => match error_code(payload)? {
+                        0 => return Ok(payloads),
+                        errno => {
+                            return Err(NetlinkError::Kernel {
+                                operation: "dump",
+                                errno,
+                            })
+                        }
+                    },
+                    _ => payloads.push(payload.to_vec()),
+                }
+            }
+        }
+    }
+}
+
+/// Opens a `NETLINK_ROUTE` socket with the extra socket `flags`, subscribed
+/// to the multicast `groups`.
+pub(super) fn open_socket(flags: i32, groups: u32) -> Result<OwnedFd, NetlinkError> {
+    let raw = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | flags,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if raw < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // The descriptor was just created and is owned by nothing else
+    let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as u16;
+    addr.nl_groups = groups;
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(fd)
+}
+
+/// A netlink request under construction.
+struct Message {
+    kind: u16,
+    flags: u16,
+    body: Vec<u8>,
+}
+
+impl Message {
+    fn new(kind: u16, flags: i32) -> Self {
+        Self {
+            kind,
+            flags: (libc::NLM_F_REQUEST | flags) as u16,
+            body: Vec::new(),
+        }
+    }
+
+    fn with_flags(mut self, flags: i32) -> Self {
+        self.flags |= flags as u16;
+        self
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.body.extend_from_slice(bytes);
+        self.body.resize(align(self.body.len()), 0);
+    }
+
+    fn push_attr(&mut self, kind: u16, value: &[u8]) {
+        let len = (4 + value.len()) as u16;
+        self.body.extend_from_slice(&len.to_ne_bytes());
+        self.body.extend_from_slice(&kind.to_ne_bytes());
+        self.push(value);
+    }
+
+    fn finish(self, seq: u32) -> Vec<u8> {
+        let len = (NLMSG_HEADER_LEN + self.body.len()) as u32;
+        let mut bytes = Vec::with_capacity(len as usize);
+        bytes.extend_from_slice(&len.to_ne_bytes());
+        bytes.extend_from_slice(&self.kind.to_ne_bytes());
+        bytes.extend_from_slice(&self.flags.to_ne_bytes());
+        bytes.extend_from_slice(&seq.to_ne_bytes());
+        bytes.extend_from_slice(&0u32.to_ne_bytes()); // port id, filled in by the kernel
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+pub(super) struct Header {
+    pub(super) kind: u16,
+    seq: u32,
+}
+
+/// Splits a receive buffer into netlink messages.
+pub(super) fn messages(mut buf: &[u8]) -> impl Iterator<Item = (Header, &[u8])> {
+    std::iter::from_fn(move || {
+        let len = u32::from_ne_bytes(buf.get(0..4)?.try_into().ok()?) as usize;
+        if len < NLMSG_HEADER_LEN || len > buf.len() {
+            return None;
+        }
+        let header = Header {
+            kind: u16::from_ne_bytes(buf[4..6].try_into().ok()?),
+            seq: u32::from_ne_bytes(buf[8..12].try_into().ok()?),
+        };
+        let payload = &buf[NLMSG_HEADER_LEN..len];
+        buf = &buf[align(len).min(buf.len())..];
+        Some((header, payload))
+    })
+}
+
+/// Splits attributes into (type, value) pairs.
+fn attributes(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let len = usize::from(u16::from_ne_bytes(buf.get(0..2)?.try_into().ok()?));
+        if len < 4 || len > buf.len() {
+            return None;
+        }
+        // Mask out NLA_F_NESTED and NLA_F_NET_BYTEORDER
+        let kind = u16::from_ne_bytes(buf[2..4].try_into().ok()?) & 0x3fff;
+        let value = &buf[4..len];
+        buf = &buf[align(len).min(buf.len())..];
+        Some((kind, value))
+    })
+}
+
+/// Negated errno from an NLMSG_ERROR payload; zero is an acknowledgement.
+fn error_code(payload: &[u8]) -> Result<i32, NetlinkError> {
+    let code = payload.get(0..4).ok_or(NetlinkError::Malformed)?;
+    Ok(-i32::from_ne_bytes(code.try_into().unwrap()))
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn ipv4(value: &[u8]) -> Option<Ipv4Addr> {
+    let octets: [u8; 4] = value.try_into().ok()?;
+    Some(Ipv4Addr::from(octets))
+}
+
+fn u32_attr(value: &[u8]) -> Option<u32> {
+    Some(u32::from_ne_bytes(value.try_into().ok()?))
+}
+
+/// Preferred and valid lifetimes from an `ifa_cacheinfo`.
+fn lifetimes_attr(value: &[u8]) -> (Option<u32>, Option<u32>) {
+    let lifetime = |range: std::ops::Range<usize>| {
+        value
+            .get(range)
+            .and_then(u32_attr)
+            .filter(|&secs| secs != INFINITE_LIFETIME)
+    };
+    (lifetime(0..4), lifetime(4..8))
+}
+
+fn string_attr(value: &[u8]) -> Option<String> {
+    let value = value.split(|&b| b == 0).next()?;
+    String::from_utf8(value.to_vec()).ok()
+}
+
+/// Interface index and carrier from an RTM_NEWLINK payload. The carrier
+/// counts as present when the link is up and `IFF_LOWER_UP` is set.
+pub(super) fn link_state(payload: &[u8]) -> Option<(u32, bool)> {
+    let index = u32::from_ne_bytes(payload.get(4..8)?.try_into().ok()?);
+    let flags = u32::from_ne_bytes(payload.get(8..12)?.try_into().ok()?);
+    let up = libc::IFF_UP as u32 | libc::IFF_LOWER_UP as u32;
+    Some((index, flags & up == up))
+}
+
+/// Name, index, type, hardware address, MTU and flags from an RTM_NEWLINK
+/// payload.
+pub(super) fn parse_link(payload: &[u8]) -> Option<Interface> {
+    let header = payload.get(..IFINFOMSG_LEN)?;
+    let mut interface = Interface {
+        name: String::new(),
+        index: u32::from_ne_bytes(header[4..8].try_into().ok()?),
+        hardware_type: u16::from_ne_bytes(header[2..4].try_into().ok()?),
+        hardware_address: Bytes::new(),
+        mtu: 0,
+        flags: u32::from_ne_bytes(header[8..12].try_into().ok()?),
+    };
+    for (kind, value) in attributes(&payload[IFINFOMSG_LEN..]) {
+        match kind {
+            libc::IFLA_IFNAME => interface.name = string_attr(value).unwrap_or_default(),
+            libc::IFLA_ADDRESS => interface.hardware_address = Bytes::copy_from_slice(value),
+            libc::IFLA_MTU => interface.mtu = u32_attr(value).unwrap_or_default(),
+            _ => {}
+        }
+    }
+    Some(interface)
+}
+
+fn ifinfomsg(index: u32, flags: u32, change: u32) -> [u8; IFINFOMSG_LEN] {
+    let mut msg = [0u8; IFINFOMSG_LEN];
+    msg[0] = libc::AF_UNSPEC as u8;
+    msg[4..8].copy_from_slice(&index.to_ne_bytes());
+    msg[8..12].copy_from_slice(&flags.to_ne_bytes());
+    msg[12..16].copy_from_slice(&change.to_ne_bytes());
+    msg
+}
+
+fn ifaddrmsg(index: u32, prefix_len: u8, scope: u8) -> [u8; IFADDRMSG_LEN] {
+    let mut msg = [0u8; IFADDRMSG_LEN];
+    msg[0] = libc::AF_INET as u8;
+    msg[1] = prefix_len;
+    msg[3] = scope;
+    msg[4..8].copy_from_slice(&index.to_ne_bytes());
+    msg
+}
+
+fn fib_rule_hdr(source_len: u8, table: u32) -> [u8; FIB_RULE_HDR_LEN] {
+    let mut hdr = [0u8; FIB_RULE_HDR_LEN];
+    hdr[0] = libc::AF_INET as u8;
+    hdr[2] = source_len;
+    hdr[4] = table_byte(table);
+    hdr[7] = FR_ACT_TO_TBL;
+    hdr
+}
+
+fn push_rule(msg: &mut Message, rule: &Rule) {
+    msg.push(&fib_rule_hdr(rule.source_len, rule.table));
+    msg.push_attr(FRA_SRC, &rule.source.octets());
+    msg.push_attr(FRA_TABLE, &rule.table.to_ne_bytes());
+    if let Some(priority) = rule.priority {
+        msg.push_attr(FRA_PRIORITY, &priority.to_ne_bytes());
+    }
+    if rule.protocol != 0 {
+        msg.push_attr(FRA_PROTOCOL, &[rule.protocol]);
+    }
+}
+
+fn ifa_cacheinfo(preferred: Option<u32>, valid: Option<u32>) -> [u8; IFA_CACHEINFO_LEN] {
+    let mut info = [0u8; IFA_CACHEINFO_LEN];
+    info[0..4].copy_from_slice(&preferred.unwrap_or(INFINITE_LIFETIME).to_ne_bytes());
+    info[4..8].copy_from_slice(&valid.unwrap_or(INFINITE_LIFETIME).to_ne_bytes());
+    info
+}
+
+/// Table number for a header field, which only holds tables below 256;
+/// larger ones are passed as an attribute.
+fn table_byte(table: u32) -> u8 {
+    u8::try_from(table).unwrap_or(libc::RT_TABLE_UNSPEC)
+}
+
+fn rtmsg(dst_len: u8, table: u32, protocol: u8, scope: u8) -> [u8; RTMSG_LEN] {
+    let mut msg = [0u8; RTMSG_LEN];
+    msg[0] = libc::AF_INET as u8;
+    msg[1] = dst_len;
+    msg[4] = table_byte(table);
+    msg[5] = protocol;
+    msg[6] = scope;
+    msg[7] = libc::RTN_UNICAST;
+    msg
+}
+
+/// Runs `test` on a thread inside a fresh network namespace, or skips it
+/// if the sandbox does not allow creating one.
+#[cfg(test)]
+pub(crate) fn in_network_namespace(test: impl FnOnce() + Send + 'static) {
+    std::thread::spawn(move || {
+        if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+            eprintln!(
+                "skipping: cannot create network namespace: {}",
+                io::Error::last_os_error()
+            );
+            return;
+        }
+        test();
+    })
+    .join()
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attributes_roundtrip() {
+        let mut msg = Message::new(libc::RTM_NEWADDR, 0);
+        msg.push(&ifaddrmsg(1, 24, 0));
+        msg.push_attr(libc::IFA_LOCAL, &[10, 0, 0, 1]);
+        msg.push_attr(libc::IFA_LABEL, b"eth0\0");
+        msg.push_attr(libc::IFA_BROADCAST, &[10, 0, 0, 255]);
+        let bytes = msg.finish(7);
+
+        let (header, payload) = messages(&bytes).next().unwrap();
+        assert_eq!(header.seq, 7);
+        assert_eq!(header.kind, libc::RTM_NEWADDR);
+
+        let attrs: Vec<_> = attributes(&payload[IFADDRMSG_LEN..]).collect();
+        assert_eq!(attrs.len(), 3);
+        assert_eq!(attrs[1], (libc::IFA_LABEL, &b"eth0\0"[..]));
+        assert_eq!(ipv4(attrs[2].1), Some(Ipv4Addr::new(10, 0, 0, 255)));
+    }
+
+    #[test]
+    fn test_address_lifecycle_in_namespace() {
+        in_network_namespace(|| {
+            let netlink = NetlinkHandle::open().unwrap();
+            let lo = netlink.link_index("lo").unwrap();
+            netlink.set_link_up(lo).unwrap();
+
+            let mut address = Address::new(Ipv4Addr::new(10, 1, 2, 3), 24);
+            address.broadcast = Some(Ipv4Addr::new(10, 1, 2, 255));
+            address.label = Some("lo:herald".to_string());
+            netlink.add_address(lo, &address).unwrap();
+            // Replacing an existing address is not an error
+            netlink.add_address(lo, &address).unwrap();
+            assert!(netlink.addresses(lo).unwrap().contains(&address));
+
+            // Lifetimes count down from the values given
+            address.valid_lifetime = Some(300);
+            address.preferred_lifetime = Some(200);
+            netlink.add_address(lo, &address).unwrap();
+            let installed = netlink.addresses(lo).unwrap().pop().unwrap();
+            assert!(matches!(installed.valid_lifetime, Some(290..=300)));
+            assert!(matches!(installed.preferred_lifetime, Some(190..=200)));
+
+            // Only addresses with the label are flushed
+            let other = Address::new(Ipv4Addr::new(10, 1, 3, 3), 24);
+            netlink.add_address(lo, &other).unwrap();
+            netlink.flush_addresses(lo, "lo:herald").unwrap();
+            let remaining = netlink.addresses(lo).unwrap();
+            assert!(remaining.iter().any(|a| a.address == other.address));
+            assert!(!remaining.iter().any(|a| a.address == address.address));
+
+            let err = netlink
+                .delete_address(lo, address.address, address.prefix_len)
+                .unwrap_err();
+            assert_eq!(err.errno(), Some(libc::EADDRNOTAVAIL));
+        });
+    }
+
+    #[test]
+    fn test_routes_in_namespace() {
+        in_network_namespace(|| {
+            let netlink = NetlinkHandle::open().unwrap();
+            let lo = netlink.link_index("lo").unwrap();
+            netlink.set_link_up(lo).unwrap();
+            netlink
+                .add_address(lo, &Address::new(Ipv4Addr::new(10, 1, 2, 3), 24))
+                .unwrap();
+
+            let mut route = Route::default_via(Ipv4Addr::new(10, 1, 2, 1));
+            route.metric = Some(1024);
+            netlink.replace_route(lo, &route).unwrap();
+            assert!(netlink.routes(MAIN_TABLE).unwrap().contains(&(lo, route)));
+
+            // Deleting only matches routes with the same protocol
+            let mut other = route;
+            other.protocol = RTPROT_DHCP;
+            let err = netlink.delete_route(lo, &other).unwrap_err();
+            assert_eq!(err.errno(), Some(libc::ESRCH));
+
+            // Only routes of the protocol with a metric in the range are
+            // flushed
+            other.metric = Some(1025);
+            netlink.replace_route(lo, &other).unwrap();
+            let mut kept = other;
+            kept.metric = Some(1027);
+            netlink.replace_route(lo, &kept).unwrap();
+            netlink
+                .flush_routes(lo, MAIN_TABLE, RTPROT_DHCP, 1024..=1026)
+                .unwrap();
+            let remaining = netlink.routes(MAIN_TABLE).unwrap();
+            assert!(remaining.contains(&(lo, route)));
+            assert!(!remaining.contains(&(lo, other)));
+            assert!(remaining.contains(&(lo, kept)));
+            netlink.delete_route(lo, &kept).unwrap();
+
+            // Gateways must be reachable
+            let unreachable = Route::default_via(Ipv4Addr::new(192, 0, 2, 1));
+            let err = netlink.replace_route(lo, &unreachable).unwrap_err();
+            assert_eq!(err.errno(), Some(libc::ENETUNREACH));
+        });
+    }
+
+    #[test]
+    fn test_policy_routing_in_namespace() {
+        in_network_namespace(|| {
+            let netlink = NetlinkHandle::open().unwrap();
+            let lo = netlink.link_index("lo").unwrap();
+            netlink.set_link_up(lo).unwrap();
+            netlink
+                .add_address(lo, &Address::new(Ipv4Addr::new(10, 1, 2, 3), 24))
+                .unwrap();
+
+            // Tables above 255 do not fit the header field
+            let mut route = Route::default_via(Ipv4Addr::new(10, 1, 2, 1));
+            route.table = 1000;
+            netlink.replace_route(lo, &route).unwrap();
+            assert_eq!(netlink.routes(1000).unwrap(), vec![(lo, route)]);
+            assert!(!netlink.routes(MAIN_TABLE).unwrap().contains(&(lo, route)));
+
+            let rule = Rule {
+                source: Ipv4Addr::new(10, 1, 2, 3),
+                source_len: 32,
+                table: 1000,
+                priority: Some(10000),
+                protocol: RTPROT_DHCP,
+            };
+            netlink.add_rule(&rule).unwrap();
+            assert_eq!(netlink.rules().unwrap(), vec![rule]);
+            let err = netlink.add_rule(&rule).unwrap_err();
+            assert_eq!(err.errno(), Some(libc::EEXIST));
+
+            netlink.delete_rule(&rule).unwrap();
+            assert!(netlink.rules().unwrap().is_empty());
+            netlink.delete_route(lo, &route).unwrap();
+            assert!(netlink.routes(1000).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_missing_interface() {
+        let netlink = NetlinkHandle::open().unwrap();
+        assert!(matches!(
+            netlink.link_index("herald-missing0"),
+            Err(NetlinkError::InterfaceNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,212 @@
+//! Network namespaces
+//!
+//! herald can configure an interface inside another network namespace, such
+//! as a container's, while the process itself stays where it was started.
+//! Namespaces belong to threads, so everything that must happen inside runs
+//! on a short-lived thread that joined the namespace with `setns`. Sockets
+//! keep the namespace they were created in.
+
+use crate::error::HeraldError;
+use std::{
+    fmt,
+    fs::File,
+    io,
+    os::{fd::AsRawFd, unix::process::CommandExt},
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+/// Directory in which `ip netns` keeps named namespaces.
+pub const NETNS_RUN_DIR: &str = "/run/netns";
+
+/// Directory whose `<name>` subdirectory `ip netns exec` mounts over `/etc`.
+pub const NETNS_ETC_DIR: &str = "/etc/netns";
+
+/// A network namespace, identified by its file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetNs {
+    path: PathBuf,
+}
+
+impl NetNs {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The name given to `ip netns`, if the namespace has one.
+    pub fn name(&self) -> Option<&str> {
+        let name = self.path.strip_prefix(NETNS_RUN_DIR).ok()?.to_str()?;
+        (!name.is_empty() && !name.contains('/')).then_some(name)
+    }
+
+    /// Where files for the namespace's `/etc` go, such as its resolv.conf.
+    /// Only named namespaces have one.
+    pub fn etc_dir(&self) -> Option<PathBuf> {
+        self.name().map(|name| Path::new(NETNS_ETC_DIR).join(name))
+    }
+
+    /// Runs `f` on a thread inside the namespace. The caller's tokio runtime,
+    /// if any, is entered there too, so async sockets can be created.
+    pub fn run<T: Send>(
+        &self,
+        f: impl FnOnce() -> Result<T, HeraldError> + Send,
+    ) -> Result<T, HeraldError> {
+        let file = self.open()?;
+        let runtime = tokio::runtime::Handle::try_current().ok();
+
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let _runtime = runtime.as_ref().map(|handle| handle.enter());
+                    enter(&file).map_err(HeraldError::Io)?;
+                    f()
+                })
+                .join()
+                .map_err(|_| {
+                    HeraldError::Critical(format!("thread in namespace {self} panicked"))
+                })?
+        })
+    }
+
+    /// Makes `command` run inside the namespace.
+    pub fn apply_to(&self, command: &mut Command) -> Result<(), HeraldError> {
+        let file = self.open()?;
+        // Only makes a system call between fork and exec
+        unsafe {
+            command.pre_exec(move || enter(&file));
+        }
+        Ok(())
+    }
+
+    fn open(&self) -> Result<File, HeraldError> {
+        File::open(&self.path).map_err(|e| {
+            HeraldError::Critical(format!("cannot open network namespace {self}: {e}"))
+        })
+    }
+}
+
+impl FromStr for NetNs {
+    type Err = String;
+
+    /// Parses a namespace name from `ip netns`, or a path such as
+    /// `/proc/<pid>/ns/net`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" | "." | ".." => Err(format!("invalid network namespace '{s}'")),
+            path if path.contains('/') => Ok(NetNs::new(path)),
+            name => Ok(NetNs::new(Path::new(NETNS_RUN_DIR).join(name))),
+        }
+    }
+}
+
+impl fmt::Display for NetNs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+/// Runs `f` inside `netns`, or on the calling thread without one.
+pub fn run_in<T: Send>(
+    netns: Option<&NetNs>,
+    f: impl FnOnce() -> Result<T, HeraldError> + Send,
+) -> Result<T, HeraldError> {
+    match netns {
+        Some(netns) => netns.run(f),
+        None => f(),
+    }
+}
+
+/// Moves the calling thread into the network namespace of `file`
+fn enter(file: &File) -> io::Result<()> {
+    if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::netlink::{Address, NetlinkHandle};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "blue".parse::<NetNs>().unwrap().path(),
+            Path::new("/run/netns/blue")
+        );
+        assert_eq!(
+            "/proc/42/ns/net".parse::<NetNs>().unwrap().path(),
+            Path::new("/proc/42/ns/net")
+        );
+        assert!("..".parse::<NetNs>().is_err());
+        assert!("".parse::<NetNs>().is_err());
+    }
+
+    #[test]
+    fn test_name_and_etc_dir() {
+        let named: NetNs = "blue".parse().unwrap();
+        assert_eq!(named.name(), Some("blue"));
+        assert_eq!(named.etc_dir(), Some(PathBuf::from("/etc/netns/blue")));
+
+        let path: NetNs = "/proc/42/ns/net".parse().unwrap();
+        assert_eq!(path.name(), None);
+        assert_eq!(path.etc_dir(), None);
+    }
+
+    #[test]
+    fn test_missing_namespace() {
+        let netns: NetNs = "herald-missing".parse().unwrap();
+        assert!(netns.run(|| Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_changes_stay_in_namespace() {
+        // A namespace kept alive only by an open file
+        let file = std::thread::spawn(|| {
+            if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+                return None;
+            }
+            File::open("/proc/thread-self/ns/net").ok()
+        })
+        .join()
+        .unwrap();
+        let Some(file) = file else {
+            eprintln!("skipping: cannot create network namespace");
+            return;
+        };
+        let netns = NetNs::new(format!("/proc/self/fd/{}", file.as_raw_fd()));
+        let address = Address::new(Ipv4Addr::new(10, 200, 0, 1), 32);
+
+        let lo = netns
+            .run(|| {
+                let netlink = NetlinkHandle::open()?;
+                let lo = netlink.link_index("lo")?;
+                netlink.set_link_up(lo)?;
+                netlink.add_address(lo, &address)?;
+                Ok(lo)
+            })
+            .unwrap();
+
+        let inside = netns
+            .run(|| Ok(NetlinkHandle::open()?.addresses(lo)?))
+            .unwrap();
+        assert!(inside.iter().any(|a| a.address == address.address));
+        let outside = NetlinkHandle::open().unwrap().addresses(lo).unwrap();
+        assert!(!outside.iter().any(|a| a.address == address.address));
+
+        // Commands run inside as well
+        let mut command = Command::new("ip");
+        command.args(["-4", "addr", "show", "dev", "lo"]);
+        netns.apply_to(&mut command).unwrap();
+        if let Ok(output) = command.output() {
+            assert!(String::from_utf8_lossy(&output.stdout).contains("10.200.0.1/32"));
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! NTP server export for local time daemons
+//!
+//! NTP servers received in option 42 are written to a per-interface drop-in
+//! file that chrony or systemd-timesyncd picks up.
+
+use crate::error::HeraldError;
+use std::{
+    fmt,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+use tracing::{info, warn};
+
+/// Time daemon drop-in format for exported NTP servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NtpFormat {
+    /// chrony `sourcedir` file (`server <addr> iburst` lines)
+    Chrony,
+    /// systemd-timesyncd `timesyncd.conf.d` drop-in
+    Timesyncd,
+}
+
+impl NtpFormat {
+    /// Directory the drop-in is written to when none is configured.
+    pub fn default_directory(self) -> &'static Path {
+        match self {
+            NtpFormat::Chrony => Path::new("/run/chrony-dhcp"),
+            NtpFormat::Timesyncd => Path::new("/run/systemd/timesyncd.conf.d"),
+        }
+    }
+
+    /// Name of the drop-in file for `interface`.
+    pub fn file_name(self, interface: &str) -> String {
+        match self {
+            NtpFormat::Chrony => format!("herald-{interface}.sources"),
+            NtpFormat::Timesyncd => format!("50-herald-{interface}.conf"),
+        }
+    }
+
+    /// Render the drop-in contents for `servers`.
+    pub fn render(self, servers: &[Ipv4Addr]) -> String {
+        let mut contents = String::from("# Generated by herald DHCP client\n");
+        match self {
+            NtpFormat::Chrony => {
+                for server in servers {
+                    contents.push_str(&format!("server {server} iburst\n"));
+                }
+            }
+            NtpFormat::Timesyncd => {
+                let servers = servers
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                contents.push_str(&format!("[Time]\nNTP={servers}\n"));
+            }
+        }
+        contents
+    }
+
+    /// Ask the daemon to pick up the changed drop-in. Failures are only logged.
+    fn reload(self) {
+        let (program, args): (&str, &[&str]) = match self {
+            NtpFormat::Chrony => ("chronyc", &["reload", "sources"]),
+            NtpFormat::Timesyncd => ("systemctl", &["try-restart", "systemd-timesyncd.service"]),
+        };
+        match Command::new(program).args(args).output() {
+            Ok(output) if output.status.success() => {}
+            Ok(output) => warn!(
+                "Failed to reload time daemon: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            Err(e) => warn!("Failed to run {}: {}", program, e),
+        }
+    }
+}
+
+impl FromStr for NtpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chrony" => Ok(NtpFormat::Chrony),
+            "timesyncd" => Ok(NtpFormat::Timesyncd),
+            _ => Err(format!(
+                "unknown NTP format '{s}' (expected chrony or timesyncd)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for NtpFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NtpFormat::Chrony => "chrony",
+            NtpFormat::Timesyncd => "timesyncd",
+        })
+    }
+}
+
+/// Per-interface NTP drop-in managed for a lease.
+#[derive(Debug, Clone)]
+pub struct NtpExport {
+    format: NtpFormat,
+    path: PathBuf,
+}
+
+impl NtpExport {
+    pub fn new(format: NtpFormat, directory: Option<&Path>, interface: &str) -> Self {
+        let directory = directory.unwrap_or_else(|| format.default_directory());
+        Self {
+            format,
+            path: directory.join(format.file_name(interface)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the drop-in for `servers` without reloading the daemon.
+    pub fn write(&self, servers: &[Ipv4Addr]) -> Result<(), HeraldError> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(HeraldError::Io)?;
+        }
+        std::fs::write(&self.path, self.format.render(servers)).map_err(HeraldError::Io)
+    }
+
+    /// Remove the drop-in. Returns whether a file was removed.
+    pub fn remove(&self) -> Result<bool, HeraldError> {
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(HeraldError::Io(e)),
+        }
+    }
+
+    /// Write the drop-in for `servers` and reload the daemon.
+    pub fn apply(&self, servers: &[Ipv4Addr]) -> Result<(), HeraldError> {
+        info!(
+            "Writing NTP servers {:?} to {}",
+            servers,
+            self.path.display()
+        );
+        self.write(servers)?;
+        self.format.reload();
+        Ok(())
+    }
+
+    /// Remove the drop-in and reload the daemon if it existed.
+    pub fn clear(&self) -> Result<(), HeraldError> {
+        if self.remove()? {
+            info!("Removed NTP drop-in {}", self.path.display());
+            self.format.reload();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_chrony() {
+        let servers = [Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)];
+        assert_eq!(
+            NtpFormat::Chrony.render(&servers),
+            "# Generated by herald DHCP client\nserver 10.0.0.1 iburst\nserver 10.0.0.2 iburst\n"
+        );
+    }
+
+    #[test]
+    fn test_render_timesyncd() {
+        let servers = [Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)];
+        assert_eq!(
+            NtpFormat::Timesyncd.render(&servers),
+            "# Generated by herald DHCP client\n[Time]\nNTP=10.0.0.1 10.0.0.2\n"
+        );
+    }
+
+    #[test]
+    fn test_write_and_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let export = NtpExport::new(NtpFormat::Chrony, Some(dir.path()), "eth0");
+        assert_eq!(export.path(), dir.path().join("herald-eth0.sources"));
+
+        export.write(&[Ipv4Addr::new(10, 0, 0, 1)]).unwrap();
+        assert!(export.path().exists());
+
+        assert!(export.remove().unwrap());
+        assert!(!export.path().exists());
+        assert!(!export.remove().unwrap());
+    }
+}
@@ -0,0 +1,473 @@
+//! Raw `AF_PACKET` transport
+//!
+//! Until the interface has an address, DHCP messages are sent and received as
+//! complete Ethernet frames. This does not depend on the broadcast flag, and
+//! it also sees replies that are unicast to the offered address, which the
+//! kernel would drop before they reach a UDP socket.
+
+use crate::{error::HeraldError, network::SocketError};
+use std::{
+    ffi::CString,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+use tokio::io::{unix::AsyncFd, Interest};
+
+/// Length of an Ethernet header.
+const ETHERNET_HEADER_LEN: usize = 14;
+/// Length of an IPv4 header without options.
+const IPV4_HEADER_LEN: usize = 20;
+/// Length of a UDP header.
+const UDP_HEADER_LEN: usize = 8;
+/// IP protocol number of UDP.
+const IPPROTO_UDP: u8 = 17;
+/// TTL of sent datagrams.
+const DEFAULT_TTL: u8 = 64;
+/// Largest frame read from the socket.
+const MAX_FRAME_LEN: usize = ETHERNET_HEADER_LEN + 1500;
+/// Ethernet broadcast address.
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+/// `AF_PACKET` socket carrying DHCP as Ethernet/IPv4/UDP frames.
+pub struct PacketSocket {
+    fd: AsyncFd<OwnedFd>,
+    ifindex: i32,
+    mac: [u8; 6],
+    port: u16,
+}
+
+impl PacketSocket {
+    /// Opens a packet socket on `interface` that only receives UDP datagrams
+    /// for `port`, sent from `mac`.
+    pub fn open(interface: &str, mac: &[u8], port: u16) -> Result<Self, HeraldError> {
+        let mac: [u8; 6] = mac
+            .try_into()
+            .map_err(|_| HeraldError::InterfaceInvalid(interface.to_string()))?;
+        let (fd, ifindex) = open(interface, libc::SOCK_RAW, libc::ETH_P_IP as u16)?;
+
+        // Attach the filter so only DHCP replies wake us up
+        let filter = udp_port_filter(port);
+        let program = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_ptr() as *mut libc::sock_filter,
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_ATTACH_FILTER,
+                &program as *const libc::sock_fprog as *const libc::c_void,
+                std::mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(SocketError::AttachFilter(io::Error::last_os_error()).into());
+        }
+
+        Ok(Self {
+            fd: AsyncFd::new(fd).map_err(SocketError::ConvertToTokio)?,
+            ifindex,
+            mac,
+            port,
+        })
+    }
+
+    /// Sends `payload` from 0.0.0.0 to `dest` in a broadcast Ethernet frame.
+    pub async fn send_to(&self, payload: &[u8], dest: SocketAddr) -> io::Result<()> {
+        let SocketAddr::V4(dest) = dest else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packet socket only supports IPv4",
+            ));
+        };
+        let src = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, self.port);
+        let frame = build_udp_frame(self.mac, BROADCAST_MAC, src, dest, payload);
+        let addr = link_layer_address(self.ifindex, libc::ETH_P_IP as u16, BROADCAST_MAC);
+
+        let send = |fd: &OwnedFd| {
+            let ret = unsafe {
+                libc::sendto(
+                    fd.as_raw_fd(),
+                    frame.as_ptr() as *const libc::c_void,
+                    frame.len(),
+                    0,
+                    &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+                )
+            };
+            if ret < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        };
+        self.fd
+            .async_io(Interest::WRITABLE, |fd| match send(fd) {
+                // A link that went down leaves ENETDOWN pending on the socket,
+                // reported once by the next send even if the link is back
+                Err(e) if e.raw_os_error() == Some(libc::ENETDOWN) => send(fd),
+                result => result,
+            })
+            .await
+    }
+
+    /// Receives the next valid UDP datagram for our port, copying its payload
+    /// into `buf`. Frames that fail validation are skipped.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut frame = [0u8; MAX_FRAME_LEN];
+        loop {
+            let len = self
+                .fd
+                .async_io(Interest::READABLE, |fd| {
+                    let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+                    let mut addr_len = std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+                    let ret = unsafe {
+                        libc::recvfrom(
+                            fd.as_raw_fd(),
+                            frame.as_mut_ptr() as *mut libc::c_void,
+                            frame.len(),
+                            0,
+                            &mut addr as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                            &mut addr_len,
+                        )
+                    };
+                    if ret < 0 {
+                        Err(io::Error::last_os_error())
+                    } else if addr.sll_pkttype == libc::PACKET_OUTGOING {
+                        Ok(None)
+                    } else {
+                        Ok(Some(ret as usize))
+                    }
+                })
+                .await?;
+
+            let Some(len) = len else {
+                continue;
+            };
+            match parse_udp_frame(&frame[..len], self.port) {
+                Some((src, payload)) if payload.len() <= buf.len() => {
+                    buf[..payload.len()].copy_from_slice(payload);
+                    return Ok((payload.len(), SocketAddr::V4(src)));
+                }
+                Some(_) => tracing::debug!("Dropping oversized datagram"),
+                None => tracing::debug!("Dropping invalid frame of {} bytes", len),
+            }
+        }
+    }
+}
+
+/// Opens an `AF_PACKET` socket of `socket_type` for `protocol`, bound to
+/// `interface`. Returns the socket and the interface index.
+pub(crate) fn open(
+    interface: &str,
+    socket_type: i32,
+    protocol: u16,
+) -> Result<(OwnedFd, i32), HeraldError> {
+    let name = CString::new(interface)
+        .map_err(|_| HeraldError::InterfaceInvalid(interface.to_string()))?;
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(HeraldError::InterfaceInvalid(interface.to_string()));
+    }
+    let ifindex = ifindex as i32;
+
+    let raw = unsafe {
+        libc::socket(
+            libc::AF_PACKET,
+            socket_type | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            i32::from(protocol.to_be()),
+        )
+    };
+    if raw < 0 {
+        return Err(SocketError::CreateSocket(io::Error::last_os_error()).into());
+    }
+    // The descriptor was just created and is owned by nothing else
+    let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+
+    let addr = link_layer_address(ifindex, protocol, [0; 6]);
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(SocketError::BindSocket(io::Error::last_os_error()).into());
+    }
+
+    Ok((fd, ifindex))
+}
+
+/// Link-layer address of `mac` on `ifindex`, with `protocol` in host order.
+pub(crate) fn link_layer_address(ifindex: i32, protocol: u16, mac: [u8; 6]) -> libc::sockaddr_ll {
+    let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = protocol.to_be();
+    addr.sll_ifindex = ifindex;
+    addr.sll_halen = 6;
+    addr.sll_addr[..6].copy_from_slice(&mac);
+    addr
+}
+
+/// Builds an Ethernet frame carrying `payload` in an IPv4/UDP datagram.
+pub fn build_udp_frame(
+    src_mac: [u8; 6],
+    dst_mac: [u8; 6],
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = UDP_HEADER_LEN + payload.len();
+    let ip_len = IPV4_HEADER_LEN + udp_len;
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER_LEN + ip_len);
+
+    // Ethernet
+    frame.extend_from_slice(&dst_mac);
+    frame.extend_from_slice(&src_mac);
+    frame.extend_from_slice(&(libc::ETH_P_IP as u16).to_be_bytes());
+
+    // IPv4
+    let mut ip = [0u8; IPV4_HEADER_LEN];
+    ip[0] = 0x45; // version 4, 5 words
+    ip[2..4].copy_from_slice(&(ip_len as u16).to_be_bytes());
+    ip[8] = DEFAULT_TTL;
+    ip[9] = IPPROTO_UDP;
+    ip[12..16].copy_from_slice(&src.ip().octets());
+    ip[16..20].copy_from_slice(&dst.ip().octets());
+    let ip_checksum = checksum(&[&ip]);
+    ip[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+    frame.extend_from_slice(&ip);
+
+    // UDP
+    let mut udp = [0u8; UDP_HEADER_LEN];
+    udp[0..2].copy_from_slice(&src.port().to_be_bytes());
+    udp[2..4].copy_from_slice(&dst.port().to_be_bytes());
+    udp[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    let pseudo = pseudo_header(*src.ip(), *dst.ip(), udp_len as u16);
+    let udp_checksum = match checksum(&[&pseudo, &udp, payload]) {
+        0 => 0xffff, // zero means "no checksum"
+        sum => sum,
+    };
+    udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+    frame.extend_from_slice(&udp);
+    frame.extend_from_slice(payload);
+
+    frame
+}
+
+/// Validates an Ethernet/IPv4/UDP frame addressed to `port` and returns the
+/// sender and the UDP payload.
+pub fn parse_udp_frame(frame: &[u8], port: u16) -> Option<(SocketAddrV4, &[u8])> {
+    if frame.get(12..14)? != (libc::ETH_P_IP as u16).to_be_bytes() {
+        return None;
+    }
+    let ip = frame.get(ETHERNET_HEADER_LEN..)?;
+
+    // IPv4 header, which may carry options
+    let header_len = usize::from(ip.first()? & 0x0f) * 4;
+    if ip[0] >> 4 != 4 || header_len < IPV4_HEADER_LEN || ip.len() < header_len {
+        return None;
+    }
+    let total_len = usize::from(u16::from_be_bytes([ip[2], ip[3]]));
+    if total_len < header_len + UDP_HEADER_LEN || ip.len() < total_len {
+        return None;
+    }
+    let fragment = u16::from_be_bytes([ip[6], ip[7]]);
+    if fragment & 0x3fff != 0 || ip[9] != IPPROTO_UDP || checksum(&[&ip[..header_len]]) != 0 {
+        return None;
+    }
+    let src_ip = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+    let dst_ip = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+
+    // UDP header; anything after the IPv4 total length is link padding
+    let udp = &ip[header_len..total_len];
+    let src_port = u16::from_be_bytes([udp[0], udp[1]]);
+    let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
+    let udp_len = usize::from(u16::from_be_bytes([udp[4], udp[5]]));
+    if dst_port != port || udp_len < UDP_HEADER_LEN || udp_len > udp.len() {
+        return None;
+    }
+    let udp = &udp[..udp_len];
+    let udp_checksum = u16::from_be_bytes([udp[6], udp[7]]);
+    if udp_checksum != 0 {
+        let pseudo = pseudo_header(src_ip, dst_ip, udp_len as u16);
+        if checksum(&[&pseudo, udp]) != 0 {
+            return None;
+        }
+    }
+
+    Some((SocketAddrV4::new(src_ip, src_port), &udp[UDP_HEADER_LEN..]))
+}
+
+/// IPv4 pseudo-header covered by the UDP checksum.
+fn pseudo_header(src: Ipv4Addr, dst: Ipv4Addr, udp_len: u16) -> [u8; 12] {
+    let mut pseudo = [0u8; 12];
+    pseudo[0..4].copy_from_slice(&src.octets());
+    pseudo[4..8].copy_from_slice(&dst.octets());
+    pseudo[9] = IPPROTO_UDP;
+    pseudo[10..12].copy_from_slice(&udp_len.to_be_bytes());
+    pseudo
+}
+
+/// Internet checksum (RFC 1071) over the concatenation of `parts`.
+///
+/// Every part except the last must have an even length.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        let mut words = part.chunks_exact(2);
+        for word in &mut words {
+            sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+        }
+        if let [last] = words.remainder() {
+            sum += u32::from(*last) << 8;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Classic BPF program accepting unfragmented IPv4 UDP datagrams to `port`.
+fn udp_port_filter(port: u16) -> [libc::sock_filter; 11] {
+    const fn stmt(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+    const fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    [
+        // Ethertype is IPv4
+        stmt(libc::BPF_LD | libc::BPF_H | libc::BPF_ABS, 12),
+        jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            libc::ETH_P_IP as u32,
+            0,
+            8,
+        ),
+        // Protocol is UDP
+        stmt(libc::BPF_LD | libc::BPF_B | libc::BPF_ABS, 23),
+        jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            u32::from(IPPROTO_UDP),
+            0,
+            6,
+        ),
+        // Not a fragment
+        stmt(libc::BPF_LD | libc::BPF_H | libc::BPF_ABS, 20),
+        jump(libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K, 0x1fff, 4, 0),
+        // X = IPv4 header length
+        stmt(
+            libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH,
+            ETHERNET_HEADER_LEN as u32,
+        ),
+        // Destination port
+        stmt(
+            libc::BPF_LD | libc::BPF_H | libc::BPF_IND,
+            ETHERNET_HEADER_LEN as u32 + 2,
+        ),
+        jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            u32::from(port),
+            0,
+            1,
+        ),
+        stmt(libc::BPF_RET | libc::BPF_K, u32::MAX),
+        stmt(libc::BPF_RET | libc::BPF_K, 0),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_MAC: [u8; 6] = [0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4];
+    const SERVER_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    fn server_reply(payload: &[u8]) -> Vec<u8> {
+        build_udp_frame(
+            SERVER_MAC,
+            CLIENT_MAC,
+            SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 1), 67),
+            SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 100), 68),
+            payload,
+        )
+    }
+
+    #[test]
+    fn test_frame_headers() {
+        let frame = build_udp_frame(
+            CLIENT_MAC,
+            BROADCAST_MAC,
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 68),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, 67),
+            b"dhcp",
+        );
+
+        assert_eq!(
+            frame.len(),
+            ETHERNET_HEADER_LEN + IPV4_HEADER_LEN + UDP_HEADER_LEN + 4
+        );
+        assert_eq!(&frame[0..6], &BROADCAST_MAC);
+        assert_eq!(&frame[6..12], &CLIENT_MAC);
+
+        // A correct checksum makes the header sum to zero
+        let ip = &frame[ETHERNET_HEADER_LEN..ETHERNET_HEADER_LEN + IPV4_HEADER_LEN];
+        assert_eq!(checksum(&[ip]), 0);
+        assert_eq!(&ip[16..20], &[255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_parse_roundtrip() {
+        // An odd-length payload exercises checksum padding
+        let mut frame = server_reply(b"offer");
+        frame.extend_from_slice(&[0; 6]); // Ethernet padding
+
+        let (src, payload) = parse_udp_frame(&frame, 68).unwrap();
+        assert_eq!(src, SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 1), 67));
+        assert_eq!(payload, b"offer");
+        assert_eq!(parse_udp_frame(&frame, 546), None);
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_checksums() {
+        let frame = server_reply(b"offer");
+
+        let mut corrupted = frame.clone();
+        corrupted[ETHERNET_HEADER_LEN + 8] ^= 1; // TTL
+        assert_eq!(parse_udp_frame(&corrupted, 68), None);
+
+        let mut corrupted = frame.clone();
+        *corrupted.last_mut().unwrap() ^= 1; // payload
+        assert_eq!(parse_udp_frame(&corrupted, 68), None);
+
+        // A zero UDP checksum means the sender did not compute one
+        let mut unchecked = frame;
+        *unchecked.last_mut().unwrap() ^= 1;
+        let offset = ETHERNET_HEADER_LEN + IPV4_HEADER_LEN + 6;
+        unchecked[offset..offset + 2].copy_from_slice(&[0, 0]);
+        assert!(parse_udp_frame(&unchecked, 68).is_some());
+    }
+
+    #[test]
+    fn test_parse_rejects_truncated_frames() {
+        let frame = server_reply(b"offer");
+        for len in 0..frame.len() {
+            assert_eq!(parse_udp_frame(&frame[..len], 68), None);
+        }
+    }
+}
@@ -0,0 +1,596 @@
+//! Resolver configuration from option 6
+//!
+//! DNS servers are handed to `resolvconf`, or written as per-interface
+//! fragments that are merged into `/etc/resolv.conf`, ahead of the original
+//! file's own nameservers, `search`, `options` and other settings. The
+//! original file is backed up before herald first replaces it and restored
+//! once no interface has DNS servers left.
+
+use crate::error::HeraldError;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    net::Ipv4Addr,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+};
+use tracing::{info, warn};
+
+/// Resolver configuration file.
+pub const DEFAULT_RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Directory holding the per-interface fragments and the backup.
+pub const DEFAULT_STATE_DIR: &str = "/run/herald/resolv";
+
+/// Directory holding the state of each named network namespace, by name.
+pub const NETNS_STATE_DIR: &str = "/run/herald/netns";
+
+/// Priority of an interface's nameservers when none is configured; lower
+/// values are listed first.
+pub const DEFAULT_DNS_PRIORITY: u32 = 100;
+
+/// First line of every file herald generates.
+const HEADER: &str = "# Generated by herald DHCP client\n";
+
+/// Directory systemd-resolved keeps its resolv.conf variants in.
+const RESOLVED_DIR: &str = "/run/systemd/resolve/";
+
+/// How DNS servers reach the resolver.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DnsStrategy {
+    /// Leave a systemd-resolved stub alone, otherwise use `resolvconf` if
+    /// installed, otherwise merge
+    #[default]
+    Auto,
+    /// Merge per-interface fragments into resolv.conf
+    Merge,
+    /// Hand the servers to `resolvconf -a`
+    Resolvconf,
+    /// Never touch the resolver configuration
+    None,
+}
+
+impl FromStr for DnsStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(DnsStrategy::Auto),
+            "merge" => Ok(DnsStrategy::Merge),
+            "resolvconf" => Ok(DnsStrategy::Resolvconf),
+            "none" => Ok(DnsStrategy::None),
+            _ => Err(format!(
+                "unknown DNS strategy '{s}' (expected auto, merge, resolvconf or none)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DnsStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DnsStrategy::Auto => "auto",
+            DnsStrategy::Merge => "merge",
+            DnsStrategy::Resolvconf => "resolvconf",
+            DnsStrategy::None => "none",
+        })
+    }
+}
+
+/// One interface's share of the resolver configuration.
+#[derive(Debug, Clone)]
+pub struct ResolvConf {
+    strategy: DnsStrategy,
+    interface: String,
+    priority: u32,
+    resolv_conf: PathBuf,
+    state_dir: PathBuf,
+}
+
+impl ResolvConf {
+    pub fn new(strategy: DnsStrategy, interface: &str, priority: u32) -> Self {
+        Self {
+            strategy,
+            interface: interface.to_string(),
+            priority,
+            resolv_conf: PathBuf::from(DEFAULT_RESOLV_CONF),
+            state_dir: PathBuf::from(DEFAULT_STATE_DIR),
+        }
+    }
+
+    /// The resolv.conf this interface's nameservers go to.
+    pub fn path(&self) -> &Path {
+        &self.resolv_conf
+    }
+
+    /// Use `resolv_conf` and `state_dir` instead of the system paths.
+    pub fn with_paths(mut self, resolv_conf: &Path, state_dir: &Path) -> Self {
+        self.resolv_conf = resolv_conf.to_path_buf();
+        self.state_dir = state_dir.to_path_buf();
+        self
+    }
+
+    /// Publish `servers` as this interface's nameservers.
+    pub fn apply(&self, servers: &[Ipv4Addr]) -> Result<(), HeraldError> {
+        match self.strategy() {
+            DnsStrategy::Merge => {
+                info!(
+                    "Merging DNS servers {:?} for {} into {}",
+                    servers,
+                    self.interface,
+                    self.resolv_conf.display()
+                );
+                let _lock = self.lock()?;
+                if self.fragments()?.is_empty() {
+                    self.backup()?;
+                }
+                let fragment = format!("# priority {}\n{}", self.priority, render(servers));
+                std::fs::write(self.fragment_path(), fragment).map_err(HeraldError::Io)?;
+                if let Some(dir) = self.resolv_conf.parent() {
+                    std::fs::create_dir_all(dir).map_err(HeraldError::Io)?;
+                }
+                self.merge()
+            }
+            DnsStrategy::Resolvconf => {
+                info!("Passing DNS servers {:?} to resolvconf", servers);
+                let contents = format!("{HEADER}{}", render(servers));
+                resolvconf(&["-a", &self.record_name()], Some(&contents))
+            }
+            DnsStrategy::Auto | DnsStrategy::None => {
+                info!("Leaving resolver configuration alone");
+                Ok(())
+            }
+        }
+    }
+
+    /// Withdraw this interface's nameservers, restoring the original
+    /// resolv.conf once no interface has any left.
+    pub fn clear(&self) -> Result<(), HeraldError> {
+        match self.strategy() {
+            DnsStrategy::Merge => {
+                let _lock = self.lock()?;
+                match std::fs::remove_file(self.fragment_path()) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+                    Err(e) => return Err(HeraldError::Io(e)),
+                }
+                if self.fragments()?.is_empty() {
+                    self.restore()
+                } else {
+                    self.merge()
+                }
+            }
+            DnsStrategy::Resolvconf => resolvconf(&["-d", &self.record_name()], None),
+            DnsStrategy::Auto | DnsStrategy::None => Ok(()),
+        }
+    }
+
+    /// The configured strategy, with `Auto` resolved against the system
+    pub(crate) fn strategy(&self) -> DnsStrategy {
+        if self.strategy != DnsStrategy::Auto {
+            return self.strategy;
+        }
+        if is_resolved_stub(&self.resolv_conf) {
+            DnsStrategy::None
+        } else if find_in_path("resolvconf").is_some() {
+            DnsStrategy::Resolvconf
+        } else {
+            DnsStrategy::Merge
+        }
+    }
+
+    /// Name of this interface's record in resolvconf
+    fn record_name(&self) -> String {
+        format!("{}.dhcp", self.interface)
+    }
+
+    fn fragment_path(&self) -> PathBuf {
+        self.state_dir.join(format!("{}.conf", self.interface))
+    }
+
+    fn backup_path(&self) -> PathBuf {
+        self.state_dir.join("resolv.conf.orig")
+    }
+
+    fn backup_link_path(&self) -> PathBuf {
+        self.state_dir.join("resolv.conf.orig.link")
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        self.state_dir.join("merge.lock")
+    }
+
+    /// Lock the fragments and resolv.conf until the returned file is closed.
+    /// The herald processes of all interfaces share them, so the lock is an
+    /// `flock` on a file in the state directory.
+    fn lock(&self) -> Result<File, HeraldError> {
+        std::fs::create_dir_all(&self.state_dir).map_err(HeraldError::Io)?;
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.lock_path())
+            .map_err(HeraldError::Io)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } < 0 {
+            return Err(HeraldError::Io(io::Error::last_os_error()));
+        }
+        Ok(file)
+    }
+
+    /// Fragments of every interface as (priority, interface, servers)
+    fn fragments(&self) -> Result<Vec<(u32, String, Vec<Ipv4Addr>)>, HeraldError> {
+        let entries = match std::fs::read_dir(&self.state_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(HeraldError::Io(e)),
+        };
+
+        let mut fragments = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(HeraldError::Io)?.path();
+            let Some(interface) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".conf"))
+            else {
+                continue;
+            };
+            let contents = std::fs::read_to_string(&path).map_err(HeraldError::Io)?;
+            let (priority, servers) = parse_fragment(&contents);
+            fragments.push((priority, interface.to_string(), servers));
+        }
+        fragments.sort();
+        Ok(fragments)
+    }
+
+    /// Write resolv.conf from every interface's fragment, by priority,
+    /// followed by what the original file set itself
+    fn merge(&self) -> Result<(), HeraldError> {
+        let mut servers: Vec<Ipv4Addr> = Vec::new();
+        for (_, _, fragment) in self.fragments()? {
+            for server in fragment {
+                if !servers.contains(&server) {
+                    servers.push(server);
+                }
+            }
+        }
+        let original = self.original()?.unwrap_or_default();
+        let contents = format!(
+            "{HEADER}{}{}",
+            render(&servers),
+            original_settings(&original, &servers)
+        );
+        replace_file(&self.resolv_conf, &contents)
+    }
+
+    /// Contents of the resolv.conf saved by `backup`, following a saved link
+    fn original(&self) -> Result<Option<String>, HeraldError> {
+        if let Ok(target) = std::fs::read_to_string(self.backup_link_path()) {
+            let target = self
+                .resolv_conf
+                .parent()
+                .unwrap_or(Path::new("/"))
+                .join(target);
+            return Ok(std::fs::read_to_string(target).ok());
+        }
+
+        match std::fs::read_to_string(self.backup_path()) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(HeraldError::Io(e)),
+        }
+    }
+
+    /// Save the current resolv.conf, unless it is herald's own output
+    fn backup(&self) -> Result<(), HeraldError> {
+        std::fs::create_dir_all(&self.state_dir).map_err(HeraldError::Io)?;
+
+        if let Ok(target) = std::fs::read_link(&self.resolv_conf) {
+            info!("Backing up resolv.conf link to {}", target.display());
+            let target = target.to_string_lossy().into_owned();
+            return std::fs::write(self.backup_link_path(), target).map_err(HeraldError::Io);
+        }
+
+        match std::fs::read_to_string(&self.resolv_conf) {
+            Ok(contents) if contents.starts_with(HEADER) => Ok(()),
+            Ok(contents) => {
+                info!("Backing up {}", self.resolv_conf.display());
+                std::fs::write(self.backup_path(), contents).map_err(HeraldError::Io)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(HeraldError::Io(e)),
+        }
+    }
+
+    /// Put back the resolv.conf saved by `backup`, or remove herald's if
+    /// there was none
+    fn restore(&self) -> Result<(), HeraldError> {
+        if let Ok(target) = std::fs::read_to_string(self.backup_link_path()) {
+            info!("Restoring resolv.conf link to {}", target);
+            remove_if_exists(&self.resolv_conf)?;
+            std::os::unix::fs::symlink(&target, &self.resolv_conf).map_err(HeraldError::Io)?;
+            return remove_if_exists(&self.backup_link_path());
+        }
+
+        match std::fs::read_to_string(self.backup_path()) {
+            Ok(contents) => {
+                info!("Restoring {}", self.resolv_conf.display());
+                replace_file(&self.resolv_conf, &contents)?;
+                remove_if_exists(&self.backup_path())
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => remove_if_exists(&self.resolv_conf),
+            Err(e) => Err(HeraldError::Io(e)),
+        }
+    }
+}
+
+/// `nameserver` lines for `servers`
+fn render(servers: &[Ipv4Addr]) -> String {
+    servers
+        .iter()
+        .map(|server| format!("nameserver {server}\n"))
+        .collect()
+}
+
+/// Lines of the original resolv.conf kept after herald's nameservers: its own
+/// nameservers, unless among `servers`, then its other settings such as
+/// `search` and `options`. Comments are dropped.
+fn original_settings(original: &str, servers: &[Ipv4Addr]) -> String {
+    let (nameservers, settings): (Vec<&str>, Vec<&str>) = original
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with(['#', ';']))
+        .partition(|line| line.split_whitespace().next() == Some("nameserver"));
+
+    let nameservers = nameservers.into_iter().filter(|line| {
+        !line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|server| server.parse::<Ipv4Addr>().ok())
+            .is_some_and(|server| servers.contains(&server))
+    });
+    nameservers
+        .chain(settings)
+        .map(|line| format!("{line}\n"))
+        .collect()
+}
+
+/// Priority and servers of a fragment written by `ResolvConf::apply`
+fn parse_fragment(contents: &str) -> (u32, Vec<Ipv4Addr>) {
+    let mut priority = DEFAULT_DNS_PRIORITY;
+    let mut servers = Vec::new();
+    for line in contents.lines() {
+        if let Some(value) = line.strip_prefix("# priority ") {
+            priority = value.trim().parse().unwrap_or(priority);
+        } else if let Some(value) = line.strip_prefix("nameserver ") {
+            servers.extend(value.trim().parse::<Ipv4Addr>().ok());
+        }
+    }
+    (priority, servers)
+}
+
+/// Whether `path` is a link into systemd-resolved's runtime directory
+fn is_resolved_stub(path: &Path) -> bool {
+    std::fs::read_link(path)
+        .map(|target| {
+            let target = path.parent().unwrap_or(Path::new("/")).join(target);
+            target.starts_with(RESOLVED_DIR)
+        })
+        .unwrap_or(false)
+}
+
+/// Full path of `program` if it is an executable on `PATH`
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Replace `path` atomically with `contents`, replacing a symlink rather than
+/// writing through it. The temporary file is named after the process so that
+/// several herald instances do not write to the same one.
+fn replace_file(path: &Path, contents: &str) -> Result<(), HeraldError> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{file_name}.{}.herald", std::process::id()));
+    std::fs::write(&temp, contents).map_err(HeraldError::Io)?;
+    std::fs::rename(&temp, path).map_err(HeraldError::Io)
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), HeraldError> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(HeraldError::Io(e)),
+        _ => Ok(()),
+    }
+}
+
+/// Run `resolvconf` with `args`, feeding it `input`
+fn resolvconf(args: &[&str], input: Option<&str>) -> Result<(), HeraldError> {
+    let mut child = Command::new("resolvconf")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(HeraldError::Io)?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(input.unwrap_or_default().as_bytes())
+            .map_err(HeraldError::Io)?;
+    }
+
+    let output = child.wait_with_output().map_err(HeraldError::Io)?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        warn!("resolvconf {} failed: {}", args.join(" "), stderr.trim());
+        return Err(HeraldError::Critical(format!(
+            "resolvconf {} failed: {}",
+            args.join(" "),
+            stderr.trim()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolv_conf(dir: &Path, interface: &str, priority: u32) -> ResolvConf {
+        ResolvConf::new(DnsStrategy::Merge, interface, priority)
+            .with_paths(&dir.join("resolv.conf"), &dir.join("state"))
+    }
+
+    #[test]
+    fn test_merge_by_priority_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("resolv.conf");
+        std::fs::write(&path, "nameserver 192.0.2.53\n").unwrap();
+
+        let eth0 = resolv_conf(dir.path(), "eth0", 200);
+        let eth1 = resolv_conf(dir.path(), "eth1", 100);
+        eth0.apply(&[Ipv4Addr::new(10, 0, 0, 1)]).unwrap();
+        eth1.apply(&[Ipv4Addr::new(10, 1, 0, 1), Ipv4Addr::new(10, 0, 0, 1)])
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{HEADER}nameserver 10.1.0.1\nnameserver 10.0.0.1\nnameserver 192.0.2.53\n")
+        );
+
+        // Releasing one interface keeps the other's servers
+        eth1.clear().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{HEADER}nameserver 10.0.0.1\nnameserver 192.0.2.53\n")
+        );
+
+        eth0.clear().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "nameserver 192.0.2.53\n"
+        );
+        eth0.clear().unwrap();
+    }
+
+    #[test]
+    fn test_merge_keeps_original_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("resolv.conf");
+        let original = "# static resolver\nsearch example.com\nnameserver 10.0.0.1\n\
+                        nameserver 2001:db8::53\noptions ndots:2 edns0\n";
+        std::fs::write(&path, original).unwrap();
+
+        let eth0 = resolv_conf(dir.path(), "eth0", DEFAULT_DNS_PRIORITY);
+        eth0.apply(&[Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)])
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!(
+                "{HEADER}nameserver 10.0.0.1\nnameserver 10.0.0.2\nnameserver 2001:db8::53\n\
+                 search example.com\noptions ndots:2 edns0\n"
+            )
+        );
+
+        eth0.clear().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), original);
+    }
+
+    #[test]
+    fn test_merge_lock_excludes_other_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        let eth0 = resolv_conf(dir.path(), "eth0", DEFAULT_DNS_PRIORITY);
+        let lock = eth0.lock().unwrap();
+
+        // Another process opens the lock file on its own
+        let other = File::open(eth0.lock_path()).unwrap();
+        let ret = unsafe { libc::flock(other.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        assert_eq!(ret, -1);
+        assert_eq!(
+            io::Error::last_os_error().raw_os_error(),
+            Some(libc::EWOULDBLOCK)
+        );
+
+        drop(lock);
+        let ret = unsafe { libc::flock(other.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        assert_eq!(ret, 0);
+    }
+
+    #[test]
+    fn test_concurrent_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("resolv.conf");
+
+        std::thread::scope(|scope| {
+            for i in 0..8u8 {
+                let dir = dir.path();
+                scope.spawn(move || {
+                    let client = resolv_conf(dir, &format!("eth{i}"), DEFAULT_DNS_PRIORITY);
+                    for _ in 0..20 {
+                        client.apply(&[Ipv4Addr::new(10, i, 0, 1)]).unwrap();
+                    }
+                });
+            }
+        });
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        for i in 0..8 {
+            assert!(contents.contains(&format!("nameserver 10.{i}.0.1\n")));
+        }
+    }
+
+    #[test]
+    fn test_symlink_is_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("resolv.conf");
+        std::os::unix::fs::symlink("/etc/resolv.conf.static", &path).unwrap();
+
+        let eth0 = resolv_conf(dir.path(), "eth0", DEFAULT_DNS_PRIORITY);
+        eth0.apply(&[Ipv4Addr::new(10, 0, 0, 1)]).unwrap();
+        assert!(!path.is_symlink());
+
+        eth0.clear().unwrap();
+        assert_eq!(
+            std::fs::read_link(&path).unwrap(),
+            Path::new("/etc/resolv.conf.static")
+        );
+    }
+
+    #[test]
+    fn test_missing_file_is_removed_on_clear() {
+        let dir = tempfile::tempdir().unwrap();
+        let eth0 = resolv_conf(dir.path(), "eth0", DEFAULT_DNS_PRIORITY);
+
+        eth0.apply(&[Ipv4Addr::new(10, 0, 0, 1)]).unwrap();
+        assert!(dir.path().join("resolv.conf").exists());
+        eth0.clear().unwrap();
+        assert!(!dir.path().join("resolv.conf").exists());
+    }
+
+    #[test]
+    fn test_auto_leaves_resolved_stub_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("resolv.conf");
+        std::os::unix::fs::symlink("/run/systemd/resolve/stub-resolv.conf", &path).unwrap();
+
+        let eth0 = ResolvConf::new(DnsStrategy::Auto, "eth0", DEFAULT_DNS_PRIORITY)
+            .with_paths(&path, &dir.path().join("state"));
+        assert_eq!(eth0.strategy(), DnsStrategy::None);
+        eth0.apply(&[Ipv4Addr::new(10, 0, 0, 1)]).unwrap();
+        assert!(path.is_symlink());
+    }
+
+    #[test]
+    fn test_strategy_round_trip() {
+        for strategy in [
+            DnsStrategy::Auto,
+            DnsStrategy::Merge,
+            DnsStrategy::Resolvconf,
+            DnsStrategy::None,
+        ] {
+            assert_eq!(strategy.to_string().parse::<DnsStrategy>(), Ok(strategy));
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! Shared DHCP client socket
+//!
+//! A single UDP socket on port 68 serves every interface instead of one
+//! `SO_BINDTODEVICE` socket each. `IP_PKTINFO` reports the interface a reply
+//! arrived on and selects the interface a message leaves through. Replies
+//! are handed to the endpoint registered for the ingress ifindex whose last
+//! transaction ID matches.
+//!
+//! Only the UDP socket is shared. Each client still opens its own
+//! `AF_PACKET` socket for the exchange before it is bound, and its own
+//! rtnetlink socket to follow the carrier.
+
+use super::SocketError;
+use socket2::{Domain, Socket, Type};
+use std::{
+    collections::HashMap,
+    io, mem,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    os::fd::AsRawFd,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    io::Interest,
+    net::UdpSocket,
+    sync::{self, mpsc},
+    task::JoinHandle,
+};
+
+/// Largest datagram read from the socket.
+const MAX_DATAGRAM_LEN: usize = 1500;
+/// Replies queued per endpoint before further ones are dropped.
+const ENDPOINT_QUEUE_LEN: usize = 16;
+/// Offset of the transaction ID in a BOOTP message.
+const XID_OFFSET: usize = 4;
+
+/// A reply waiting for its endpoint
+struct Datagram {
+    data: Vec<u8>,
+    source: SocketAddr,
+}
+
+/// An endpoint as seen by the receive task
+struct Registration {
+    id: u64,
+    xid: Arc<AtomicU32>,
+    queue: mpsc::Sender<Datagram>,
+}
+
+struct Shared {
+    socket: UdpSocket,
+    /// Endpoints by ifindex
+    endpoints: Mutex<HashMap<u32, Vec<Registration>>>,
+    next_id: AtomicU64,
+}
+
+/// Stops the receive task once the last handle is gone
+struct ReceiveTask(JoinHandle<()>);
+
+impl Drop for ReceiveTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// UDP socket shared by the clients of many interfaces.
+#[derive(Clone)]
+pub struct SharedSocket {
+    shared: Arc<Shared>,
+    _task: Arc<ReceiveTask>,
+}
+
+impl SharedSocket {
+    /// Binds the shared socket to `port` on all addresses and starts
+    /// demultiplexing replies. Must be called within a tokio runtime.
+    pub fn open(port: u16) -> Result<Self, SocketError> {
+        let socket =
+            Socket::new(Domain::IPV4, Type::DGRAM, None).map_err(SocketError::CreateSocket)?;
+        socket
+            .set_broadcast(true)
+            .map_err(SocketError::SetBroadcast)?;
+        socket
+            .set_reuse_address(true)
+            .map_err(SocketError::SetReuseAddress)?;
+        set_option(&socket, libc::IPPROTO_IP, libc::IP_PKTINFO).map_err(SocketError::SetPktInfo)?;
+        let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+        socket.bind(&addr.into()).map_err(SocketError::BindSocket)?;
+        socket
+            .set_nonblocking(true)
+            .map_err(SocketError::SetNonBlocking)?;
+        let socket = UdpSocket::from_std(socket.into()).map_err(SocketError::ConvertToTokio)?;
+
+        let shared = Arc::new(Shared {
+            socket,
+            endpoints: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        });
+        let task = tokio::spawn(receive(shared.clone()));
+        Ok(Self {
+            shared,
+            _task: Arc::new(ReceiveTask(task)),
+        })
+    }
+
+    /// Registers an endpoint for the interface with index `ifindex`.
+    pub fn endpoint(&self, ifindex: u32) -> SharedEndpoint {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let xid = Arc::new(AtomicU32::new(0));
+        let (queue, replies) = mpsc::channel(ENDPOINT_QUEUE_LEN);
+        self.shared
+            .endpoints
+            .lock()
+            .unwrap()
+            .entry(ifindex)
+            .or_default()
+            .push(Registration {
+                id,
+                xid: xid.clone(),
+                queue,
+            });
+
+        SharedEndpoint {
+            socket: self.clone(),
+            ifindex,
+            id,
+            xid,
+            replies: sync::Mutex::new(replies),
+        }
+    }
+
+    /// Local address of the socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+}
+
+/// One interface's view of a [`SharedSocket`]. Unregisters when dropped.
+pub struct SharedEndpoint {
+    socket: SharedSocket,
+    ifindex: u32,
+    id: u64,
+    /// Transaction ID of the last message sent
+    xid: Arc<AtomicU32>,
+    replies: sync::Mutex<mpsc::Receiver<Datagram>>,
+}
+
+impl SharedEndpoint {
+    /// Sends `packet` to `dest` through this endpoint's interface. Replies
+    /// are accepted for the packet's transaction ID from now on.
+    pub async fn send_to(&self, packet: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        if let Some(xid) = transaction_id(packet) {
+            self.xid.store(xid, Ordering::Relaxed);
+        }
+        self.socket.shared.send(packet, dest, self.ifindex).await
+    }
+
+    /// Receives the next reply for this endpoint, truncated to `buf`.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let datagram = self
+            .replies
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "shared socket closed"))?;
+        let len = datagram.data.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram.data[..len]);
+        Ok((len, datagram.source))
+    }
+}
+
+impl Drop for SharedEndpoint {
+    fn drop(&mut self) {
+        let mut endpoints = self.socket.shared.endpoints.lock().unwrap();
+        if let Some(registered) = endpoints.get_mut(&self.ifindex) {
+            registered.retain(|registration| registration.id != self.id);
+            if registered.is_empty() {
+                endpoints.remove(&self.ifindex);
+            }
+        }
+    }
+}
+
+/// The UDP socket a client sends and receives through after binding.
+pub enum ClientSocket {
+    /// Socket of its own, bound to the interface with `SO_BINDTODEVICE`
+    Device(UdpSocket),
+    /// Endpoint on a socket shared with other interfaces
+    Shared(SharedEndpoint),
+}
+
+impl ClientSocket {
+    pub async fn send_to(&self, packet: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        match self {
+            ClientSocket::Device(socket) => socket.send_to(packet, dest).await,
+            ClientSocket::Shared(endpoint) => endpoint.send_to(packet, dest).await,
+        }
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            ClientSocket::Device(socket) => socket.recv_from(buf).await,
+            ClientSocket::Shared(endpoint) => endpoint.recv_from(buf).await,
+        }
+    }
+}
+
+impl Shared {
+    /// Sends `packet` with `IP_PKTINFO` naming the outgoing interface
+    async fn send(&self, packet: &[u8], dest: SocketAddr, ifindex: u32) -> io::Result<usize> {
+        let SocketAddr::V4(dest) = dest else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DHCPv4 needs an IPv4 destination",
+            ));
+        };
+        let info = libc::in_pktinfo {
+            ipi_ifindex: ifindex as libc::c_int,
+            ipi_spec_dst: libc::in_addr { s_addr: 0 },
+            ipi_addr: libc::in_addr { s_addr: 0 },
+        };
+
+        self.socket
+            .async_io(Interest::WRITABLE, || {
+                let mut name = sockaddr_in(dest);
+                let mut iov = libc::iovec {
+                    iov_base: packet.as_ptr() as *mut libc::c_void,
+                    iov_len: packet.len(),
+                };
+                let mut control = ControlBuffer::new();
+                let mut msg = msghdr(&mut name, &mut iov, &mut control);
+                // The control buffer has room for the one in_pktinfo
+                unsafe {
+                    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                    (*cmsg).cmsg_level = libc::IPPROTO_IP;
+                    (*cmsg).cmsg_type = libc::IP_PKTINFO;
+                    (*cmsg).cmsg_len =
+                        libc::CMSG_LEN(mem::size_of::<libc::in_pktinfo>() as u32) as _;
+                    std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::in_pktinfo, info);
+                }
+                msg.msg_controllen = ControlBuffer::pktinfo_len() as _;
+
+                let ret = unsafe { libc::sendmsg(self.socket.as_raw_fd(), &msg, 0) };
+                if ret < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(ret as usize)
+                }
+            })
+            .await
+    }
+
+    /// Receives a datagram and the index of the interface it arrived on
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, u32)> {
+        self.socket
+            .async_io(Interest::READABLE, || {
+                let mut name: libc::sockaddr_in = unsafe { mem::zeroed() };
+                let mut iov = libc::iovec {
+                    iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: buf.len(),
+                };
+                let mut control = ControlBuffer::new();
+                let mut msg = msghdr(&mut name, &mut iov, &mut control);
+
+                let ret = unsafe { libc::recvmsg(self.socket.as_raw_fd(), &mut msg, 0) };
+                if ret < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                let mut ifindex = 0;
+                // The kernel filled in msg_controllen headers within the buffer
+                unsafe {
+                    let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+                    while !cmsg.is_null() {
+                        if (*cmsg).cmsg_level == libc::IPPROTO_IP
+                            && (*cmsg).cmsg_type == libc::IP_PKTINFO
+                        {
+                            let info: libc::in_pktinfo =
+                                std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const _);
+                            ifindex = info.ipi_ifindex as u32;
+                        }
+                        cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+                    }
+                }
+
+                let source = SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(name.sin_addr.s_addr)),
+                    u16::from_be(name.sin_port),
+                );
+                Ok((ret as usize, SocketAddr::V4(source), ifindex))
+            })
+            .await
+    }
+
+    /// Queues `packet` for the endpoint on `ifindex` waiting for its
+    /// transaction ID
+    fn deliver(&self, packet: &[u8], source: SocketAddr, ifindex: u32) {
+        let Some(xid) = transaction_id(packet) else {
+            tracing::debug!("Dropping short datagram from {}", source);
+            return;
+        };
+        let endpoints = self.endpoints.lock().unwrap();
+        let registration = endpoints.get(&ifindex).and_then(|registered| {
+            registered
+                .iter()
+                .find(|registration| registration.xid.load(Ordering::Relaxed) == xid)
+        });
+        let Some(registration) = registration else {
+            tracing::debug!(
+                "No client on ifindex {} for xid {:#010x}, dropping reply from {}",
+                ifindex,
+                xid,
+                source
+            );
+            return;
+        };
+
+        let datagram = Datagram {
+            data: packet.to_vec(),
+            source,
+        };
+        if registration.queue.try_send(datagram).is_err() {
+            tracing::debug!(
+                "Client on ifindex {} is not keeping up, dropping reply",
+                ifindex
+            );
+        }
+    }
+}
+
+/// Hands every received datagram to its endpoint
+async fn receive(shared: Arc<Shared>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+    loop {
+        match shared.recv(&mut buf).await {
+            Ok((len, source, ifindex)) => shared.deliver(&buf[..len], source, ifindex),
+            Err(e) => tracing::warn!("Shared socket receive error: {}", e),
+        }
+    }
+}
+
+/// Transaction ID of a BOOTP message
+fn transaction_id(packet: &[u8]) -> Option<u32> {
+    let xid = packet.get(XID_OFFSET..XID_OFFSET + 4)?;
+    Some(u32::from_be_bytes(xid.try_into().ok()?))
+}
+
+/// Control message buffer with room for one `in_pktinfo`, aligned for
+/// `cmsghdr`
+#[repr(C)]
+struct ControlBuffer {
+    _align: [libc::cmsghdr; 0],
+    data: [u8; 64],
+}
+
+impl ControlBuffer {
+    fn new() -> Self {
+        Self {
+            _align: [],
+            data: [0; 64],
+        }
+    }
+
+    /// Space taken by one `in_pktinfo` message
+    fn pktinfo_len() -> usize {
+        unsafe { libc::CMSG_SPACE(mem::size_of::<libc::in_pktinfo>() as u32) as usize }
+    }
+}
+
+fn msghdr(
+    name: &mut libc::sockaddr_in,
+    iov: &mut libc::iovec,
+    control: &mut ControlBuffer,
+) -> libc::msghdr {
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = name as *mut libc::sockaddr_in as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    msg.msg_iov = iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.data.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.data.len() as _;
+    msg
+}
+
+fn sockaddr_in(addr: SocketAddrV4) -> libc::sockaddr_in {
+    let mut sockaddr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+    sockaddr.sin_port = addr.port().to_be();
+    sockaddr.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+    sockaddr
+}
+
+fn set_option(socket: &Socket, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &1i32 as *const i32 as *const libc::c_void,
+            mem::size_of::<i32>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::netlink::{self, NetlinkHandle};
+    use std::time::Duration;
+
+    fn message(xid: u32) -> Vec<u8> {
+        let mut packet = vec![0u8; 240];
+        packet[XID_OFFSET..XID_OFFSET + 4].copy_from_slice(&xid.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_transaction_id() {
+        assert_eq!(transaction_id(&message(0x1234_5678)), Some(0x1234_5678));
+        assert_eq!(transaction_id(&[0; 7]), None);
+    }
+
+    #[test]
+    fn test_demultiplex_in_namespace() {
+        netlink::in_network_namespace(|| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let netlink = NetlinkHandle::open().unwrap();
+                let lo = netlink.link_index("lo").unwrap();
+                netlink.set_link_up(lo).unwrap();
+
+                let shared = SharedSocket::open(0).unwrap();
+                let port = shared.local_addr().unwrap().port();
+                let endpoint = shared.endpoint(lo);
+                let other = shared.endpoint(lo + 100);
+
+                let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let server_addr = server.local_addr().unwrap();
+                endpoint.send_to(&message(7), server_addr).await.unwrap();
+                let mut buf = [0u8; 1500];
+                let (len, client_addr) = server.recv_from(&mut buf).await.unwrap();
+                assert_eq!(len, 240);
+                assert_eq!(client_addr.port(), port);
+
+                // A stale xid is dropped, the current one delivered
+                server.send_to(&message(6), client_addr).await.unwrap();
+                server.send_to(&message(7), client_addr).await.unwrap();
+                let recv =
+                    tokio::time::timeout(Duration::from_secs(1), endpoint.recv_from(&mut buf));
+                let (len, source) = recv.await.unwrap().unwrap();
+                assert_eq!(transaction_id(&buf[..len]), Some(7));
+                assert_eq!(source, server_addr);
+
+                // Nothing reaches the endpoint of another interface
+                let recv =
+                    tokio::time::timeout(Duration::from_millis(50), other.recv_from(&mut buf));
+                assert!(recv.await.is_err());
+                let recv =
+                    tokio::time::timeout(Duration::from_millis(50), endpoint.recv_from(&mut buf));
+                assert!(recv.await.is_err());
+
+                // Dropped endpoints are unregistered
+                drop(other);
+                assert_eq!(shared.shared.endpoints.lock().unwrap().len(), 1);
+            });
+        });
+    }
+}
@@ -0,0 +1,130 @@
+//! Timezone configuration from option 101 (RFC 4833)
+//!
+//! The tz database name from the lease is applied by pointing `/etc/localtime`
+//! at the matching zoneinfo file.
+
+use crate::error::HeraldError;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Default location of the local time link.
+pub const LOCALTIME_PATH: &str = "/etc/localtime";
+
+/// Default location of the tz database.
+pub const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+
+/// Links the local time to a tz database zone.
+#[derive(Debug, Clone)]
+pub struct TimezoneLink {
+    localtime: PathBuf,
+    zoneinfo_dir: PathBuf,
+}
+
+impl Default for TimezoneLink {
+    fn default() -> Self {
+        Self::new(LOCALTIME_PATH, ZONEINFO_DIR)
+    }
+}
+
+impl TimezoneLink {
+    pub fn new(localtime: impl Into<PathBuf>, zoneinfo_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            localtime: localtime.into(),
+            zoneinfo_dir: zoneinfo_dir.into(),
+        }
+    }
+
+    /// Point the local time link at `zone` (e.g. `Europe/Zurich`).
+    ///
+    /// The zone name comes from the network, so it must be a relative path of
+    /// tz database characters that names an existing zoneinfo file.
+    pub fn apply(&self, zone: &str) -> Result<(), HeraldError> {
+        if !is_valid_zone_name(zone) {
+            return Err(HeraldError::Critical(format!(
+                "Refusing invalid timezone name '{zone}'"
+            )));
+        }
+
+        let target = self.zoneinfo_dir.join(zone);
+        if !target.is_file() {
+            return Err(HeraldError::Critical(format!(
+                "Timezone '{}' not found in {}",
+                zone,
+                self.zoneinfo_dir.display()
+            )));
+        }
+
+        if std::fs::read_link(&self.localtime).ok().as_deref() == Some(target.as_path()) {
+            return Ok(());
+        }
+
+        info!("Setting timezone to {}", zone);
+
+        // Swap the link atomically so the local time is never missing
+        let tmp = temporary_path(&self.localtime);
+        let _ = std::fs::remove_file(&tmp);
+        std::os::unix::fs::symlink(&target, &tmp).map_err(HeraldError::Io)?;
+        std::fs::rename(&tmp, &self.localtime).map_err(|e| {
+            let _ = std::fs::remove_file(&tmp);
+            HeraldError::Io(e)
+        })
+    }
+}
+
+/// Whether `zone` looks like a tz database name such as `America/Argentina/Salta`.
+fn is_valid_zone_name(zone: &str) -> bool {
+    !zone.is_empty()
+        && !zone.starts_with('/')
+        && zone
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+        && zone
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '_' | '-' | '+'))
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".herald-tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zone_name_validation() {
+        assert!(is_valid_zone_name("Europe/Zurich"));
+        assert!(is_valid_zone_name("Etc/GMT+5"));
+        assert!(is_valid_zone_name("UTC"));
+        assert!(!is_valid_zone_name(""));
+        assert!(!is_valid_zone_name("/etc/passwd"));
+        assert!(!is_valid_zone_name("../../etc/passwd"));
+        assert!(!is_valid_zone_name("Europe//Zurich"));
+        assert!(!is_valid_zone_name("Europe/Zurich\n"));
+    }
+
+    #[test]
+    fn test_apply_links_localtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let zoneinfo = dir.path().join("zoneinfo");
+        std::fs::create_dir_all(zoneinfo.join("Europe")).unwrap();
+        std::fs::write(zoneinfo.join("Europe/Zurich"), b"TZif").unwrap();
+        let localtime = dir.path().join("localtime");
+        std::fs::write(&localtime, b"old").unwrap();
+
+        let link = TimezoneLink::new(&localtime, &zoneinfo);
+        link.apply("Europe/Zurich").unwrap();
+        assert_eq!(
+            std::fs::read_link(&localtime).unwrap(),
+            zoneinfo.join("Europe/Zurich")
+        );
+
+        assert!(link.apply("Mars/Olympus_Mons").is_err());
+        assert_eq!(
+            std::fs::read_link(&localtime).unwrap(),
+            zoneinfo.join("Europe/Zurich")
+        );
+    }
+}
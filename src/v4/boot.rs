@@ -0,0 +1,228 @@
+//! Network boot information
+//!
+//! This module merges the BOOTP header fields (`siaddr`, `sname`, `file`)
+//! with the boot related options of a reply:
+//! - Option Overload (52, RFC 2132)
+//! - TFTP Server Name (66) and Bootfile Name (67)
+//! - TFTP Server Address (150, RFC 5859)
+
+use super::options::{decode_sub_options, SubOptions};
+use dhcproto::v4;
+use std::net::Ipv4Addr;
+
+/// Offset of the `sname` field in a DHCP message.
+const SNAME_RANGE: std::ops::Range<usize> = 44..108;
+
+/// Offset of the `file` field in a DHCP message.
+const FILE_RANGE: std::ops::Range<usize> = 108..236;
+
+/// Offset of the options field, after the magic cookie.
+const OPTIONS_OFFSET: usize = 240;
+
+/// Option Overload value: the `file` field carries options.
+const OVERLOAD_FILE: u8 = 1;
+
+/// Option Overload value: the `sname` field carries options.
+const OVERLOAD_SNAME: u8 = 2;
+
+/// Option code for TFTP Server Address (RFC 5859).
+const TFTP_SERVER_ADDRESS: u8 = 150;
+
+/// Boot parameters from a DHCP reply.
+///
+/// Options 66 and 67 take precedence over the `sname` and `file` header
+/// fields, which are only used when they are not overloaded with options.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BootInfo {
+    /// Next server to use in bootstrap (`siaddr`)
+    pub next_server: Option<Ipv4Addr>,
+    /// TFTP server name from option 66 or the `sname` field
+    pub tftp_server_name: Option<String>,
+    /// TFTP server addresses (option 150)
+    pub tftp_server_addresses: Vec<Ipv4Addr>,
+    /// Boot file name from option 67 or the `file` field
+    pub boot_file: Option<String>,
+}
+
+impl BootInfo {
+    /// Extracts boot information from the decoded message `msg` and its raw
+    /// bytes `data`. Returns `None` if the reply carries no boot information.
+    ///
+    /// dhcproto neither parses options from overloaded header fields nor
+    /// keeps more than one option 150 address, so both are read from `data`.
+    pub fn from_reply(msg: &v4::Message, data: &[u8]) -> Option<Self> {
+        let options = raw_options(data);
+        let overload = options
+            .get(&u8::from(v4::OptionCode::OptionOverload))
+            .and_then(|value| value.first().copied())
+            .unwrap_or(0);
+
+        let header_sname = (overload & OVERLOAD_SNAME == 0)
+            .then(|| data.get(SNAME_RANGE).and_then(header_string))
+            .flatten();
+        let header_file = (overload & OVERLOAD_FILE == 0)
+            .then(|| data.get(FILE_RANGE).and_then(header_string))
+            .flatten();
+
+        let option_string = |code: v4::OptionCode| {
+            options
+                .get(&u8::from(code))
+                .and_then(|value| header_string(value))
+        };
+
+        let tftp_server_addresses = options
+            .get(&TFTP_SERVER_ADDRESS)
+            .map(|value| {
+                value
+                    .chunks_exact(4)
+                    .map(|octets| Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let info = Self {
+            next_server: Some(msg.siaddr()).filter(|addr| !addr.is_unspecified()),
+            tftp_server_name: option_string(v4::OptionCode::TFTPServerName).or(header_sname),
+            tftp_server_addresses,
+            boot_file: option_string(v4::OptionCode::BootfileName).or(header_file),
+        };
+
+        (info != Self::default()).then_some(info)
+    }
+}
+
+/// Parses a UUID for option 97, with or without dashes
+/// (e.g. `8f0e4d1c-3b7a-4c55-9e2d-0a1b2c3d4e5f`).
+pub fn parse_uuid(s: &str) -> Result<[u8; 16], String> {
+    let hex: String = s.chars().filter(|&c| c != '-').collect();
+    if hex.len() != 32 || !hex.is_ascii() {
+        return Err(format!("invalid UUID '{s}'"));
+    }
+
+    let mut uuid = [0u8; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("invalid UUID '{s}'"))?;
+    }
+    Ok(uuid)
+}
+
+/// Parses the options field of `data`, followed by the `file` and `sname`
+/// fields if option 52 says they are overloaded (RFC 2131 section 4.1).
+fn raw_options(data: &[u8]) -> SubOptions {
+    let mut options = data
+        .get(OPTIONS_OFFSET..)
+        .and_then(decode_sub_options)
+        .unwrap_or_default();
+
+    let overload = options
+        .get(&u8::from(v4::OptionCode::OptionOverload))
+        .and_then(|value| value.first().copied())
+        .unwrap_or(0);
+
+    for (flag, range) in [(OVERLOAD_FILE, FILE_RANGE), (OVERLOAD_SNAME, SNAME_RANGE)] {
+        if overload & flag == 0 {
+            continue;
+        }
+        if let Some(extra) = data.get(range).and_then(decode_sub_options) {
+            // RFC 3396: split options are concatenated in field order
+            for (code, value) in extra {
+                options.entry(code).or_default().extend(value);
+            }
+        }
+    }
+
+    options
+}
+
+/// Reads a NUL-terminated header string, returning `None` if it is empty.
+fn header_string(bytes: &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    let value = String::from_utf8_lossy(&bytes[..end]).into_owned();
+    (!value.is_empty()).then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dhcproto::{Decodable, Decoder, Encodable, Encoder};
+
+    fn encode(msg: &v4::Message) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        msg.encode(&mut Encoder::new(&mut buffer)).unwrap();
+        buffer
+    }
+
+    fn decode(data: &[u8]) -> v4::Message {
+        v4::Message::decode(&mut Decoder::new(data)).unwrap()
+    }
+
+    #[test]
+    fn test_header_fields() {
+        let mut msg = v4::Message::default();
+        msg.set_siaddr(Ipv4Addr::new(10, 0, 0, 5))
+            .set_sname_str("tftp.example.net")
+            .set_fname_str("pxelinux.0");
+        let data = encode(&msg);
+
+        let info = BootInfo::from_reply(&decode(&data), &data).unwrap();
+        assert_eq!(info.next_server, Some(Ipv4Addr::new(10, 0, 0, 5)));
+        assert_eq!(info.tftp_server_name.as_deref(), Some("tftp.example.net"));
+        assert_eq!(info.boot_file.as_deref(), Some("pxelinux.0"));
+        assert!(info.tftp_server_addresses.is_empty());
+    }
+
+    #[test]
+    fn test_options_take_precedence() {
+        let mut msg = v4::Message::default();
+        msg.set_fname_str("header.efi");
+        msg.opts_mut()
+            .insert(v4::DhcpOption::BootfileName(b"option.efi".to_vec()));
+        msg.opts_mut()
+            .insert(v4::DhcpOption::TFTPServerName(b"boot.example.net".to_vec()));
+        let data = encode(&msg);
+
+        let info = BootInfo::from_reply(&decode(&data), &data).unwrap();
+        assert_eq!(info.boot_file.as_deref(), Some("option.efi"));
+        assert_eq!(info.tftp_server_name.as_deref(), Some("boot.example.net"));
+    }
+
+    #[test]
+    fn test_overloaded_file_field() {
+        let mut msg = v4::Message::default();
+        msg.opts_mut().insert(v4::DhcpOption::OptionOverload(1));
+        let mut data = encode(&msg);
+
+        // Place option 67 and a two-address option 150 in the file field
+        let mut file = vec![67, 8];
+        file.extend_from_slice(b"boot.efi");
+        file.extend_from_slice(&[150, 8, 10, 0, 0, 1, 10, 0, 0, 2, 255]);
+        data[FILE_RANGE.start..FILE_RANGE.start + file.len()].copy_from_slice(&file);
+
+        let info = BootInfo::from_reply(&decode(&data), &data).unwrap();
+        assert_eq!(info.boot_file.as_deref(), Some("boot.efi"));
+        assert_eq!(
+            info.tftp_server_addresses,
+            vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]
+        );
+    }
+
+    #[test]
+    fn test_no_boot_info() {
+        let msg = v4::Message::default();
+        let data = encode(&msg);
+        assert_eq!(BootInfo::from_reply(&decode(&data), &data), None);
+    }
+
+    #[test]
+    fn test_parse_uuid() {
+        let uuid = parse_uuid("8f0e4d1c-3b7a-4c55-9e2d-0a1b2c3d4e5f").unwrap();
+        assert_eq!(uuid[0], 0x8f);
+        assert_eq!(uuid[15], 0x5f);
+        assert_eq!(
+            parse_uuid("8f0e4d1c3b7a4c559e2d0a1b2c3d4e5f").unwrap(),
+            uuid
+        );
+        assert!(parse_uuid("8f0e4d1c").is_err());
+    }
+}
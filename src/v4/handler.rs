@@ -3,21 +3,82 @@
 //! This module implements the DHCPv4 client state machine that handles
 //! the complete DORA (Discover, Offer, Request, Acknowledge) process.
 
-use super::message::build_dhcp_discover;
+use super::{
+    boot::BootInfo,
+    message::{
+        build_dhcp_discover, build_dhcp_init_reboot_request, build_dhcp_renew_request,
+        MessageOptions,
+    },
+    options::{
+        decode_sub_options, decode_timezone, decode_v6only_wait, decode_vendor_identifying_info,
+        validate_captive_portal, IPV6_ONLY_PREFERRED, POSIX_TIMEZONE, TZDB_TIMEZONE,
+        VENDOR_IDENTIFYING_INFO,
+    },
+};
 use crate::{
-    client::{Action, DhcpStateMachine, Event},
+    client::{Action, DhcpStateMachine, Event, Lease},
     error::HeraldError,
 };
 use bytes::Bytes;
 use dhcproto::{v4, Decodable};
-use std::{net::SocketAddr, str::FromStr, time::Duration};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// REQUESTs sent in INIT-REBOOT before falling back to discovery.
+const INIT_REBOOT_ATTEMPTS: u32 = 3;
+
+/// Shortest wait between REQUESTs while renewing or rebinding (RFC 2131 4.4.5).
+const MIN_EXTEND_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 enum DhcpV4State {
     Init,
+    /// Confirming the previous lease after the carrier returned
+    InitReboot,
     Selecting,
     Requesting,
     Bound,
+    /// Extending the lease with the server that granted it, after T1
+    Renewing,
+    /// Extending the lease with any server, after T2
+    Rebinding,
+    /// DHCPv4 paused because the network is IPv6-only (RFC 8925)
+    V6OnlyWait,
+    /// Waiting for the carrier to return
+    NoCarrier,
+}
+
+/// When a bound lease is renewed, rebound and given up (RFC 2131 4.4.5)
+#[derive(Debug, Clone, Copy)]
+struct LeaseTimers {
+    renew: Instant,
+    rebind: Instant,
+    expiry: Instant,
+}
+
+impl LeaseTimers {
+    /// Timers for `lease` bound at `now`, from options 58 and 59 or the
+    /// defaults of 0.5 and 0.875 of the lease time. An infinite lease has none.
+    fn new(lease: &Lease, now: Instant) -> Option<Self> {
+        let duration = lease.lease_duration?;
+        // 0xffffffff is an infinite lease (RFC 2131)
+        if duration.as_secs() >= u64::from(u32::MAX) {
+            return None;
+        }
+        let rebind = lease
+            .rebinding_time
+            .filter(|&rebind| rebind <= duration)
+            .unwrap_or(duration * 7 / 8);
+        let renew = lease.renewal_time.unwrap_or(duration / 2).min(rebind);
+        Some(Self {
+            renew: now + renew,
+            rebind: now + rebind,
+            expiry: now + duration,
+        })
+    }
 }
 
 pub struct DhcpV4Handler {
@@ -25,22 +86,277 @@ pub struct DhcpV4Handler {
     mac_address: Bytes,
     xid: u32,
     offer: Option<v4::Message>,
+    options: MessageOptions,
+    /// When DHCPv4 may resume after an IPv6-only preferred response
+    v6only_until: Option<Instant>,
+    /// Unanswered DISCOVERs before falling back to IPv4 link-local
+    link_local_after: Option<u32>,
+    /// DISCOVERs sent without receiving an OFFER
+    unanswered_discovers: u32,
+    /// Whether a link-local address has been claimed
+    link_local_active: bool,
+    /// Cleared once a server answers with "do not auto-configure" (option 116)
+    auto_configure_allowed: bool,
+    /// Address of the last lease, confirmed with INIT-REBOOT when the carrier returns
+    bound_address: Option<Ipv4Addr>,
+    /// REQUESTs sent in the current INIT-REBOOT
+    reboot_attempts: u32,
+    /// Timers of the bound lease; `None` if it never expires
+    lease_timers: Option<LeaseTimers>,
+    /// Server that granted the bound lease, asked first when renewing
+    lease_server: Option<Ipv4Addr>,
+    /// When the next REQUEST is due while renewing or rebinding
+    next_request: Option<Instant>,
 }
 
 impl DhcpV4Handler {
-    pub fn new(mac_address: Bytes) -> Self {
+    pub fn new(mac_address: Bytes, options: MessageOptions) -> Self {
         Self {
             state: DhcpV4State::Init,
             mac_address,
             xid: rand::random(),
             offer: None,
+            options,
+            v6only_until: None,
+            link_local_after: None,
+            unanswered_discovers: 0,
+            link_local_active: false,
+            auto_configure_allowed: true,
+            bound_address: None,
+            reboot_attempts: 0,
+            lease_timers: None,
+            lease_server: None,
+            next_request: None,
+        }
+    }
+
+    /// Falls back to IPv4 link-local after `attempts` unanswered DISCOVERs.
+    pub fn with_link_local_fallback(mut self, attempts: Option<u32>) -> Self {
+        self.link_local_after = attempts;
+        self
+    }
+
+    /// Handles option 116 in an OFFER. Returns the action to take if the
+    /// server told us not to auto-configure (RFC 2563).
+    fn check_auto_configure(&mut self, msg: &v4::Message) -> Option<Action> {
+        let Some(v4::DhcpOption::DisableSLAAC(v4::AutoConfig::DoNotAutoConfigure)) =
+            msg.opts().get(v4::OptionCode::DisableSLAAC)
+        else {
+            return None;
+        };
+
+        if self.auto_configure_allowed {
+            tracing::info!("Server asked us not to auto-configure, disabling link-local fallback");
+        }
+        self.auto_configure_allowed = false;
+
+        if self.link_local_active {
+            self.link_local_active = false;
+            self.state = DhcpV4State::Init;
+            return Some(Action::ReleaseLinkLocal);
+        }
+
+        // An OFFER without an address only carries the instruction
+        msg.yiaddr()
+            .is_unspecified()
+            .then_some(Action::Wait(Duration::from_secs(5)))
+    }
+
+    /// Returns the V6ONLY_WAIT interval if `msg` carries option 108 and the
+    /// client asked for it.
+    fn v6only_wait(&self, msg: &v4::Message) -> Option<Duration> {
+        if !self.options.ipv6_only_preferred {
+            return None;
+        }
+
+        match msg.opts().get(v4::OptionCode::Unknown(IPV6_ONLY_PREFERRED)) {
+            Some(v4::DhcpOption::Unknown(opt)) => {
+                let wait = decode_v6only_wait(opt.data());
+                if wait.is_none() {
+                    tracing::warn!("Ignoring malformed IPv6-only preferred option (108)");
+                }
+                wait
+            }
+            _ => None,
         }
     }
 
+    /// Stops DHCPv4 for `wait` without configuring an IPv4 address.
+    fn enter_v6only_wait(&mut self, wait: Duration) -> Result<Action, HeraldError> {
+        tracing::info!(
+            "Server prefers IPv6-only operation, pausing DHCPv4 for {:?}",
+            wait
+        );
+        self.state = DhcpV4State::V6OnlyWait;
+        self.offer = None;
+        self.v6only_until = Some(Instant::now() + wait);
+        Ok(Action::Wait(wait))
+    }
+
+    fn handle_v6only_wait(&mut self, event: Event) -> Result<Action, HeraldError> {
+        let remaining = self
+            .v6only_until
+            .map(|until| until.saturating_duration_since(Instant::now()))
+            .unwrap_or(Duration::ZERO);
+
+        if !remaining.is_zero() {
+            // Ignore everything until V6ONLY_WAIT has elapsed
+            if let Event::PacketReceived(_) = event {
+                tracing::debug!("Ignoring packet during V6ONLY_WAIT");
+            }
+            return Ok(Action::Wait(remaining));
+        }
+
+        tracing::info!("V6ONLY_WAIT elapsed, restarting DHCPv4 discovery");
+        self.v6only_until = None;
+        self.xid = rand::random();
+        self.state = DhcpV4State::Init;
+        self.handle_init()
+    }
+
+    /// Stops sending until the carrier returns.
+    fn handle_carrier_down(&mut self) -> Action {
+        tracing::warn!("Carrier lost in state {:?}, pausing DHCPv4", self.state);
+        self.state = DhcpV4State::NoCarrier;
+        self.offer = None;
+        self.v6only_until = None;
+        Action::WaitForCarrier
+    }
+
+    /// Restarts after the carrier returns: the previous lease is confirmed
+    /// with INIT-REBOOT, since the client may have moved to another network.
+    fn handle_carrier_up(&mut self) -> Result<Action, HeraldError> {
+        self.xid = rand::random();
+        self.offer = None;
+        self.v6only_until = None;
+        self.unanswered_discovers = 0;
+
+        match self.bound_address {
+            Some(address) => {
+                tracing::info!("Carrier regained, confirming lease {}", address);
+                self.state = DhcpV4State::InitReboot;
+                self.reboot_attempts = 0;
+                self.send_init_reboot_request(address)
+            }
+            None => {
+                tracing::info!("Carrier regained, starting discovery");
+                self.state = DhcpV4State::Init;
+                self.handle_init()
+            }
+        }
+    }
+
+    fn send_init_reboot_request(&mut self, address: Ipv4Addr) -> Result<Action, HeraldError> {
+        self.reboot_attempts += 1;
+        let request_packet =
+            build_dhcp_init_reboot_request(&self.mac_address, self.xid, address, &self.options)
+                .map_err(HeraldError::Protocol)?;
+        let broadcast_addr = SocketAddr::from_str("255.255.255.255:67")
+            .map_err(|e| HeraldError::Critical(format!("Invalid broadcast address: {e}")))?;
+        Ok(Action::Send(request_packet, broadcast_addr))
+    }
+
+    fn handle_init_reboot(&mut self, event: Event) -> Result<Action, HeraldError> {
+        match (event, self.bound_address) {
+            (Event::Timeout, Some(address)) if self.reboot_attempts < INIT_REBOOT_ATTEMPTS => {
+                tracing::warn!("Timeout confirming lease {}, retrying request", address);
+                self.send_init_reboot_request(address)
+            }
+            (Event::Timeout, _) => {
+                tracing::warn!("No server confirmed the lease, restarting discovery");
+                self.state = DhcpV4State::Init;
+                self.xid = rand::random();
+                self.handle_init()
+            }
+            // ACK and NAK are handled as in REQUESTING
+            (event, _) => self.handle_requesting_response(event),
+        }
+    }
+
+    /// Waits in BOUND until T1, then starts renewing. A lease that never
+    /// expires leaves nothing to do.
+    fn handle_bound(&mut self) -> Result<Action, HeraldError> {
+        let Some(timers) = self.lease_timers else {
+            tracing::info!("Client is in Bound state - lease is active");
+            return Ok(Action::Exit);
+        };
+
+        let now = Instant::now();
+        if now < timers.renew {
+            return Ok(Action::Wait(timers.renew - now));
+        }
+        tracing::info!("Renewing the lease with its server");
+        self.state = DhcpV4State::Renewing;
+        self.xid = rand::random();
+        self.next_request = None;
+        self.handle_extending(Event::Timeout)
+    }
+
+    /// Extends the lease in RENEWING and REBINDING: REQUESTs are repeated
+    /// after half the time left, rebinding starts at T2, and the lease is
+    /// given up when it expires (RFC 2131 4.4.5).
+    fn handle_extending(&mut self, event: Event) -> Result<Action, HeraldError> {
+        let (Some(timers), Some(address)) = (self.lease_timers, self.bound_address) else {
+            return Err(HeraldError::Critical(
+                "No lease available to extend".to_string(),
+            ));
+        };
+
+        if let Event::PacketReceived(_) = event {
+            // ACK and NAK are handled as in REQUESTING
+            let action = self.handle_requesting_response(event)?;
+            if !matches!(action, Action::Wait(_)) {
+                return Ok(action);
+            }
+        }
+
+        let now = Instant::now();
+        if now >= timers.expiry {
+            tracing::warn!("Lease {} expired, restarting discovery", address);
+            self.state = DhcpV4State::Init;
+            self.bound_address = None;
+            self.lease_timers = None;
+            self.next_request = None;
+            self.xid = rand::random();
+            return Ok(Action::DropLease);
+        }
+        if self.state == DhcpV4State::Renewing && now >= timers.rebind {
+            tracing::warn!("Lease {} not renewed, rebinding with any server", address);
+            self.state = DhcpV4State::Rebinding;
+            self.next_request = None;
+        }
+
+        match self.next_request {
+            Some(next) if now < next => Ok(Action::Wait(next - now)),
+            _ => {
+                let deadline = match self.state {
+                    DhcpV4State::Renewing => timers.rebind,
+                    _ => timers.expiry,
+                };
+                let left = deadline - now;
+                self.next_request = Some(now + (left / 2).max(MIN_EXTEND_INTERVAL).min(left));
+                self.send_renew_request(address)
+            }
+        }
+    }
+
+    /// Sends a REQUEST for `address`, unicast to the lease's server while
+    /// renewing and broadcast while rebinding.
+    fn send_renew_request(&mut self, address: Ipv4Addr) -> Result<Action, HeraldError> {
+        let request_packet =
+            build_dhcp_renew_request(&self.mac_address, self.xid, address, &self.options)
+                .map_err(HeraldError::Protocol)?;
+        let server = match (self.state, self.lease_server) {
+            (DhcpV4State::Renewing, Some(server)) => server,
+            _ => Ipv4Addr::BROADCAST,
+        };
+        Ok(Action::Send(request_packet, SocketAddr::from((server, 67))))
+    }
+
     // 私有辅助函数来处理特定的状态转换
     fn handle_init(&mut self) -> Result<Action, HeraldError> {
         self.state = DhcpV4State::Selecting;
-        let discover_packet = build_dhcp_discover(&self.mac_address, self.xid)?;
+        let discover_packet = build_dhcp_discover(&self.mac_address, self.xid, &self.options)?;
         let broadcast_addr = SocketAddr::from_str("255.255.255.255:67")
             .map_err(|e| HeraldError::Critical(format!("Invalid broadcast address: {e}")))?;
         Ok(Action::Send(discover_packet, broadcast_addr))
@@ -78,6 +394,16 @@ impl DhcpV4Handler {
                                 tracing::info!("Server identifier: {}", server_ip);
                             }
 
+                            self.unanswered_discovers = 0;
+                            if let Some(action) = self.check_auto_configure(&msg) {
+                                return Ok(action);
+                            }
+
+                            // RFC 8925: don't request the address on an IPv6-only network
+                            if let Some(wait) = self.v6only_wait(&msg) {
+                                return self.enter_v6only_wait(wait);
+                            }
+
                             self.offer = Some(msg);
                             self.state = DhcpV4State::Requesting;
                             tracing::info!("Transitioning to Requesting state");
@@ -94,10 +420,24 @@ impl DhcpV4Handler {
                 // 不是我们想要的包，继续等待
                 Ok(Action::Wait(Duration::from_secs(5)))
             }
-            Event::Timeout => {
+            // Carrier events are handled before the state is dispatched
+            Event::Timeout | Event::CarrierDown | Event::CarrierUp => {
                 tracing::warn!("Timeout in Selecting state, retrying discovery");
                 // 超时，重新发送 Discover
                 self.state = DhcpV4State::Init;
+                self.unanswered_discovers += 1;
+
+                // RFC 3927: fall back to link-local but keep looking for a server
+                if let Some(attempts) = self.link_local_after {
+                    if self.unanswered_discovers >= attempts
+                        && self.auto_configure_allowed
+                        && !self.link_local_active
+                    {
+                        self.link_local_active = true;
+                        return Ok(Action::ClaimLinkLocal);
+                    }
+                }
+
                 self.handle_init()
             }
         }
@@ -131,6 +471,7 @@ impl DhcpV4Handler {
                 self.xid,
                 offered_ip,
                 server_id,
+                &self.options,
             )
             .map_err(HeraldError::Protocol)?;
 
@@ -153,18 +494,31 @@ impl DhcpV4Handler {
                 if msg.xid() == self.xid {
                     match msg.opts().get(v4::OptionCode::MessageType) {
                         Some(v4::DhcpOption::MessageType(v4::MessageType::Ack)) => {
+                            if let Some(wait) = self.v6only_wait(&msg) {
+                                return self.enter_v6only_wait(wait);
+                            }
+
                             // DHCP ACK received - extract lease information
-                            let lease = self.extract_lease_info(&msg)?;
+                            let lease = self.extract_lease_info(&msg, data)?;
                             self.state = DhcpV4State::Bound;
-                            Ok(Action::StoreLease(lease))
+                            self.link_local_active = false;
+                            self.bound_address = Some(lease.offered_ip);
+                            self.lease_timers = LeaseTimers::new(&lease, Instant::now());
+                            self.lease_server = lease.server_identifier;
+                            self.next_request = None;
+                            Ok(Action::StoreLease(Box::new(lease)))
                         }
                         Some(v4::DhcpOption::MessageType(v4::MessageType::Nak)) => {
-                            // DHCP NAK received - restart the process
+                            // The address must no longer be used; discovery
+                            // restarts once its configuration is removed
                             tracing::warn!("Received DHCP NAK, restarting discovery");
                             self.state = DhcpV4State::Init;
                             self.offer = None;
+                            self.bound_address = None;
+                            self.lease_timers = None;
+                            self.next_request = None;
                             self.xid = rand::random(); // New transaction ID
-                            self.handle_init()
+                            Ok(Action::DropLease)
                         }
                         _ => {
                             // Not the message we're looking for, keep waiting
@@ -176,7 +530,8 @@ impl DhcpV4Handler {
                     Ok(Action::Wait(Duration::from_secs(5)))
                 }
             }
-            Event::Timeout => {
+            // Carrier events are handled before the state is dispatched
+            Event::Timeout | Event::CarrierDown | Event::CarrierUp => {
                 // Timeout waiting for ACK/NAK, retry request
                 tracing::warn!("Timeout waiting for DHCP ACK, retrying request");
                 self.handle_requesting()
@@ -184,7 +539,7 @@ impl DhcpV4Handler {
         }
     }
 
-    fn extract_lease_info(&self, msg: &v4::Message) -> Result<crate::client::Lease, HeraldError> {
+    fn extract_lease_info(&self, msg: &v4::Message, data: &[u8]) -> Result<Lease, HeraldError> {
         let offered_ip = msg.yiaddr();
 
         let subnet_mask = msg.opts().get(v4::OptionCode::SubnetMask).and_then(|opt| {
@@ -195,6 +550,17 @@ impl DhcpV4Handler {
             }
         });
 
+        let broadcast_address = msg
+            .opts()
+            .get(v4::OptionCode::BroadcastAddr)
+            .and_then(|opt| {
+                if let v4::DhcpOption::BroadcastAddr(addr) = opt {
+                    Some(*addr)
+                } else {
+                    None
+                }
+            });
+
         let routers = msg.opts().get(v4::OptionCode::Router).and_then(|opt| {
             if let v4::DhcpOption::Router(routers) = opt {
                 Some(routers.clone())
@@ -203,6 +569,17 @@ impl DhcpV4Handler {
             }
         });
 
+        let static_routes = msg
+            .opts()
+            .get(v4::OptionCode::StaticRoutingTable)
+            .and_then(|opt| {
+                if let v4::DhcpOption::StaticRoutingTable(routes) = opt {
+                    Some(routes.clone())
+                } else {
+                    None
+                }
+            });
+
         let dns_servers = msg
             .opts()
             .get(v4::OptionCode::DomainNameServer)
@@ -225,6 +602,22 @@ impl DhcpV4Handler {
                 }
             });
 
+        let renewal_time = msg.opts().get(v4::OptionCode::Renewal).and_then(|opt| {
+            if let v4::DhcpOption::Renewal(secs) = opt {
+                Some(Duration::from_secs(*secs as u64))
+            } else {
+                None
+            }
+        });
+
+        let rebinding_time = msg.opts().get(v4::OptionCode::Rebinding).and_then(|opt| {
+            if let v4::DhcpOption::Rebinding(secs) = opt {
+                Some(Duration::from_secs(*secs as u64))
+            } else {
+                None
+            }
+        });
+
         let server_identifier = msg
             .opts()
             .get(v4::OptionCode::ServerIdentifier)
@@ -236,13 +629,118 @@ impl DhcpV4Handler {
                 }
             });
 
-        Ok(crate::client::Lease {
+        let interface_mtu = msg
+            .opts()
+            .get(v4::OptionCode::InterfaceMtu)
+            .and_then(|opt| {
+                if let v4::DhcpOption::InterfaceMtu(mtu) = opt {
+                    Some(*mtu)
+                } else {
+                    None
+                }
+            });
+
+        let ntp_servers = msg.opts().get(v4::OptionCode::NtpServers).and_then(|opt| {
+            if let v4::DhcpOption::NtpServers(servers) = opt {
+                Some(servers.clone())
+            } else {
+                None
+            }
+        });
+
+        let captive_portal = msg
+            .opts()
+            .get(v4::OptionCode::CaptivePortal)
+            .and_then(|opt| {
+                if let v4::DhcpOption::CaptivePortal(uri) = opt {
+                    validate_captive_portal(uri)
+                } else {
+                    None
+                }
+            });
+
+        let time_offset = msg.opts().get(v4::OptionCode::TimeOffset).and_then(|opt| {
+            if let v4::DhcpOption::TimeOffset(offset) = opt {
+                Some(*offset)
+            } else {
+                None
+            }
+        });
+
+        let posix_timezone = msg
+            .opts()
+            .get(v4::OptionCode::Unknown(POSIX_TIMEZONE))
+            .and_then(|opt| {
+                if let v4::DhcpOption::Unknown(unknown) = opt {
+                    decode_timezone(unknown.data())
+                } else {
+                    None
+                }
+            });
+
+        let tz_database_name = msg
+            .opts()
+            .get(v4::OptionCode::Unknown(TZDB_TIMEZONE))
+            .and_then(|opt| {
+                if let v4::DhcpOption::Unknown(unknown) = opt {
+                    decode_timezone(unknown.data())
+                } else {
+                    None
+                }
+            });
+
+        let boot_info = BootInfo::from_reply(msg, data);
+
+        let vendor_specific = msg
+            .opts()
+            .get(v4::OptionCode::VendorExtensions)
+            .and_then(|opt| {
+                if let v4::DhcpOption::VendorExtensions(data) = opt {
+                    let decoded = decode_sub_options(data);
+                    if decoded.is_none() {
+                        tracing::warn!("Ignoring malformed vendor-specific information (43)");
+                    }
+                    decoded
+                } else {
+                    None
+                }
+            });
+
+        let vendor_identifying = msg
+            .opts()
+            .get(v4::OptionCode::Unknown(VENDOR_IDENTIFYING_INFO))
+            .and_then(|opt| {
+                if let v4::DhcpOption::Unknown(unknown) = opt {
+                    let decoded = decode_vendor_identifying_info(unknown.data());
+                    if decoded.is_none() {
+                        tracing::warn!("Ignoring malformed vendor-identifying information (125)");
+                    }
+                    decoded
+                } else {
+                    None
+                }
+            });
+
+        Ok(Lease {
             offered_ip,
             subnet_mask,
+            broadcast_address,
             routers,
+            static_routes,
             dns_servers,
             lease_duration,
+            renewal_time,
+            rebinding_time,
             server_identifier,
+            interface_mtu,
+            ntp_servers,
+            captive_portal,
+            time_offset,
+            posix_timezone,
+            tz_database_name,
+            boot_info,
+            vendor_specific,
+            vendor_identifying,
         })
     }
 }
@@ -251,23 +749,34 @@ impl DhcpStateMachine for DhcpV4Handler {
     fn state_name(&self) -> &'static str {
         match self.state {
             DhcpV4State::Init => "Init",
+            DhcpV4State::InitReboot => "InitReboot",
             DhcpV4State::Selecting => "Selecting",
             DhcpV4State::Requesting => "Requesting",
             DhcpV4State::Bound => "Bound",
+            DhcpV4State::Renewing => "Renewing",
+            DhcpV4State::Rebinding => "Rebinding",
+            DhcpV4State::V6OnlyWait => "V6OnlyWait",
+            DhcpV4State::NoCarrier => "NoCarrier",
         }
     }
 
     fn handle_event(&mut self, event: Event) -> Result<Action, HeraldError> {
         tracing::debug!("Handling event {:?} in state {:?}", event, self.state);
+        match event {
+            Event::CarrierDown => return Ok(self.handle_carrier_down()),
+            Event::CarrierUp => return self.handle_carrier_up(),
+            _ => {}
+        }
+
         match self.state {
             DhcpV4State::Init => self.handle_init(),
+            DhcpV4State::InitReboot => self.handle_init_reboot(event),
+            DhcpV4State::NoCarrier => Ok(Action::WaitForCarrier),
             DhcpV4State::Selecting => self.handle_selecting(event),
             DhcpV4State::Requesting => self.handle_requesting_response(event),
-            DhcpV4State::Bound => {
-                // In bound state, we could handle lease renewal, but for now just stay bound
-                tracing::info!("Client is in Bound state - lease is active");
-                Ok(Action::Exit)
-            }
+            DhcpV4State::V6OnlyWait => self.handle_v6only_wait(event),
+            DhcpV4State::Bound => self.handle_bound(),
+            DhcpV4State::Renewing | DhcpV4State::Rebinding => self.handle_extending(event),
         }
     }
 }
@@ -3,45 +3,253 @@
 //! This module provides functions for building DHCPv4 protocol messages
 //! including DISCOVER and REQUEST packets with proper options.
 
+use super::options::{
+    encode_user_classes, encode_vendor_identifying_classes, VendorClass, IPV6_ONLY_PREFERRED,
+    POSIX_TIMEZONE, TZDB_TIMEZONE, VENDOR_IDENTIFYING_CLASS, VENDOR_IDENTIFYING_INFO,
+};
+use crate::{
+    config::ClientConfig,
+    duid::{default_iaid, encode_client_identifier, Duid},
+};
 use bytes::{BufMut as _, Bytes, BytesMut};
 use dhcproto::{
-    v4::{self, OptionCode},
+    v4::{self, OptionCode, UnknownOption},
     Encodable as _, Encoder,
 };
 use std::error::Error as StdError;
 
+/// Hardware type of IP over InfiniBand (RFC 4390).
+pub const HTYPE_INFINIBAND: u8 = 32;
+
+/// Size of the chaddr field.
+const CHADDR_LEN: usize = 16;
+
+/// Trailing bytes of an IPoIB hardware address that hold the port GUID.
+const INFINIBAND_GUID_LEN: usize = 8;
+
+/// Part of a hardware address that identifies the client across restarts:
+/// the port GUID for IPoIB, whose address also holds a queue pair number,
+/// and the whole address otherwise.
+pub fn link_layer_identifier(htype: v4::HType, address: &[u8]) -> &[u8] {
+    if u8::from(htype) == HTYPE_INFINIBAND {
+        &address[address.len().saturating_sub(INFINIBAND_GUID_LEN)..]
+    } else {
+        address
+    }
+}
+
+/// Client-supplied options that are sent with every DISCOVER and REQUEST.
+#[derive(Debug, Clone, Default)]
+pub struct MessageOptions {
+    /// Client identifier (option 61); derived from the MAC address when unset
+    pub client_identifier: Option<Vec<u8>>,
+    /// Vendor class identifier (option 60)
+    pub vendor_class: Option<Vec<u8>>,
+    /// User classes (option 77)
+    pub user_classes: Vec<Vec<u8>>,
+    /// Vendor-identifying vendor classes (option 124)
+    pub vendor_identifying_classes: Vec<VendorClass>,
+    /// Whether the client can operate IPv6-only and requests option 108
+    pub ipv6_only_preferred: bool,
+    /// PXE client system architecture (option 93)
+    pub client_architecture: Option<u16>,
+    /// PXE client machine UUID (option 97)
+    pub client_machine_id: Option<[u8; 16]>,
+    /// Whether the client falls back to IPv4 link-local and sends option 116
+    pub auto_configure: bool,
+    /// Whether unicast replies reach the client before it has an address, so
+    /// the broadcast flag can be left clear
+    pub receives_unicast: bool,
+    /// Hardware type (htype) of the interface; Ethernet when unset
+    pub hardware_type: Option<v4::HType>,
+}
+
+impl MessageOptions {
+    pub fn from_config(config: &ClientConfig) -> Self {
+        Self {
+            client_identifier: None,
+            vendor_class: config
+                .vendor_class
+                .as_ref()
+                .map(|class| class.as_bytes().to_vec()),
+            user_classes: config
+                .user_classes
+                .iter()
+                .map(|class| class.as_bytes().to_vec())
+                .collect(),
+            vendor_identifying_classes: config.vendor_identifying_classes.clone(),
+            ipv6_only_preferred: config.ipv6_only_preferred,
+            client_architecture: config.client_architecture,
+            client_machine_id: config.client_machine_id,
+            auto_configure: config.link_local_fallback.is_some(),
+            receives_unicast: false,
+            hardware_type: Some(config.hardware_type),
+        }
+    }
+
+    /// Hardware type sent in `htype`.
+    fn htype(&self) -> v4::HType {
+        self.hardware_type.unwrap_or(v4::HType::Eth)
+    }
+
+    /// Whether the interface is IPoIB, which cannot use chaddr (RFC 4390)
+    fn is_infiniband(&self) -> bool {
+        u8::from(self.htype()) == HTYPE_INFINIBAND
+    }
+
+    /// The hardware address as sent in chaddr. IPoIB leaves chaddr empty, as
+    /// does any address too long for the field; the server then goes by the
+    /// client identifier.
+    fn chaddr<'a>(&self, mac_addr: &'a [u8]) -> &'a [u8] {
+        if self.is_infiniband() || mac_addr.len() > CHADDR_LEN {
+            &[]
+        } else {
+            mac_addr
+        }
+    }
+
+    /// Returns the client identifier, defaulting to the htype followed by the
+    /// MAC address. IPoIB must use an RFC 4361 identifier, so it defaults to
+    /// a DUID-LL of the port GUID.
+    fn client_identifier(&self, mac_addr: &Bytes) -> Vec<u8> {
+        if let Some(ref client_id) = self.client_identifier {
+            return client_id.clone();
+        }
+
+        if self.is_infiniband() {
+            let guid = link_layer_identifier(self.htype(), mac_addr);
+            let duid = Duid::ll(HTYPE_INFINIBAND.into(), guid);
+            return encode_client_identifier(default_iaid(guid), &duid);
+        }
+
+        let mut client_id_data = BytesMut::new();
+        client_id_data.put_u8(self.htype().into());
+        client_id_data.extend_from_slice(mac_addr);
+        client_id_data.freeze().to_vec()
+    }
+
+    /// Flags for messages sent before the client has an address. IPoIB
+    /// replies must be broadcast, as the server cannot address them without
+    /// a chaddr.
+    fn flags(&self) -> v4::Flags {
+        if self.receives_unicast && !self.is_infiniband() {
+            v4::Flags::default()
+        } else {
+            v4::Flags::default().set_broadcast()
+        }
+    }
+
+    /// Inserts the configured class and PXE options into `msg`. Fails if the
+    /// user classes do not fit option 77 or the vendor-identifying classes
+    /// option 124.
+    fn insert_into(&self, msg: &mut v4::Message) -> Result<(), Box<dyn StdError + Send + Sync>> {
+        if let Some(ref vendor_class) = self.vendor_class {
+            msg.opts_mut()
+                .insert(v4::DhcpOption::ClassIdentifier(vendor_class.clone()));
+        }
+
+        if !self.user_classes.is_empty() {
+            msg.opts_mut()
+                .insert(v4::DhcpOption::UserClass(encode_user_classes(
+                    &self.user_classes,
+                )?));
+        }
+
+        if !self.vendor_identifying_classes.is_empty() {
+            msg.opts_mut()
+                .insert(v4::DhcpOption::Unknown(UnknownOption::new(
+                    OptionCode::Unknown(VENDOR_IDENTIFYING_CLASS),
+                    encode_vendor_identifying_classes(&self.vendor_identifying_classes)?,
+                )));
+        }
+
+        if let Some(arch) = self.client_architecture {
+            msg.opts_mut()
+                .insert(v4::DhcpOption::ClientSystemArchitecture(arch.into()));
+        }
+
+        if let Some(uuid) = self.client_machine_id {
+            // RFC 4578: type 0 followed by the 16-byte UUID
+            let mut machine_id = Vec::with_capacity(17);
+            machine_id.push(0);
+            machine_id.extend_from_slice(&uuid);
+            msg.opts_mut()
+                .insert(v4::DhcpOption::ClientMachineIdentifier(machine_id));
+        }
+        Ok(())
+    }
+}
+
+/// Options requested from the server in the Parameter Request List (option 55).
+fn parameter_request_list(options: &MessageOptions) -> Vec<OptionCode> {
+    let mut codes = vec![
+        OptionCode::SubnetMask,                       // 1
+        OptionCode::TimeOffset,                       // 2
+        OptionCode::Router,                           // 3
+        OptionCode::DomainNameServer,                 // 6
+        OptionCode::DomainName,                       // 15
+        OptionCode::InterfaceMtu,                     // 26
+        OptionCode::BroadcastAddr,                    // 28
+        OptionCode::StaticRoutingTable,               // 33
+        OptionCode::NtpServers,                       // 42
+        OptionCode::VendorExtensions,                 // 43
+        OptionCode::Renewal,                          // 58
+        OptionCode::Rebinding,                        // 59
+        OptionCode::TFTPServerName,                   // 66
+        OptionCode::BootfileName,                     // 67
+        OptionCode::Unknown(POSIX_TIMEZONE),          // 100
+        OptionCode::Unknown(TZDB_TIMEZONE),           // 101
+        OptionCode::CaptivePortal,                    // 114
+        OptionCode::Unknown(VENDOR_IDENTIFYING_INFO), // 125
+        OptionCode::TFTPServerAddress,                // 150
+    ];
+
+    // RFC 8925: only IPv6-only capable clients may request option 108
+    if options.ipv6_only_preferred {
+        codes.push(OptionCode::Unknown(IPV6_ONLY_PREFERRED));
+    }
+
+    codes
+}
+
 /// Constructs a DHCP Discover message.
-pub fn build_dhcp_discover(mac_addr: &Bytes, xid: u32) -> Result<Vec<u8>, Box<dyn StdError>> {
+pub fn build_dhcp_discover(
+    mac_addr: &Bytes,
+    xid: u32,
+    options: &MessageOptions,
+) -> Result<Vec<u8>, Box<dyn StdError + Send + Sync>> {
     let mut msg = v4::Message::default();
     msg.set_opcode(v4::Opcode::BootRequest)
-        .set_chaddr(mac_addr)
-        .set_htype(v4::HType::Eth) // Ethernet
+        .set_chaddr(options.chaddr(mac_addr))
+        .set_htype(options.htype())
         .set_hops(0)
         .set_xid(xid) // Transaction ID
         .set_secs(0)
-        .set_flags(v4::Flags::default().set_broadcast());
+        .set_flags(options.flags());
 
     // Add DHCP Message Type Option (53) - DHCPDISCOVER (1)
     msg.opts_mut()
         .insert(v4::DhcpOption::MessageType(v4::MessageType::Discover));
 
     // Add Client Identifier Option (61)
-    // Using htype 1 (Ethernet) followed by the MAC address
-    let mut client_id_data = BytesMut::new();
-    client_id_data.put_u8(1); // htype Ethernet
-    client_id_data.extend_from_slice(mac_addr);
+    // Either htype 1 (Ethernet) followed by the MAC address, or an RFC 4361 IAID and DUID
     msg.opts_mut().insert(v4::DhcpOption::ClientIdentifier(
-        client_id_data.freeze().to_vec(),
+        options.client_identifier(mac_addr),
     ));
 
     // Add Parameter Request List Option (55)
-    msg.opts_mut()
-        .insert(v4::DhcpOption::ParameterRequestList(vec![
-            OptionCode::SubnetMask,       // 1
-            OptionCode::Router,           // 3
-            OptionCode::DomainNameServer, // 6
-            OptionCode::DomainName,       // 15
-        ]));
+    msg.opts_mut().insert(v4::DhcpOption::ParameterRequestList(
+        parameter_request_list(options),
+    ));
+
+    // Vendor Class (60), User Class (77), PXE (93, 97) and Vendor-Identifying Vendor Class (124)
+    options.insert_into(&mut msg)?;
+
+    // RFC 2563: tell the server we would otherwise auto-configure (option 116)
+    if options.auto_configure {
+        msg.opts_mut()
+            .insert(v4::DhcpOption::DisableSLAAC(v4::AutoConfig::AutoConfigure));
+    }
 
     let mut buffer = Vec::new();
     let mut encoder = Encoder::new(&mut buffer);
@@ -55,14 +263,15 @@ pub fn build_dhcp_request(
     xid: u32,
     offered_ip: std::net::Ipv4Addr,
     server_ip: std::net::Ipv4Addr,
-) -> Result<Vec<u8>, Box<dyn StdError>> {
+    options: &MessageOptions,
+) -> Result<Vec<u8>, Box<dyn StdError + Send + Sync>> {
     let mut msg = v4::Message::default();
     msg.set_opcode(v4::Opcode::BootRequest)
-        .set_chaddr(mac_addr)
-        .set_htype(v4::HType::Eth)
+        .set_chaddr(options.chaddr(mac_addr))
+        .set_htype(options.htype())
         .set_xid(xid)
         .set_ciaddr(std::net::Ipv4Addr::UNSPECIFIED) // Client IP, 0.0.0.0 as it's not confirmed
-        .set_flags(v4::Flags::default().set_broadcast()); // Request broadcast reply
+        .set_flags(options.flags()); // Request broadcast reply unless we can receive unicast
 
     // DHCP Message Type - REQUEST (3)
     msg.opts_mut()
@@ -77,21 +286,132 @@ pub fn build_dhcp_request(
         .insert(v4::DhcpOption::ServerIdentifier(server_ip));
 
     // Client Identifier (Option 61) - same as Discover
-    let mut client_id_data = BytesMut::new();
-    client_id_data.put_u8(1); // htype Ethernet
-    client_id_data.extend_from_slice(mac_addr);
     msg.opts_mut().insert(v4::DhcpOption::ClientIdentifier(
-        client_id_data.freeze().to_vec(),
+        options.client_identifier(mac_addr),
     ));
 
-    // Parameter Request List (Option 55) - can be same as Discover
+    // Parameter Request List (Option 55) - same as Discover
+    msg.opts_mut().insert(v4::DhcpOption::ParameterRequestList(
+        parameter_request_list(options),
+    ));
+
+    // Class options must match the Discover so the server picks the same pool
+    options.insert_into(&mut msg)?;
+
+    let mut buffer = Vec::new();
+    let mut encoder = Encoder::new(&mut buffer);
+    msg.encode(&mut encoder)?;
+    Ok(buffer)
+}
+
+/// Constructs a DHCP Request that confirms a previously leased address from
+/// the INIT-REBOOT state, e.g. after the link comes back (RFC 2131 4.3.2).
+pub fn build_dhcp_init_reboot_request(
+    mac_addr: &Bytes,
+    xid: u32,
+    requested_ip: std::net::Ipv4Addr,
+    options: &MessageOptions,
+) -> Result<Vec<u8>, Box<dyn StdError + Send + Sync>> {
+    let mut msg = v4::Message::default();
+    msg.set_opcode(v4::Opcode::BootRequest)
+        .set_chaddr(options.chaddr(mac_addr))
+        .set_htype(options.htype())
+        .set_xid(xid)
+        .set_ciaddr(std::net::Ipv4Addr::UNSPECIFIED) // Must be zero in INIT-REBOOT
+        .set_flags(options.flags());
+
+    // DHCP Message Type - REQUEST (3)
+    msg.opts_mut()
+        .insert(v4::DhcpOption::MessageType(v4::MessageType::Request));
+
+    // Requested IP Address (Option 50); no Server Identifier, any server may answer
     msg.opts_mut()
-        .insert(v4::DhcpOption::ParameterRequestList(vec![
-            OptionCode::SubnetMask,
-            OptionCode::Router,
-            OptionCode::DomainNameServer,
-            OptionCode::DomainName,
-        ]));
+        .insert(v4::DhcpOption::RequestedIpAddress(requested_ip));
+
+    // Client Identifier (Option 61) - must match the one the lease was obtained with
+    msg.opts_mut().insert(v4::DhcpOption::ClientIdentifier(
+        options.client_identifier(mac_addr),
+    ));
+
+    // Parameter Request List (Option 55) - same as Discover
+    msg.opts_mut().insert(v4::DhcpOption::ParameterRequestList(
+        parameter_request_list(options),
+    ));
+
+    options.insert_into(&mut msg)?;
+
+    let mut buffer = Vec::new();
+    let mut encoder = Encoder::new(&mut buffer);
+    msg.encode(&mut encoder)?;
+    Ok(buffer)
+}
+
+/// Constructs a DHCP Request that extends a bound lease from the RENEWING or
+/// REBINDING state (RFC 2131 4.3.2).
+pub fn build_dhcp_renew_request(
+    mac_addr: &Bytes,
+    xid: u32,
+    client_ip: std::net::Ipv4Addr,
+    options: &MessageOptions,
+) -> Result<Vec<u8>, Box<dyn StdError + Send + Sync>> {
+    let mut msg = v4::Message::default();
+    msg.set_opcode(v4::Opcode::BootRequest)
+        .set_chaddr(options.chaddr(mac_addr))
+        .set_htype(options.htype())
+        .set_xid(xid)
+        .set_ciaddr(client_ip); // The reply is unicast to the leased address
+
+    // DHCP Message Type - REQUEST (3)
+    msg.opts_mut()
+        .insert(v4::DhcpOption::MessageType(v4::MessageType::Request));
+
+    // No Requested IP Address or Server Identifier: ciaddr names the lease
+
+    // Client Identifier (Option 61) - must match the one the lease was obtained with
+    msg.opts_mut().insert(v4::DhcpOption::ClientIdentifier(
+        options.client_identifier(mac_addr),
+    ));
+
+    // Parameter Request List (Option 55) - same as Discover
+    msg.opts_mut().insert(v4::DhcpOption::ParameterRequestList(
+        parameter_request_list(options),
+    ));
+
+    options.insert_into(&mut msg)?;
+
+    let mut buffer = Vec::new();
+    let mut encoder = Encoder::new(&mut buffer);
+    msg.encode(&mut encoder)?;
+    Ok(buffer)
+}
+
+/// Constructs a DHCP Release message for a bound lease.
+pub fn build_dhcp_release(
+    mac_addr: &Bytes,
+    xid: u32,
+    client_ip: std::net::Ipv4Addr,
+    server_ip: std::net::Ipv4Addr,
+    options: &MessageOptions,
+) -> Result<Vec<u8>, Box<dyn StdError + Send + Sync>> {
+    let mut msg = v4::Message::default();
+    msg.set_opcode(v4::Opcode::BootRequest)
+        .set_chaddr(options.chaddr(mac_addr))
+        .set_htype(options.htype())
+        .set_xid(xid)
+        .set_ciaddr(client_ip); // Release is sent from the leased address
+
+    // DHCP Message Type - RELEASE (7)
+    msg.opts_mut()
+        .insert(v4::DhcpOption::MessageType(v4::MessageType::Release));
+
+    // Server Identifier (Option 54)
+    msg.opts_mut()
+        .insert(v4::DhcpOption::ServerIdentifier(server_ip));
+
+    // Client Identifier (Option 61) - must match the one the lease was obtained with
+    msg.opts_mut().insert(v4::DhcpOption::ClientIdentifier(
+        options.client_identifier(mac_addr),
+    ));
 
     let mut buffer = Vec::new();
     let mut encoder = Encoder::new(&mut buffer);
@@ -5,6 +5,10 @@
 //! - State machine handling
 //! - Protocol-specific logic
 
+pub mod boot;
 pub mod handler;
 pub mod message;
+pub mod options;
 
+#[cfg(test)]
+mod tests;
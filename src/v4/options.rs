@@ -0,0 +1,344 @@
+//! Encoding and decoding of structured DHCP options
+//!
+//! dhcproto exposes the class and vendor options as raw bytes. This module
+//! implements the wire formats for:
+//! - User Class (77, RFC 3004)
+//! - Vendor-Identifying Vendor Class (124, RFC 3925)
+//! - Vendor-Specific Information (43, RFC 2132) sub-options
+//! - Vendor-Identifying Vendor-Specific Information (125, RFC 3925)
+//! - IPv6-Only Preferred (108, RFC 8925)
+//! - Captive-Portal API URI (114, RFC 8910)
+//! - POSIX and tz database timezones (100, 101, RFC 4833)
+
+use std::{collections::BTreeMap, fmt, str::FromStr, time::Duration};
+
+/// Option code for Vendor-Identifying Vendor Class (RFC 3925).
+pub const VENDOR_IDENTIFYING_CLASS: u8 = 124;
+
+/// Option code for Vendor-Identifying Vendor-Specific Information (RFC 3925).
+pub const VENDOR_IDENTIFYING_INFO: u8 = 125;
+
+/// Option code for IPv6-Only Preferred (RFC 8925).
+pub const IPV6_ONLY_PREFERRED: u8 = 108;
+
+/// Lower bound for the V6ONLY_WAIT interval (RFC 8925 section 3.4).
+pub const MIN_V6ONLY_WAIT: Duration = Duration::from_secs(300);
+
+/// Option code for the POSIX TZ string (RFC 4833).
+pub const POSIX_TIMEZONE: u8 = 100;
+
+/// Option code for the tz database timezone name (RFC 4833).
+pub const TZDB_TIMEZONE: u8 = 101;
+
+/// URI a server sends in option 114 to signal that there is no captive portal.
+pub const CAPPORT_UNRESTRICTED: &str = "urn:ietf:params:capport:unrestricted";
+
+/// Sub-options of a vendor option, keyed by sub-option code.
+pub type SubOptions = BTreeMap<u8, Vec<u8>>;
+
+/// A vendor class sent in option 124, identified by its IANA enterprise number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VendorClass {
+    pub enterprise_number: u32,
+    pub data: Vec<u8>,
+}
+
+impl FromStr for VendorClass {
+    type Err = String;
+
+    /// Parses `<enterprise-number>:<class-data>`, e.g. `4491:docsis3.0`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (enterprise, data) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <enterprise-number>:<data>, got '{s}'"))?;
+        let enterprise_number = enterprise
+            .parse()
+            .map_err(|e| format!("invalid enterprise number '{enterprise}': {e}"))?;
+        Ok(Self {
+            enterprise_number,
+            data: data.as_bytes().to_vec(),
+        })
+    }
+}
+
+impl fmt::Display for VendorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}",
+            self.enterprise_number,
+            String::from_utf8_lossy(&self.data)
+        )
+    }
+}
+
+/// Parses a user class for option 77, which must take 1 to 255 bytes.
+pub fn parse_user_class(s: &str) -> Result<String, String> {
+    match s.len() {
+        0 => Err("user class is empty".to_string()),
+        len if len > usize::from(u8::MAX) => {
+            Err(format!("user class takes {len} bytes, more than 255"))
+        }
+        _ => Ok(s.to_string()),
+    }
+}
+
+/// Encodes user classes as a sequence of length-prefixed class entries (RFC 3004).
+///
+/// Fails if a class takes more than the 255 bytes its length can describe.
+pub fn encode_user_classes<T: AsRef<[u8]>>(classes: &[T]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    for class in classes {
+        let class = class.as_ref();
+        let len = u8::try_from(class.len())
+            .map_err(|_| format!("user class takes {} bytes, more than 255", class.len()))?;
+        out.push(len);
+        out.extend_from_slice(class);
+    }
+    Ok(out)
+}
+
+/// Encodes vendor classes as option 124 data (RFC 3925).
+///
+/// Classes sharing an enterprise number are grouped into a single entry, in
+/// the order in which the enterprise number first appears. Fails if a group
+/// takes more than the 255 bytes its length can describe.
+pub fn encode_vendor_identifying_classes(classes: &[VendorClass]) -> Result<Vec<u8>, String> {
+    let mut grouped: Vec<(u32, Vec<u8>)> = Vec::new();
+    for class in classes {
+        let data = &class.data;
+        let entry = match grouped
+            .iter_mut()
+            .find(|(enterprise, _)| *enterprise == class.enterprise_number)
+        {
+            Some(entry) => entry,
+            None => {
+                grouped.push((class.enterprise_number, Vec::new()));
+                grouped.last_mut().expect("entry was just pushed")
+            }
+        };
+        entry.1.push(data.len().min(u8::MAX as usize) as u8);
+        entry.1.extend_from_slice(data);
+    }
+
+    let mut out = Vec::new();
+    for (enterprise, data) in grouped {
+        let len = u8::try_from(data.len()).map_err(|_| {
+            format!(
+                "vendor classes of enterprise {enterprise} take {} bytes, more than 255",
+                data.len()
+            )
+        })?;
+        out.extend_from_slice(&enterprise.to_be_bytes());
+        out.push(len);
+        out.extend_from_slice(&data);
+    }
+    Ok(out)
+}
+
+/// Decodes encapsulated vendor-specific sub-options (option 43).
+///
+/// Returns `None` if a sub-option runs past the end of the data.
+pub fn decode_sub_options(data: &[u8]) -> Option<SubOptions> {
+    let mut options = SubOptions::new();
+    let mut rest = data;
+    while let Some((&code, tail)) = rest.split_first() {
+        match code {
+            0 => rest = tail,
+            255 => break,
+            _ => {
+                let (&len, tail) = tail.split_first()?;
+                let value = tail.get(..len as usize)?;
+                options.entry(code).or_default().extend_from_slice(value);
+                rest = &tail[len as usize..];
+            }
+        }
+    }
+    Some(options)
+}
+
+/// Decodes option 125 data into sub-options keyed by enterprise number.
+///
+/// Returns `None` if an entry runs past the end of the data.
+pub fn decode_vendor_identifying_info(data: &[u8]) -> Option<BTreeMap<u32, SubOptions>> {
+    let mut vendors = BTreeMap::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let enterprise = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?);
+        let len = *rest.get(4)? as usize;
+        let payload = rest.get(5..5 + len)?;
+        let options = decode_sub_options(payload)?;
+        vendors
+            .entry(enterprise)
+            .or_insert_with(SubOptions::new)
+            .extend(options);
+        rest = &rest[5 + len..];
+    }
+    Some(vendors)
+}
+
+/// Decodes the V6ONLY_WAIT interval from option 108 data, clamped to
+/// [`MIN_V6ONLY_WAIT`].
+///
+/// Returns `None` if the option is not exactly four bytes long.
+pub fn decode_v6only_wait(data: &[u8]) -> Option<Duration> {
+    let secs = u32::from_be_bytes(data.try_into().ok()?);
+    Some(Duration::from_secs(secs as u64).max(MIN_V6ONLY_WAIT))
+}
+
+/// Decodes an RFC 4833 timezone string, dropping any NUL padding.
+///
+/// Returns `None` if the option is empty or not valid UTF-8.
+pub fn decode_timezone(data: &[u8]) -> Option<String> {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    let value = std::str::from_utf8(&data[..end]).ok()?.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Validates a captive portal API URI from option 114 (RFC 8910 section 2).
+///
+/// Returns `None` for the "unrestricted" URN and for URIs that are not HTTPS.
+pub fn validate_captive_portal(uri: &url::Url) -> Option<url::Url> {
+    if uri.as_str() == CAPPORT_UNRESTRICTED {
+        return None;
+    }
+    if uri.scheme() != "https" || uri.host().is_none() {
+        tracing::warn!("Ignoring captive portal URI that is not HTTPS: {}", uri);
+        return None;
+    }
+    Some(uri.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_user_classes() {
+        let encoded = encode_user_classes(&["ab", "xyz"]).unwrap();
+        assert_eq!(encoded, b"\x02ab\x03xyz");
+    }
+
+    #[test]
+    fn test_user_classes_longer_than_255_bytes_are_rejected() {
+        let longest = "x".repeat(255);
+        assert_eq!(parse_user_class(&longest), Ok(longest.clone()));
+        assert_eq!(encode_user_classes(&[&longest]).unwrap().len(), 256);
+
+        let long = "x".repeat(256);
+        assert_eq!(
+            parse_user_class(&long),
+            Err("user class takes 256 bytes, more than 255".to_string())
+        );
+        assert!(encode_user_classes(&[&long]).is_err());
+        assert!(parse_user_class("").is_err());
+    }
+
+    #[test]
+    fn test_encode_vendor_identifying_classes_groups_by_enterprise() {
+        let classes = vec![
+            "4491:docsis3.0".parse::<VendorClass>().unwrap(),
+            "9:ios".parse::<VendorClass>().unwrap(),
+            "4491:cm".parse::<VendorClass>().unwrap(),
+        ];
+        let encoded = encode_vendor_identifying_classes(&classes).unwrap();
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&4491u32.to_be_bytes());
+        expected.extend_from_slice(b"\x0d\x09docsis3.0\x02cm");
+        expected.extend_from_slice(&9u32.to_be_bytes());
+        expected.extend_from_slice(b"\x04\x03ios");
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn test_encode_vendor_identifying_classes_rejects_long_groups() {
+        let class = |enterprise_number, len| VendorClass {
+            enterprise_number,
+            data: vec![b'x'; len],
+        };
+
+        // 1 + 254 bytes just fit
+        assert_eq!(
+            encode_vendor_identifying_classes(&[class(9, 254)])
+                .unwrap()
+                .len(),
+            4 + 1 + 255
+        );
+        assert!(encode_vendor_identifying_classes(&[class(9, 255)]).is_err());
+        // Too long only once grouped
+        assert!(encode_vendor_identifying_classes(&[class(9, 127), class(9, 127)]).is_err());
+        assert!(encode_vendor_identifying_classes(&[class(9, 127), class(4491, 127)]).is_ok());
+    }
+
+    #[test]
+    fn test_vendor_class_parse_rejects_missing_separator() {
+        assert!("docsis".parse::<VendorClass>().is_err());
+        assert!("abc:docsis".parse::<VendorClass>().is_err());
+    }
+
+    #[test]
+    fn test_decode_sub_options() {
+        let data = b"\x01\x02ab\x00\x02\x01c\xff\x03\x01d";
+        let options = decode_sub_options(data).unwrap();
+        assert_eq!(options.len(), 2);
+        assert_eq!(options[&1], b"ab");
+        assert_eq!(options[&2], b"c");
+
+        assert!(decode_sub_options(b"\x01\x05ab").is_none());
+    }
+
+    #[test]
+    fn test_decode_vendor_identifying_info() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&3561u32.to_be_bytes());
+        data.extend_from_slice(b"\x05\x01\x03abc");
+        data.extend_from_slice(&4491u32.to_be_bytes());
+        data.extend_from_slice(b"\x03\x02\x01z");
+
+        let vendors = decode_vendor_identifying_info(&data).unwrap();
+        assert_eq!(vendors[&3561][&1], b"abc");
+        assert_eq!(vendors[&4491][&2], b"z");
+
+        assert!(decode_vendor_identifying_info(&data[..7]).is_none());
+    }
+
+    #[test]
+    fn test_decode_v6only_wait() {
+        assert_eq!(
+            decode_v6only_wait(&1800u32.to_be_bytes()),
+            Some(Duration::from_secs(1800))
+        );
+        assert_eq!(
+            decode_v6only_wait(&0u32.to_be_bytes()),
+            Some(MIN_V6ONLY_WAIT)
+        );
+        assert_eq!(decode_v6only_wait(&[0, 1]), None);
+    }
+
+    #[test]
+    fn test_validate_captive_portal() {
+        let api: url::Url = "https://portal.example.net/api".parse().unwrap();
+        assert_eq!(validate_captive_portal(&api), Some(api.clone()));
+
+        let unrestricted: url::Url = CAPPORT_UNRESTRICTED.parse().unwrap();
+        assert_eq!(validate_captive_portal(&unrestricted), None);
+
+        let plain: url::Url = "http://portal.example.net/api".parse().unwrap();
+        assert_eq!(validate_captive_portal(&plain), None);
+    }
+
+    #[test]
+    fn test_decode_timezone() {
+        assert_eq!(
+            decode_timezone(b"Europe/Zurich\0"),
+            Some("Europe/Zurich".to_string())
+        );
+        assert_eq!(
+            decode_timezone(b"CET-1CEST,M3.5.0,M10.5.0/3"),
+            Some("CET-1CEST,M3.5.0,M10.5.0/3".to_string())
+        );
+        assert_eq!(decode_timezone(b""), None);
+        assert_eq!(decode_timezone(&[0xff, 0xfe]), None);
+    }
+}
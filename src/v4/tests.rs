@@ -1,90 +1,956 @@
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use bytes::Bytes;
-    use dhcproto::{v4, Decodable, Decoder};
-    use std::net::Ipv4Addr;
-
-    #[test]
-    fn test_build_dhcp_discover() {
-        let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
-        let xid = 0x12345678;
-        
-        let packet = build_dhcp_discover(&mac_addr, xid).unwrap();
-        
-        // Decode the packet to verify it's valid
-        let mut decoder = Decoder::new(&packet);
-        let msg = v4::Message::decode(&mut decoder).unwrap();
-        
-        assert_eq!(msg.xid(), xid);
-        assert_eq!(msg.chaddr(), &mac_addr[..]);
-        assert_eq!(msg.opcode(), v4::Opcode::BootRequest);
-        
-        // Check for DHCP message type
-        let msg_type = msg.opts().get(v4::OptionCode::MessageType);
-        assert!(matches!(msg_type, Some(v4::DhcpOption::MessageType(v4::MessageType::Discover))));
-        
-        // Check for client identifier
-        let client_id = msg.opts().get(v4::OptionCode::ClientIdentifier);
-        assert!(client_id.is_some());
-    }
-
-    #[test]
-    fn test_build_dhcp_request() {
-        let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
-        let xid = 0x87654321;
-        let offered_ip = Ipv4Addr::new(192, 168, 1, 100);
-        let server_ip = Ipv4Addr::new(192, 168, 1, 1);
-        
-        let packet = build_dhcp_request(&mac_addr, xid, offered_ip, server_ip).unwrap();
-        
-        // Decode the packet to verify it's valid
-        let mut decoder = Decoder::new(&packet);
-        let msg = v4::Message::decode(&mut decoder).unwrap();
-        
-        assert_eq!(msg.xid(), xid);
-        assert_eq!(msg.chaddr(), &mac_addr[..]);
-        assert_eq!(msg.opcode(), v4::Opcode::BootRequest);
-        
-        // Check for DHCP message type
-        let msg_type = msg.opts().get(v4::OptionCode::MessageType);
-        assert!(matches!(msg_type, Some(v4::DhcpOption::MessageType(v4::MessageType::Request))));
-        
-        // Check for requested IP address
-        let requested_ip = msg.opts().get(v4::OptionCode::RequestedIpAddress);
-        assert!(matches!(requested_ip, Some(v4::DhcpOption::RequestedIpAddress(ip)) if *ip == offered_ip));
-        
-        // Check for server identifier
-        let server_id = msg.opts().get(v4::OptionCode::ServerIdentifier);
-        assert!(matches!(server_id, Some(v4::DhcpOption::ServerIdentifier(ip)) if *ip == server_ip));
-        
-        // Check broadcast flag is set
-        assert!(msg.flags().broadcast());
+use super::{
+    handler::DhcpV4Handler,
+    message::{
+        build_dhcp_discover, build_dhcp_init_reboot_request, build_dhcp_release,
+        build_dhcp_renew_request, build_dhcp_request, MessageOptions, HTYPE_INFINIBAND,
+    },
+    options::{VendorClass, IPV6_ONLY_PREFERRED, MIN_V6ONLY_WAIT},
+};
+use crate::{
+    client::DhcpStateMachine,
+    duid::{encode_client_identifier, Duid},
+};
+use bytes::Bytes;
+use dhcproto::{v4, Decodable, Decoder};
+use std::{net::Ipv4Addr, time::Duration};
+
+#[test]
+fn test_build_dhcp_discover() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let xid = 0x12345678;
+
+    let packet = build_dhcp_discover(&mac_addr, xid, &MessageOptions::default()).unwrap();
+
+    // Decode the packet to verify it's valid
+    let mut decoder = Decoder::new(&packet);
+    let msg = v4::Message::decode(&mut decoder).unwrap();
+
+    assert_eq!(msg.xid(), xid);
+    assert_eq!(msg.chaddr(), &mac_addr[..]);
+    assert_eq!(msg.opcode(), v4::Opcode::BootRequest);
+
+    // Check for DHCP message type
+    let msg_type = msg.opts().get(v4::OptionCode::MessageType);
+    assert!(matches!(
+        msg_type,
+        Some(v4::DhcpOption::MessageType(v4::MessageType::Discover))
+    ));
+
+    // Check for client identifier
+    let client_id = msg.opts().get(v4::OptionCode::ClientIdentifier);
+    assert!(client_id.is_some());
+}
+
+#[test]
+fn test_build_dhcp_request() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let xid = 0x87654321;
+    let offered_ip = Ipv4Addr::new(192, 168, 1, 100);
+    let server_ip = Ipv4Addr::new(192, 168, 1, 1);
+
+    let packet = build_dhcp_request(
+        &mac_addr,
+        xid,
+        offered_ip,
+        server_ip,
+        &MessageOptions::default(),
+    )
+    .unwrap();
+
+    // Decode the packet to verify it's valid
+    let mut decoder = Decoder::new(&packet);
+    let msg = v4::Message::decode(&mut decoder).unwrap();
+
+    assert_eq!(msg.xid(), xid);
+    assert_eq!(msg.chaddr(), &mac_addr[..]);
+    assert_eq!(msg.opcode(), v4::Opcode::BootRequest);
+
+    // Check for DHCP message type
+    let msg_type = msg.opts().get(v4::OptionCode::MessageType);
+    assert!(matches!(
+        msg_type,
+        Some(v4::DhcpOption::MessageType(v4::MessageType::Request))
+    ));
+
+    // Check for requested IP address
+    let requested_ip = msg.opts().get(v4::OptionCode::RequestedIpAddress);
+    assert!(
+        matches!(requested_ip, Some(v4::DhcpOption::RequestedIpAddress(ip)) if *ip == offered_ip)
+    );
+
+    // Check for server identifier
+    let server_id = msg.opts().get(v4::OptionCode::ServerIdentifier);
+    assert!(matches!(server_id, Some(v4::DhcpOption::ServerIdentifier(ip)) if *ip == server_ip));
+
+    // Check broadcast flag is set
+    assert!(msg.flags().broadcast());
+}
+
+#[test]
+fn test_dhcp_v4_handler_creation() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let handler = DhcpV4Handler::new(mac_addr.clone(), MessageOptions::default());
+
+    assert_eq!(handler.state_name(), "Init");
+}
+
+#[test]
+fn test_dhcp_v4_handler_init_transition() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let mut handler = DhcpV4Handler::new(mac_addr, MessageOptions::default());
+
+    let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+
+    match action {
+        crate::client::Action::Send(packet, addr) => {
+            assert!(!packet.is_empty());
+            assert_eq!(addr.port(), 67);
+        }
+        _ => panic!("Expected Send action"),
+    }
+
+    assert_eq!(handler.state_name(), "Selecting");
+}
+
+#[test]
+fn test_build_dhcp_discover_with_class_options() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let options = MessageOptions {
+        vendor_class: Some(b"herald".to_vec()),
+        user_classes: vec![b"kiosk".to_vec()],
+        vendor_identifying_classes: vec![VendorClass {
+            enterprise_number: 4491,
+            data: b"docsis3.0".to_vec(),
+        }],
+        ..MessageOptions::default()
+    };
+
+    let packet = build_dhcp_discover(&mac_addr, 1, &options).unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+
+    assert!(matches!(
+        msg.opts().get(v4::OptionCode::ClassIdentifier),
+        Some(v4::DhcpOption::ClassIdentifier(class)) if class == b"herald"
+    ));
+    assert!(matches!(
+        msg.opts().get(v4::OptionCode::UserClass),
+        Some(v4::DhcpOption::UserClass(class)) if class == b"\x05kiosk"
+    ));
+    match msg.opts().get(v4::OptionCode::Unknown(124)) {
+        Some(v4::DhcpOption::Unknown(opt)) => {
+            assert_eq!(&opt.data()[..4], &4491u32.to_be_bytes());
+            assert_eq!(&opt.data()[4..], b"\x0a\x09docsis3.0");
+        }
+        other => panic!("Expected option 124, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_build_dhcp_discover_rejects_oversized_vendor_classes() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let class = VendorClass {
+        enterprise_number: 4491,
+        data: vec![b'x'; 200],
+    };
+    let options = MessageOptions {
+        vendor_identifying_classes: vec![class.clone(), class],
+        ..MessageOptions::default()
+    };
+
+    assert!(build_dhcp_discover(&mac_addr, 1, &options).is_err());
+}
+
+#[test]
+fn test_build_dhcp_discover_omits_unset_class_options() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let packet = build_dhcp_discover(&mac_addr, 1, &MessageOptions::default()).unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+
+    assert!(msg.opts().get(v4::OptionCode::ClassIdentifier).is_none());
+    assert!(msg.opts().get(v4::OptionCode::UserClass).is_none());
+    assert!(msg.opts().get(v4::OptionCode::Unknown(124)).is_none());
+}
+
+#[test]
+fn test_client_identifier_defaults_to_mac() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let packet = build_dhcp_discover(&mac_addr, 1, &MessageOptions::default()).unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+
+    assert!(matches!(
+        msg.opts().get(v4::OptionCode::ClientIdentifier),
+        Some(v4::DhcpOption::ClientIdentifier(id)) if id[..] == [1, 0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]
+    ));
+}
+
+#[test]
+fn test_duid_client_identifier_in_request() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let duid = Duid::ll(1, &mac_addr);
+    let options = MessageOptions {
+        client_identifier: Some(encode_client_identifier(0x29a8_92f4, &duid)),
+        ..MessageOptions::default()
+    };
+
+    let offered_ip = Ipv4Addr::new(192, 168, 1, 100);
+    let server_ip = Ipv4Addr::new(192, 168, 1, 1);
+    let packet = build_dhcp_request(&mac_addr, 1, offered_ip, server_ip, &options).unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+
+    match msg.opts().get(v4::OptionCode::ClientIdentifier) {
+        Some(v4::DhcpOption::ClientIdentifier(id)) => {
+            assert_eq!(id[0], 255);
+            assert_eq!(&id[1..5], &[0x29, 0xa8, 0x92, 0xf4]);
+            assert_eq!(&id[5..], duid.as_bytes());
+        }
+        other => panic!("Expected client identifier, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_build_dhcp_release() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let client_ip = Ipv4Addr::new(192, 168, 1, 100);
+    let server_ip = Ipv4Addr::new(192, 168, 1, 1);
+
+    let packet = build_dhcp_release(
+        &mac_addr,
+        1,
+        client_ip,
+        server_ip,
+        &MessageOptions::default(),
+    )
+    .unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+
+    assert_eq!(msg.ciaddr(), client_ip);
+    assert!(!msg.flags().broadcast());
+    assert!(matches!(
+        msg.opts().get(v4::OptionCode::MessageType),
+        Some(v4::DhcpOption::MessageType(v4::MessageType::Release))
+    ));
+    assert!(matches!(
+        msg.opts().get(v4::OptionCode::ServerIdentifier),
+        Some(v4::DhcpOption::ServerIdentifier(ip)) if *ip == server_ip
+    ));
+}
+
+#[test]
+fn test_parameter_request_list_includes_mtu() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let packet = build_dhcp_discover(&mac_addr, 1, &MessageOptions::default()).unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+
+    match msg.opts().get(v4::OptionCode::ParameterRequestList) {
+        Some(v4::DhcpOption::ParameterRequestList(codes)) => {
+            assert!(codes.contains(&v4::OptionCode::InterfaceMtu));
+        }
+        other => panic!("Expected parameter request list, got {other:?}"),
+    }
+}
+
+/// Builds a server reply of `msg_type` for the transaction `xid`.
+fn build_reply(xid: u32, msg_type: v4::MessageType, extra: Vec<v4::DhcpOption>) -> Vec<u8> {
+    use dhcproto::{Encodable, Encoder};
+
+    let mut msg = v4::Message::default();
+    msg.set_opcode(v4::Opcode::BootReply)
+        .set_xid(xid)
+        .set_yiaddr(Ipv4Addr::new(192, 168, 1, 100));
+    msg.opts_mut().insert(v4::DhcpOption::MessageType(msg_type));
+    msg.opts_mut()
+        .insert(v4::DhcpOption::ServerIdentifier(Ipv4Addr::new(
+            192, 168, 1, 1,
+        )));
+    for opt in extra {
+        msg.opts_mut().insert(opt);
+    }
+
+    let mut buffer = Vec::new();
+    msg.encode(&mut Encoder::new(&mut buffer)).unwrap();
+    buffer
+}
+
+/// Starts `handler` and returns the xid of the DISCOVER it sent.
+fn start_discovery(handler: &mut DhcpV4Handler) -> u32 {
+    match handler.handle_event(crate::client::Event::Timeout).unwrap() {
+        crate::client::Action::Send(packet, _) => v4::Message::decode(&mut Decoder::new(&packet))
+            .unwrap()
+            .xid(),
+        other => panic!("Expected Send action, got {other:?}"),
+    }
+}
+
+fn v6only_option(secs: u32) -> v4::DhcpOption {
+    v4::DhcpOption::Unknown(v4::UnknownOption::new(
+        v4::OptionCode::Unknown(IPV6_ONLY_PREFERRED),
+        secs.to_be_bytes().to_vec(),
+    ))
+}
+
+#[test]
+fn test_ipv6_only_preferred_requested_when_enabled() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let options = MessageOptions {
+        ipv6_only_preferred: true,
+        ..MessageOptions::default()
+    };
+    let packet = build_dhcp_discover(&mac_addr, 1, &options).unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+
+    match msg.opts().get(v4::OptionCode::ParameterRequestList) {
+        Some(v4::DhcpOption::ParameterRequestList(codes)) => {
+            assert!(codes.contains(&v4::OptionCode::Unknown(IPV6_ONLY_PREFERRED)));
+        }
+        other => panic!("Expected parameter request list, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_offer_with_ipv6_only_preferred_pauses_dhcpv4() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let options = MessageOptions {
+        ipv6_only_preferred: true,
+        ..MessageOptions::default()
+    };
+    let mut handler = DhcpV4Handler::new(mac_addr, options);
+    let xid = start_discovery(&mut handler);
+
+    let offer = build_reply(xid, v4::MessageType::Offer, vec![v6only_option(1800)]);
+    let action = handler
+        .handle_event(crate::client::Event::PacketReceived(&offer))
+        .unwrap();
+
+    assert_eq!(handler.state_name(), "V6OnlyWait");
+    assert!(matches!(action, crate::client::Action::Wait(d) if d == Duration::from_secs(1800)));
+
+    // Packets received while waiting don't end the pause
+    let action = handler
+        .handle_event(crate::client::Event::PacketReceived(&offer))
+        .unwrap();
+    assert_eq!(handler.state_name(), "V6OnlyWait");
+    assert!(matches!(action, crate::client::Action::Wait(d) if d <= Duration::from_secs(1800)));
+}
+
+#[test]
+fn test_short_v6only_wait_is_clamped() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let options = MessageOptions {
+        ipv6_only_preferred: true,
+        ..MessageOptions::default()
+    };
+    let mut handler = DhcpV4Handler::new(mac_addr, options);
+    let xid = start_discovery(&mut handler);
+
+    let offer = build_reply(xid, v4::MessageType::Offer, vec![v6only_option(10)]);
+    let action = handler
+        .handle_event(crate::client::Event::PacketReceived(&offer))
+        .unwrap();
+    assert!(matches!(action, crate::client::Action::Wait(d) if d == MIN_V6ONLY_WAIT));
+}
+
+#[test]
+fn test_ipv6_only_preferred_ignored_when_not_requested() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let mut handler = DhcpV4Handler::new(mac_addr, MessageOptions::default());
+    let xid = start_discovery(&mut handler);
+
+    let offer = build_reply(xid, v4::MessageType::Offer, vec![v6only_option(1800)]);
+    let action = handler
+        .handle_event(crate::client::Event::PacketReceived(&offer))
+        .unwrap();
+
+    assert_eq!(handler.state_name(), "Requesting");
+    assert!(matches!(action, crate::client::Action::Send(_, _)));
+}
+
+#[test]
+fn test_pxe_options_in_discover() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let uuid = [0x11; 16];
+    let options = MessageOptions {
+        client_architecture: Some(7),
+        client_machine_id: Some(uuid),
+        ..MessageOptions::default()
+    };
+
+    let packet = build_dhcp_discover(&mac_addr, 1, &options).unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+
+    assert!(matches!(
+        msg.opts().get(v4::OptionCode::ClientSystemArchitecture),
+        Some(v4::DhcpOption::ClientSystemArchitecture(
+            v4::Architecture::BC
+        ))
+    ));
+    match msg.opts().get(v4::OptionCode::ClientMachineIdentifier) {
+        Some(v4::DhcpOption::ClientMachineIdentifier(id)) => {
+            assert_eq!(id[0], 0);
+            assert_eq!(&id[1..], &uuid);
+        }
+        other => panic!("Expected client machine identifier, got {other:?}"),
     }
+}
 
-    #[test]
-    fn test_dhcp_v4_handler_creation() {
-        let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
-        let handler = DhcpV4Handler::new(mac_addr.clone());
-        
-        assert_eq!(handler.state_name(), "Init");
+#[test]
+fn test_ack_boot_info_on_lease() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let mut handler = DhcpV4Handler::new(mac_addr, MessageOptions::default());
+    let xid = start_discovery(&mut handler);
+
+    let offer = build_reply(xid, v4::MessageType::Offer, vec![]);
+    handler
+        .handle_event(crate::client::Event::PacketReceived(&offer))
+        .unwrap();
+
+    let ack = build_reply(
+        xid,
+        v4::MessageType::Ack,
+        vec![
+            v4::DhcpOption::BootfileName(b"grubx64.efi".to_vec()),
+            v4::DhcpOption::TFTPServerAddress(Ipv4Addr::new(10, 0, 0, 9)),
+        ],
+    );
+    match handler
+        .handle_event(crate::client::Event::PacketReceived(&ack))
+        .unwrap()
+    {
+        crate::client::Action::StoreLease(lease) => {
+            let boot = lease.boot_info.expect("boot info");
+            assert_eq!(boot.boot_file.as_deref(), Some("grubx64.efi"));
+            assert_eq!(boot.tftp_server_addresses, vec![Ipv4Addr::new(10, 0, 0, 9)]);
+        }
+        other => panic!("Expected StoreLease action, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_auto_configure_option_in_discover() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let options = MessageOptions {
+        auto_configure: true,
+        ..MessageOptions::default()
+    };
+    let packet = build_dhcp_discover(&mac_addr, 1, &options).unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+    assert!(matches!(
+        msg.opts().get(v4::OptionCode::DisableSLAAC),
+        Some(v4::DhcpOption::DisableSLAAC(v4::AutoConfig::AutoConfigure))
+    ));
+
+    let packet = build_dhcp_discover(&mac_addr, 1, &MessageOptions::default()).unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+    assert!(msg.opts().get(v4::OptionCode::DisableSLAAC).is_none());
+}
+
+#[test]
+fn test_link_local_fallback_after_unanswered_discovers() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let mut handler =
+        DhcpV4Handler::new(mac_addr, MessageOptions::default()).with_link_local_fallback(Some(2));
+    start_discovery(&mut handler);
+
+    let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+    assert!(matches!(action, crate::client::Action::Send(_, _)));
+    let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+    assert!(matches!(action, crate::client::Action::ClaimLinkLocal));
+
+    // Discovery continues in the background without claiming again
+    for _ in 0..3 {
+        let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+        assert!(matches!(action, crate::client::Action::Send(_, _)));
     }
+}
+
+#[test]
+fn test_do_not_auto_configure_releases_link_local() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let mut handler =
+        DhcpV4Handler::new(mac_addr, MessageOptions::default()).with_link_local_fallback(Some(1));
+    start_discovery(&mut handler);
+
+    let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+    assert!(matches!(action, crate::client::Action::ClaimLinkLocal));
+    let xid = start_discovery(&mut handler);
 
-    #[test]
-    fn test_dhcp_v4_handler_init_transition() {
-        let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
-        let mut handler = DhcpV4Handler::new(mac_addr);
-        
+    let offer = build_reply(
+        xid,
+        v4::MessageType::Offer,
+        vec![v4::DhcpOption::DisableSLAAC(
+            v4::AutoConfig::DoNotAutoConfigure,
+        )],
+    );
+    let action = handler
+        .handle_event(crate::client::Event::PacketReceived(&offer))
+        .unwrap();
+    assert!(matches!(action, crate::client::Action::ReleaseLinkLocal));
+
+    // The fallback stays disabled for later timeouts
+    start_discovery(&mut handler);
+    for _ in 0..3 {
         let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
-        
-        match action {
-            crate::client::Action::Send(packet, addr) => {
-                assert!(!packet.is_empty());
-                assert_eq!(addr.port(), 67);
-            }
-            _ => panic!("Expected Send action"),
+        assert!(matches!(action, crate::client::Action::Send(_, _)));
+    }
+}
+
+#[test]
+fn test_broadcast_flag_clear_when_unicast_is_received() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let options = MessageOptions {
+        receives_unicast: true,
+        ..MessageOptions::default()
+    };
+
+    let packet = build_dhcp_discover(&mac_addr, 1, &options).unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+    assert!(!msg.flags().broadcast());
+
+    let offered_ip = Ipv4Addr::new(192, 168, 1, 100);
+    let server_ip = Ipv4Addr::new(192, 168, 1, 1);
+    let packet = build_dhcp_request(&mac_addr, 1, offered_ip, server_ip, &options).unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+    assert!(!msg.flags().broadcast());
+}
+
+/// Binds `handler` to 192.168.1.100 through a full DORA exchange.
+fn bind(handler: &mut DhcpV4Handler) {
+    bind_lease(handler, vec![]);
+}
+
+/// Binds `handler` to a lease whose ACK carries `extra`.
+fn bind_lease(handler: &mut DhcpV4Handler, extra: Vec<v4::DhcpOption>) {
+    let xid = start_discovery(handler);
+    let offer = build_reply(xid, v4::MessageType::Offer, vec![]);
+    handler
+        .handle_event(crate::client::Event::PacketReceived(&offer))
+        .unwrap();
+    let ack = build_reply(xid, v4::MessageType::Ack, extra);
+    let action = handler
+        .handle_event(crate::client::Event::PacketReceived(&ack))
+        .unwrap();
+    assert!(matches!(action, crate::client::Action::StoreLease(_)));
+}
+
+/// Decodes the message sent by a `Send` action.
+fn sent_message(action: crate::client::Action) -> v4::Message {
+    match action {
+        crate::client::Action::Send(packet, _) => {
+            v4::Message::decode(&mut Decoder::new(&packet)).unwrap()
         }
-        
-        assert_eq!(handler.state_name(), "Selecting");
+        other => panic!("Expected Send action, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_build_dhcp_init_reboot_request() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let requested_ip = Ipv4Addr::new(192, 168, 1, 100);
+    let packet =
+        build_dhcp_init_reboot_request(&mac_addr, 7, requested_ip, &MessageOptions::default())
+            .unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+
+    assert!(matches!(
+        msg.opts().get(v4::OptionCode::MessageType),
+        Some(v4::DhcpOption::MessageType(v4::MessageType::Request))
+    ));
+    assert!(matches!(
+        msg.opts().get(v4::OptionCode::RequestedIpAddress),
+        Some(v4::DhcpOption::RequestedIpAddress(ip)) if *ip == requested_ip
+    ));
+    assert!(msg.opts().get(v4::OptionCode::ServerIdentifier).is_none());
+    assert!(msg.ciaddr().is_unspecified());
+}
+
+#[test]
+fn test_carrier_loss_pauses_discovery() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let mut handler = DhcpV4Handler::new(mac_addr, MessageOptions::default());
+    start_discovery(&mut handler);
+
+    let action = handler
+        .handle_event(crate::client::Event::CarrierDown)
+        .unwrap();
+    assert!(matches!(action, crate::client::Action::WaitForCarrier));
+    assert_eq!(handler.state_name(), "NoCarrier");
+
+    // Nothing is sent until the carrier returns
+    let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+    assert!(matches!(action, crate::client::Action::WaitForCarrier));
+
+    // Without a previous lease, discovery starts over
+    let action = handler
+        .handle_event(crate::client::Event::CarrierUp)
+        .unwrap();
+    let msg = sent_message(action);
+    assert!(matches!(
+        msg.opts().get(v4::OptionCode::MessageType),
+        Some(v4::DhcpOption::MessageType(v4::MessageType::Discover))
+    ));
+}
+
+#[test]
+fn test_carrier_regain_confirms_lease_with_init_reboot() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let mut handler = DhcpV4Handler::new(mac_addr, MessageOptions::default());
+    bind(&mut handler);
+
+    handler
+        .handle_event(crate::client::Event::CarrierDown)
+        .unwrap();
+    let action = handler
+        .handle_event(crate::client::Event::CarrierUp)
+        .unwrap();
+    assert_eq!(handler.state_name(), "InitReboot");
+    let request = sent_message(action);
+    assert!(matches!(
+        request.opts().get(v4::OptionCode::RequestedIpAddress),
+        Some(v4::DhcpOption::RequestedIpAddress(ip)) if *ip == Ipv4Addr::new(192, 168, 1, 100)
+    ));
+    assert!(request
+        .opts()
+        .get(v4::OptionCode::ServerIdentifier)
+        .is_none());
+
+    let ack = build_reply(request.xid(), v4::MessageType::Ack, vec![]);
+    let action = handler
+        .handle_event(crate::client::Event::PacketReceived(&ack))
+        .unwrap();
+    assert!(matches!(action, crate::client::Action::StoreLease(_)));
+    assert_eq!(handler.state_name(), "Bound");
+}
+
+#[test]
+fn test_init_reboot_nak_restarts_discovery() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let mut handler = DhcpV4Handler::new(mac_addr, MessageOptions::default());
+    bind(&mut handler);
+
+    handler
+        .handle_event(crate::client::Event::CarrierDown)
+        .unwrap();
+    let action = handler
+        .handle_event(crate::client::Event::CarrierUp)
+        .unwrap();
+    let request = sent_message(action);
+
+    // The configuration of the declined lease is removed first
+    let nak = build_reply(request.xid(), v4::MessageType::Nak, vec![]);
+    let action = handler
+        .handle_event(crate::client::Event::PacketReceived(&nak))
+        .unwrap();
+    assert!(matches!(action, crate::client::Action::DropLease));
+    assert_eq!(handler.state_name(), "Init");
+
+    let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+    assert_eq!(handler.state_name(), "Selecting");
+    assert!(matches!(
+        sent_message(action).opts().get(v4::OptionCode::MessageType),
+        Some(v4::DhcpOption::MessageType(v4::MessageType::Discover))
+    ));
+
+    // The NAKed address is not requested again after the next carrier loss
+    handler
+        .handle_event(crate::client::Event::CarrierDown)
+        .unwrap();
+    handler
+        .handle_event(crate::client::Event::CarrierUp)
+        .unwrap();
+    assert_eq!(handler.state_name(), "Selecting");
+}
+
+#[test]
+fn test_unanswered_init_reboot_falls_back_to_discovery() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let mut handler = DhcpV4Handler::new(mac_addr, MessageOptions::default());
+    bind(&mut handler);
+
+    handler
+        .handle_event(crate::client::Event::CarrierDown)
+        .unwrap();
+    handler
+        .handle_event(crate::client::Event::CarrierUp)
+        .unwrap();
+    for _ in 1..3 {
+        let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+        assert_eq!(handler.state_name(), "InitReboot");
+        assert!(sent_message(action)
+            .opts()
+            .get(v4::OptionCode::RequestedIpAddress)
+            .is_some());
     }
-}
\ No newline at end of file
+
+    handler.handle_event(crate::client::Event::Timeout).unwrap();
+    assert_eq!(handler.state_name(), "Selecting");
+}
+
+#[test]
+fn test_build_dhcp_renew_request() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let client_ip = Ipv4Addr::new(192, 168, 1, 100);
+    let packet =
+        build_dhcp_renew_request(&mac_addr, 7, client_ip, &MessageOptions::default()).unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+
+    assert!(matches!(
+        msg.opts().get(v4::OptionCode::MessageType),
+        Some(v4::DhcpOption::MessageType(v4::MessageType::Request))
+    ));
+    assert_eq!(msg.ciaddr(), client_ip);
+    assert!(msg.opts().get(v4::OptionCode::RequestedIpAddress).is_none());
+    assert!(msg.opts().get(v4::OptionCode::ServerIdentifier).is_none());
+    assert!(!msg.flags().broadcast());
+}
+
+#[test]
+fn test_infinite_lease_is_not_renewed() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let mut handler = DhcpV4Handler::new(mac_addr, MessageOptions::default());
+    bind_lease(
+        &mut handler,
+        vec![v4::DhcpOption::AddressLeaseTime(u32::MAX)],
+    );
+
+    let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+    assert!(matches!(action, crate::client::Action::Exit));
+    assert_eq!(handler.state_name(), "Bound");
+}
+
+#[test]
+fn test_bound_lease_is_renewed_with_its_server() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let mut handler = DhcpV4Handler::new(mac_addr, MessageOptions::default());
+    bind_lease(
+        &mut handler,
+        vec![
+            v4::DhcpOption::AddressLeaseTime(3600),
+            v4::DhcpOption::Renewal(0),
+            v4::DhcpOption::Rebinding(3000),
+        ],
+    );
+
+    // T1 has passed: the REQUEST goes to the server, from the leased address
+    let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+    assert_eq!(handler.state_name(), "Renewing");
+    assert!(matches!(
+        action,
+        crate::client::Action::Send(_, addr) if addr == "192.168.1.1:67".parse().unwrap()
+    ));
+    let request = sent_message(action);
+    assert_eq!(request.ciaddr(), Ipv4Addr::new(192, 168, 1, 100));
+
+    // The next REQUEST waits for half the time left until T2
+    let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+    assert!(matches!(
+        action,
+        crate::client::Action::Wait(d) if d > Duration::from_secs(1400) && d <= Duration::from_secs(1500)
+    ));
+
+    // The ACK binds the lease again, with T1 at half of it
+    let ack = build_reply(
+        request.xid(),
+        v4::MessageType::Ack,
+        vec![v4::DhcpOption::AddressLeaseTime(3600)],
+    );
+    let action = handler
+        .handle_event(crate::client::Event::PacketReceived(&ack))
+        .unwrap();
+    assert!(matches!(action, crate::client::Action::StoreLease(_)));
+    assert_eq!(handler.state_name(), "Bound");
+    let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+    assert!(matches!(
+        action,
+        crate::client::Action::Wait(d) if d > Duration::from_secs(1700) && d <= Duration::from_secs(1800)
+    ));
+}
+
+#[test]
+fn test_lease_is_rebound_with_any_server_after_t2() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let mut handler = DhcpV4Handler::new(mac_addr, MessageOptions::default());
+    bind_lease(
+        &mut handler,
+        vec![
+            v4::DhcpOption::AddressLeaseTime(3600),
+            v4::DhcpOption::Renewal(0),
+            v4::DhcpOption::Rebinding(0),
+        ],
+    );
+
+    let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+    assert_eq!(handler.state_name(), "Rebinding");
+    assert!(matches!(
+        action,
+        crate::client::Action::Send(_, addr) if addr == "255.255.255.255:67".parse().unwrap()
+    ));
+
+    // A NAK ends the lease
+    let nak = build_reply(sent_message(action).xid(), v4::MessageType::Nak, vec![]);
+    let action = handler
+        .handle_event(crate::client::Event::PacketReceived(&nak))
+        .unwrap();
+    assert!(matches!(action, crate::client::Action::DropLease));
+    assert_eq!(handler.state_name(), "Init");
+}
+
+#[test]
+fn test_expired_lease_is_dropped() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let mut handler = DhcpV4Handler::new(mac_addr, MessageOptions::default());
+    bind_lease(&mut handler, vec![v4::DhcpOption::AddressLeaseTime(0)]);
+
+    let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+    assert!(matches!(action, crate::client::Action::DropLease));
+    assert_eq!(handler.state_name(), "Init");
+
+    // Discovery starts over, and the carrier no longer brings the lease back
+    let action = handler.handle_event(crate::client::Event::Timeout).unwrap();
+    assert!(matches!(
+        sent_message(action).opts().get(v4::OptionCode::MessageType),
+        Some(v4::DhcpOption::MessageType(v4::MessageType::Discover))
+    ));
+    handler
+        .handle_event(crate::client::Event::CarrierDown)
+        .unwrap();
+    handler
+        .handle_event(crate::client::Event::CarrierUp)
+        .unwrap();
+    assert_eq!(handler.state_name(), "Selecting");
+}
+
+#[test]
+fn test_hardware_type_in_messages() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let options = MessageOptions {
+        hardware_type: Some(v4::HType::IEEE802),
+        ..MessageOptions::default()
+    };
+    let packet = build_dhcp_discover(&mac_addr, 1, &options).unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+
+    assert_eq!(msg.htype(), v4::HType::IEEE802);
+    assert_eq!(msg.hlen(), 6);
+    assert!(matches!(
+        msg.opts().get(v4::OptionCode::ClientIdentifier),
+        Some(v4::DhcpOption::ClientIdentifier(id)) if id[0] == 6 && id[1..] == mac_addr[..]
+    ));
+}
+
+/// A 20-byte IPoIB hardware address: queue pair number, subnet prefix and port GUID.
+const IPOIB_ADDRESS: [u8; 20] = [
+    0x80, 0x00, 0x04, 0x04, 0xfe, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xc9, 0x03,
+    0x00, 0x0a, 0x0b, 0x0c,
+];
+
+/// Decodes the DISCOVER and REQUEST built for `mac_addr` with `options`.
+fn build_both(mac_addr: &Bytes, options: &MessageOptions) -> [v4::Message; 2] {
+    let discover = build_dhcp_discover(mac_addr, 1, options).unwrap();
+    let request = build_dhcp_request(
+        mac_addr,
+        1,
+        Ipv4Addr::new(192, 168, 1, 100),
+        Ipv4Addr::new(192, 168, 1, 1),
+        options,
+    )
+    .unwrap();
+    [discover, request].map(|packet| v4::Message::decode(&mut Decoder::new(&packet)).unwrap())
+}
+
+#[test]
+fn test_ethernet_messages() {
+    let mac_addr = Bytes::from_static(&[0x00, 0x0c, 0x29, 0xa8, 0x92, 0xf4]);
+    let options = MessageOptions {
+        hardware_type: Some(v4::HType::Eth),
+        receives_unicast: true,
+        ..MessageOptions::default()
+    };
+
+    for msg in build_both(&mac_addr, &options) {
+        assert_eq!(msg.htype(), v4::HType::Eth);
+        assert_eq!(msg.hlen(), 6);
+        assert_eq!(&msg.chaddr()[..6], &mac_addr[..]);
+        assert!(!msg.flags().broadcast());
+        assert!(matches!(
+            msg.opts().get(v4::OptionCode::ClientIdentifier),
+            Some(v4::DhcpOption::ClientIdentifier(id)) if id[0] == 1 && id[1..] == mac_addr[..]
+        ));
+    }
+}
+
+#[test]
+fn test_ipoib_messages() {
+    let mac_addr = Bytes::from_static(&IPOIB_ADDRESS);
+    let options = MessageOptions {
+        hardware_type: Some(v4::HType::from(HTYPE_INFINIBAND)),
+        // Unicast is never used for IPoIB
+        receives_unicast: true,
+        ..MessageOptions::default()
+    };
+    let guid = &IPOIB_ADDRESS[12..];
+    let expected_id = encode_client_identifier(0x000a_0b0c, &Duid::ll(32, guid));
+
+    for msg in build_both(&mac_addr, &options) {
+        assert_eq!(u8::from(msg.htype()), HTYPE_INFINIBAND);
+        assert_eq!(msg.hlen(), 0);
+        assert!(msg.chaddr().iter().all(|&b| b == 0));
+        assert!(msg.flags().broadcast());
+        assert!(matches!(
+            msg.opts().get(v4::OptionCode::ClientIdentifier),
+            Some(v4::DhcpOption::ClientIdentifier(id)) if *id == expected_id
+        ));
+    }
+
+    // A configured DUID-based identifier is used as is
+    let options = MessageOptions {
+        client_identifier: Some(vec![255, 1, 2, 3, 4, 0, 4]),
+        ..options
+    };
+    for msg in build_both(&mac_addr, &options) {
+        assert!(matches!(
+            msg.opts().get(v4::OptionCode::ClientIdentifier),
+            Some(v4::DhcpOption::ClientIdentifier(id)) if id[..] == [255, 1, 2, 3, 4, 0, 4]
+        ));
+    }
+}
+
+#[test]
+fn test_long_hardware_address_leaves_chaddr_empty() {
+    // Fibre Channel (htype 18) with an address too long for chaddr
+    let mac_addr = Bytes::from_static(&IPOIB_ADDRESS);
+    let options = MessageOptions {
+        hardware_type: Some(v4::HType::FibreChannel),
+        ..MessageOptions::default()
+    };
+
+    for msg in build_both(&mac_addr, &options) {
+        assert_eq!(msg.htype(), v4::HType::FibreChannel);
+        assert_eq!(msg.hlen(), 0);
+        assert!(matches!(
+            msg.opts().get(v4::OptionCode::ClientIdentifier),
+            Some(v4::DhcpOption::ClientIdentifier(id)) if id[0] == 18 && id[1..] == mac_addr[..]
+        ));
+    }
+}
+
+#[test]
+fn test_ipoib_release() {
+    let mac_addr = Bytes::from_static(&IPOIB_ADDRESS);
+    let options = MessageOptions {
+        hardware_type: Some(v4::HType::from(HTYPE_INFINIBAND)),
+        ..MessageOptions::default()
+    };
+    let packet = build_dhcp_release(
+        &mac_addr,
+        1,
+        Ipv4Addr::new(192, 168, 1, 100),
+        Ipv4Addr::new(192, 168, 1, 1),
+        &options,
+    )
+    .unwrap();
+    let msg = v4::Message::decode(&mut Decoder::new(&packet)).unwrap();
+    assert_eq!(msg.hlen(), 0);
+    assert!(matches!(
+        msg.opts().get(v4::OptionCode::ClientIdentifier),
+        Some(v4::DhcpOption::ClientIdentifier(id)) if id[0] == 255
+    ));
+}